@@ -0,0 +1,16 @@
+# Program A in assembly: copy the bytes at m0x00 onwards to m0x10 onwards
+# until r2 reaches r0, by rewriting its own load and store addresses
+        .org 0x30
+        load r0, 3              # Number of bytes to copy
+        load r1, 1
+        load r2, 0              # Next address to copy from
+        load r3, 0x10           # Next address to copy to
+copy:   load r4, [0x00]
+paste:  store r4, [0x10]
+        add r2, r2, r1
+        add r3, r3, r1
+        store r2, [copy+1]      # The address byte of `copy`
+        store r3, [paste+1]     # The address byte of `paste`
+        jump r2, done
+        jump copy
+done:   halt
@@ -0,0 +1,15 @@
+# Program A copies the three bytes at m0x00..m0x02 to m0x10..m0x12
+program = A
+cycles = 40
+forbid = 6
+sanitize = fault
+
+[initial]
+m0x00 = 11 22 33
+
+[expected]
+pc = 48
+r0 = 03
+r2 = 03
+r3 = 13
+m0x10 = 11 22 33
@@ -0,0 +1,9 @@
+# Program B doubles r1 until it reaches the value in r0
+program = B
+cycles = 20
+
+[expected]
+pc = 0C
+r0 = 04
+r1 = 04
+r2 = 01
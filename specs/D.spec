@@ -0,0 +1,10 @@
+# Program D doubles r1 twice with a subroutine that keeps r2 intact
+program = D
+cycles = 20
+sanitize = fault
+
+[expected]
+pc = 0A
+r1 = 04
+r2 = 07
+m0x40 = 04
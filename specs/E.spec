@@ -0,0 +1,8 @@
+# Program E detects that 100 + 100 overflows a two's complement byte
+program = E
+cycles = 10
+
+[expected]
+pc = 0E
+r3 = C8
+r4 = FF
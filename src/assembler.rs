@@ -0,0 +1,465 @@
+// Assembler for Vole assembly, with a relocation table for every address it fills in from a label
+//
+//     # Copy the bytes at m0x00 onwards to m0x10 onwards until r2 reaches r0
+//             .org 0x30
+//             load r0, 3
+//             load r1, 1
+//             load r2, 0
+//             load r3, 0x10
+//     copy:   load r4, [0x00]
+//     paste:  store r4, [0x10]
+//             add r2, r2, r1
+//             add r3, r3, r1
+//             store r2, [copy+1]      # Self-modifying: the address byte of `copy`
+//             store r3, [paste+1]
+//             jump r2, done
+//             jump copy
+//     done:   halt
+//
+// Instructions (rR, rS, rT are registers r0 to rF, X a number from 0 to 15):
+//     nop                  0x0000      rotate rR, X         0xAR0X
+//     load rR, [address]   0x1RXY      jump rR, address     0xBRXY
+//     load rR, value       0x2RXY      jump address         0xB0XY
+//     store rR, [address]  0x3RXY      halt                 0xC000
+//     move rR, rS          0x40RS      call address         0xD0XY  (stack extension)
+//     add rR, rS, rT       0x5RST      ret                  0xD100  (stack extension)
+//     addf rR, rS, rT      0x6RST      push rR              0xER00  (stack extension)
+//     or rR, rS, rT        0x7RST      pop rR               0xER01  (stack extension)
+//     and rR, rS, rT       0x8RST      jump condition, address
+//     xor rR, rS, rT       0x9RST                           0xFCXY  (flags extension)
+//
+// Values and addresses are decimal or `0x` hexadecimal numbers, labels, or a label plus or minus
+// a number. Every byte filled in from a label is an address inside the program and goes into the
+// relocation table, so the program can be loaded at any address. Directives: `.org address` sets
+// where the program is assembled for, `.byte value, ...` places data and
+// `.extensions stack flags` enables instruction set extensions.
+
+use crate::compiler::{CompileError, SourceMap};
+use crate::instruction::Instruction;
+use crate::{Address, Condition, Extensions, Program};
+
+fn error<T>(line: usize, message: String) -> Result<T, CompileError> {
+    Err(CompileError { line, message })
+}
+
+// A line of source with its label definitions removed
+struct Line<'a> {
+    number: usize,
+    mnemonic: &'a str,
+    operands: Vec<&'a str>,
+}
+
+impl Line<'_> {
+    fn size(&self) -> usize {
+        match self.mnemonic {
+            ".byte" => self.operands.len(),
+            ".org" | ".extensions" => 0,
+            _ => 2,
+        }
+    }
+
+    fn expect_operands(&self, count: usize) -> Result<(), CompileError> {
+        match self.operands.len() == count {
+            true => Ok(()),
+            false => error(self.number, format!("'{}' takes {count} operand(s), not {}", self.mnemonic, self.operands.len())),
+        }
+    }
+}
+
+struct Assembler<'a> {
+    labels: Vec<(&'a str, usize)>,
+    // Offsets of the bytes of the program filled in from a label
+    relocations: Vec<usize>,
+    start_address: Address,
+}
+
+impl Assembler<'_> {
+    fn register(&self, line: &Line, text: &str) -> Result<usize, CompileError> {
+        text.strip_prefix('r')
+            .and_then(|r| usize::from_str_radix(r, 16).ok())
+            .filter(|&r| r < 16)
+            .map_or_else(|| error(line.number, format!("'{text}' is not a register (r0 to rF)")), Ok)
+    }
+
+    // A number, or a label plus or minus a number, placed at the offset in the program
+    fn value(&mut self, line: &Line, text: &str, offset: usize) -> Result<u8, CompileError> {
+        let text = text.trim();
+        let (base, adjustment) = match text.find(['+', '-']) {
+            Some(position) if position > 0 => {
+                let amount = parse_number(text[position + 1..].trim())
+                    .ok_or_else(|| CompileError { line: line.number, message: format!("'{text}' is not a value") })?;
+                let amount = if text[position..].starts_with('-') { amount.wrapping_neg() } else { amount };
+                (text[..position].trim(), amount)
+            },
+            _ => (text, 0),
+        };
+
+        if let Some(value) = parse_number(base) {
+            return Ok(value.wrapping_add(adjustment));
+        }
+        match self.labels.iter().find(|(label, _)| *label == base) {
+            Some(&(_, label_offset)) => {
+                self.relocations.push(offset);
+                Ok(self.start_address.wrapping_add(label_offset as u8).wrapping_add(adjustment))
+            },
+            None => error(line.number, format!("'{base}' is neither a number nor a label")),
+        }
+    }
+
+    fn address(&mut self, line: &Line, text: &str, offset: usize) -> Result<u8, CompileError> {
+        match text.strip_prefix('[').and_then(|text| text.strip_suffix(']')) {
+            Some(inner) => self.value(line, inner, offset),
+            None => error(line.number, format!("'{text}' should be a memory cell like [0x10] or [label]")),
+        }
+    }
+
+    fn instruction(&mut self, line: &Line, offset: usize, extensions: Extensions) -> Result<Instruction, CompileError> {
+        let operands = &line.operands;
+        let operand = |index: usize| operands[index];
+        // The address byte of an instruction is its second byte
+        let address_offset = offset + 1;
+
+        let instruction = match line.mnemonic {
+            "nop" => {
+                line.expect_operands(0)?;
+                Instruction::NoOp
+            },
+            "load" => {
+                line.expect_operands(2)?;
+                let r = self.register(line, operand(0))?;
+                match operand(1).starts_with('[') {
+                    true => Instruction::LoadFrom { r, address: self.address(line, operand(1), address_offset)? },
+                    false => Instruction::Load { r, value: self.value(line, operand(1), address_offset)? },
+                }
+            },
+            "store" => {
+                line.expect_operands(2)?;
+                Instruction::Store { r: self.register(line, operand(0))?, address: self.address(line, operand(1), address_offset)? }
+            },
+            "move" => {
+                line.expect_operands(2)?;
+                Instruction::Move { r: self.register(line, operand(0))?, s: self.register(line, operand(1))? }
+            },
+            "add" | "addf" | "or" | "and" | "xor" => {
+                line.expect_operands(3)?;
+                let (r, s, t) = (
+                    self.register(line, operand(0))?,
+                    self.register(line, operand(1))?,
+                    self.register(line, operand(2))?,
+                );
+                match line.mnemonic {
+                    "add" => Instruction::AddTc { r, s, t },
+                    "addf" => Instruction::AddFl { r, s, t },
+                    "or" => Instruction::Or { r, s, t },
+                    "and" => Instruction::And { r, s, t },
+                    _ => Instruction::Xor { r, s, t },
+                }
+            },
+            "rotate" => {
+                line.expect_operands(2)?;
+                let r = self.register(line, operand(0))?;
+                match parse_number(operand(1)).filter(|&bits| bits < 16) {
+                    Some(bits) => Instruction::Rotate { r, bits },
+                    None => return error(line.number, format!("'{}' is not a number of bits (0 to 15)", operand(1))),
+                }
+            },
+            "jump" if operands.len() == 1 => Instruction::Jump { r: 0, address: self.value(line, operand(0), address_offset)? },
+            "jump" => {
+                line.expect_operands(2)?;
+                if let Ok(r) = self.register(line, operand(0)) {
+                    Instruction::Jump { r, address: self.value(line, operand(1), address_offset)? }
+                } else {
+                    let condition = Condition::ALL.into_iter()
+                        .find(|condition| condition.to_string().replace(' ', "") == operand(0));
+                    let Some(condition) = condition else {
+                        return error(line.number, format!("'{}' is neither a register nor a condition like carry or notzero", operand(0)));
+                    };
+                    if !extensions.flags {
+                        return error(line.number, String::from("jumps on a condition need `.extensions flags`"));
+                    }
+                    Instruction::JumpIf { condition, address: self.value(line, operand(1), address_offset)? }
+                }
+            },
+            "halt" => {
+                line.expect_operands(0)?;
+                Instruction::Halt
+            },
+            "call" | "ret" | "push" | "pop" if !extensions.stack => {
+                return error(line.number, format!("'{}' needs `.extensions stack`", line.mnemonic));
+            },
+            "call" => {
+                line.expect_operands(1)?;
+                Instruction::Call { address: self.value(line, operand(0), address_offset)? }
+            },
+            "ret" => {
+                line.expect_operands(0)?;
+                Instruction::Return
+            },
+            "push" | "pop" => {
+                line.expect_operands(1)?;
+                let r = self.register(line, operand(0))?;
+                if line.mnemonic == "push" { Instruction::Push { r } } else { Instruction::Pop { r } }
+            },
+            mnemonic => return error(line.number, format!("unknown instruction '{mnemonic}'")),
+        };
+        Ok(instruction)
+    }
+}
+
+fn parse_number(text: &str) -> Option<u8> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn is_label(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Every label and the line defining it, also for source that doesn't assemble
+pub fn label_definitions(source: &str) -> Vec<(&str, usize)> {
+    let mut definitions = Vec::new();
+    for (index, text) in source.lines().enumerate() {
+        let mut rest = text.split('#').next().unwrap_or_default().trim();
+        while let Some((label, after)) = rest.split_once(':') {
+            let label = label.trim();
+            if !is_label(label) {
+                break;
+            }
+            definitions.push((label, index + 1));
+            rest = after.trim();
+        }
+    }
+    definitions
+}
+
+pub fn assemble(name: &str, source: &str) -> Result<(Program, SourceMap), CompileError> {
+    // First pass: split the lines and find where every label is
+    let mut lines = Vec::new();
+    let mut labels: Vec<(&str, usize)> = Vec::new();
+    let mut offset = 0;
+    for (index, text) in source.lines().enumerate() {
+        let number = index + 1;
+        let mut rest = text.split('#').next().unwrap_or_default().trim();
+        while let Some((label, after)) = rest.split_once(':') {
+            let label = label.trim();
+            if !is_label(label) {
+                return error(number, format!("'{label}' is not a label name"));
+            }
+            if labels.iter().any(|(other, _)| *other == label) {
+                return error(number, format!("the label '{label}' is defined twice"));
+            }
+            labels.push((label, offset));
+            rest = after.trim();
+        }
+        if rest.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, operands) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let operands = match operands.trim() {
+            "" => Vec::new(),
+            operands if mnemonic == ".extensions" => operands.split_whitespace().collect(),
+            operands => operands.split(',').map(str::trim).collect(),
+        };
+        let line = Line { number, mnemonic, operands };
+        offset += line.size();
+        lines.push(line);
+    }
+
+    // Second pass: encode, now that every label is known
+    let mut assembler = Assembler { labels, relocations: Vec::new(), start_address: 0x00 };
+    let mut extensions = Extensions::default();
+    let mut code = Vec::new();
+    let mut source_map = SourceMap::default();
+    for line in &lines {
+        match line.mnemonic {
+            ".org" if !code.is_empty() => return error(line.number, String::from("`.org` has to come before any code")),
+            ".org" => {
+                line.expect_operands(1)?;
+                assembler.start_address = match parse_number(line.operands[0]) {
+                    Some(address) => address,
+                    None => return error(line.number, format!("'{}' is not an address", line.operands[0])),
+                };
+            },
+            ".extensions" => {
+                for &extension in &line.operands {
+                    match extension {
+                        "stack" => extensions.stack = true,
+                        "flags" => extensions.flags = true,
+                        _ => return error(line.number, format!("unknown extension '{extension}' (stack or flags)")),
+                    }
+                }
+            },
+            ".byte" => {
+                for operand in &line.operands {
+                    let value = assembler.value(line, operand, code.len())?;
+                    code.push(value);
+                }
+            },
+            _ => {
+                let instruction = assembler.instruction(line, code.len(), extensions)?;
+                source_map.entries.push((assembler.start_address.wrapping_add(code.len() as u8), line.number));
+                code.extend(instruction.encode());
+            },
+        }
+        if assembler.start_address as usize + code.len() > 256 {
+            return error(line.number, String::from("the program doesn't fit in memory"));
+        }
+    }
+
+    let program = Program::new(name.to_string(), code, assembler.start_address)
+        .with_extensions(extensions)
+        .with_relocations(assembler.relocations);
+    Ok((program, source_map))
+}
+
+pub fn assemble_file(path: &str) -> Result<(Program, SourceMap), String> {
+    let source = std::fs::read_to_string(path).map_err(|error| format!("{path}: {error}"))?;
+    let name = std::path::Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string());
+
+    assemble(&name, &source).map_err(|error| format!("{path}: {error}"))
+}
+
+// `vole-machine assemble <source.asm>`
+pub fn assemble_command(args: &[String]) -> bool {
+    let Some(path) = args.first() else {
+        println!("Usage: vole-machine assemble <source.asm>");
+        return false;
+    };
+
+    match assemble_file(path) {
+        Ok((program, source_map)) => {
+            let source = std::fs::read_to_string(path).unwrap_or_default();
+            print!("{}", crate::compiler::listing(&program, &source_map, &source));
+            let relocations: Vec<String> = program.relocations.iter().flatten()
+                .map(|&offset| format!("m0x{:02X}", program.start_address.wrapping_add(offset as u8)))
+                .collect();
+            println!("\nRelocations: {}", if relocations.is_empty() { String::from("none") } else { relocations.join(" ") });
+            true
+        },
+        Err(error) => {
+            println!("{error}");
+            false
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cpu, Step};
+
+    const COPY: &str = include_str!("../programs/copy.asm");
+
+    // Run the program on the bytes 11 22 33 at m0x00 and return what it copied to m0x10
+    fn run_copy(program: Program) -> [u8; 3] {
+        let mut cpu = Cpu::new();
+        cpu.import(program);
+        cpu.memory[..3].copy_from_slice(&[0x11, 0x22, 0x33]);
+        while cpu.cycles < 1_000 && cpu.step() == Step::Continue {}
+        cpu.memory[0x10..0x13].try_into().unwrap()
+    }
+
+    #[test]
+    fn assembles_every_instruction() {
+        let source = "
+            .extensions stack flags
+            nop
+            load r1, [0x10]
+            load r2, 255
+            store r3, [16]
+            move r4, r5
+            add r1, r2, r3
+            addf r4, r5, r6
+            or r7, r8, r9
+            and rA, rB, rC
+            xor rD, rE, rF
+            rotate r1, 3
+            jump r2, 0x40
+            jump 0x42
+            jump notzero, 0x44
+            halt
+            call 0x46
+            ret
+            push r1
+            pop r2
+            .byte 1, 0x02
+        ";
+        let (program, source_map) = assemble("all", source).unwrap();
+        assert_eq!(program.code, [
+            0x00, 0x00, 0x11, 0x10, 0x22, 0xFF, 0x33, 0x10, 0x40, 0x45, 0x51, 0x23, 0x64, 0x56, 0x77, 0x89,
+            0x8A, 0xBC, 0x9D, 0xEF, 0xA1, 0x03, 0xB2, 0x40, 0xB0, 0x42, 0xF6, 0x44, 0xC0, 0x00, 0xD0, 0x46,
+            0xD1, 0x00, 0xE1, 0x00, 0xE2, 0x01, 0x01, 0x02,
+        ][..]);
+        assert_eq!(program.relocations, Some(Vec::new()));
+        assert_eq!(source_map.entries[..2], [(0x00, 3), (0x02, 4)]);
+    }
+
+    #[test]
+    fn decodes_what_it_assembles() {
+        let (program, _) = assemble("round trip", ".extensions stack flags\nmove r4, r5\njump carry, 0x10\npush r1\nret").unwrap();
+        let decoded: Vec<u8> = program.code.chunks(2)
+            .flat_map(|pair| Instruction::decode(pair[0], pair[1], program.extensions).encode())
+            .collect();
+        assert_eq!(decoded, program.code);
+    }
+
+    #[test]
+    fn labels_are_relocated() {
+        let (program, _) = assemble("copy", COPY).unwrap();
+        assert_eq!(program.start_address, 0x30);
+        // `store r2, [copy+1]`, `store r3, [paste+1]`, `jump r2, done` and `jump copy`
+        assert_eq!(program.relocations, Some(vec![0x11, 0x13, 0x15, 0x17]));
+        assert_eq!(run_copy(program.clone()), [0x11, 0x22, 0x33]);
+
+        let relocated = program.relocate(0x80).unwrap();
+        assert_eq!(relocated.start_address, 0x80);
+        assert_eq!(relocated.code[0x11], program.code[0x11] + 0x50);
+        assert_eq!(run_copy(relocated), [0x11, 0x22, 0x33]);
+    }
+
+    #[test]
+    fn relocation_errors() {
+        let (program, _) = assemble("copy", COPY).unwrap();
+        assert!(program.relocate(0xF0).err().unwrap().contains("doesn't fit at m0xF0"));
+
+        let fixed = Program::new(String::from("fixed"), vec![0xC0, 0x00], 0x10);
+        assert!(fixed.relocate(0x20).err().unwrap().contains("has no relocation table"));
+        assert!(fixed.relocate(0x10).is_ok());
+    }
+
+    #[test]
+    fn label_definitions_survive_errors() {
+        assert_eq!(label_definitions("a: b: nop\n\nc: bogus r9"), vec![("a", 1), ("b", 1), ("c", 3)]);
+    }
+
+    #[test]
+    fn malformed_source() {
+        for (source, line, message) in [
+            ("frob r1", 1, "unknown instruction 'frob'"),
+            ("load r1", 1, "'load' takes 2 operand(s), not 1"),
+            ("move r1, rG", 1, "'rG' is not a register (r0 to rF)"),
+            ("store r1, 0x10", 1, "'0x10' should be a memory cell like [0x10] or [label]"),
+            ("load r1, 256", 1, "'256' is neither a number nor a label"),
+            ("jump nowhere", 1, "'nowhere' is neither a number nor a label"),
+            ("rotate r1, 16", 1, "'16' is not a number of bits (0 to 15)"),
+            ("a: nop\na: nop", 2, "the label 'a' is defined twice"),
+            ("1a: nop", 1, "'1a' is not a label name"),
+            ("nop\n.org 0x10", 2, "`.org` has to come before any code"),
+            ("push r1", 1, "'push' needs `.extensions stack`"),
+            ("jump zero, 0x10", 1, "jumps on a condition need `.extensions flags`"),
+            ("jump often, 0x10", 1, "'often' is neither a register nor a condition like carry or notzero"),
+            (".extensions vectors", 1, "unknown extension 'vectors' (stack or flags)"),
+            (".org 0xFE\nnop\nnop", 3, "the program doesn't fit in memory"),
+        ] {
+            let error = assemble("bad", source).err();
+            assert_eq!(error, Some(CompileError { line, message: message.to_string() }), "{source}");
+        }
+    }
+}
@@ -0,0 +1,265 @@
+// Control-flow graphs of programs and their Graphviz DOT export
+//
+// A program is split into basic blocks at every jump target and after every jump or halt.
+// `0xB0XY` always jumps (r0 is compared against itself), any other `0xBRXY` is conditional
+// and gets a taken edge (rR == r0) and a fall-through edge (rR != r0). A call gets an edge
+// to the subroutine and a fall-through edge to where the subroutine returns to. Jumps on the
+// status flags are conditional like `0xBRXY`.
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::instruction::Instruction;
+use crate::{Address, Condition, Program};
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct BasicBlock {
+    pub start: Address,
+    // Every instruction of the block with its address and raw bytes
+    pub instructions: Vec<(Address, [u8; 2], Instruction)>,
+}
+
+impl BasicBlock {
+    pub fn last(&self) -> Instruction {
+        self.instructions.last().map(|&(_, _, instruction)| instruction).unwrap_or(Instruction::NoOp)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Target {
+    Block(Address),
+    // An address that isn't the start of an instruction in the program
+    Outside(Address),
+}
+
+// What a conditional jump tests
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Branch {
+    Equal { r: usize }, // rR == r0
+    Flag(Condition),
+}
+
+impl Branch {
+    pub fn describe(&self, taken: bool) -> String {
+        match (self, taken) {
+            (Branch::Equal { r }, true) => format!("r{r:X} == r0"),
+            (Branch::Equal { r }, false) => format!("r{r:X} != r0"),
+            (Branch::Flag(condition), true) => condition.to_string(),
+            (Branch::Flag(condition), false) => condition.negate().to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EdgeKind {
+    FallThrough,
+    Unconditional,
+    Taken(Branch),
+    NotTaken(Branch),
+    Call,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Edge {
+    pub from: Address,
+    pub to: Target,
+    pub kind: EdgeKind,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Cfg {
+    pub name: String,
+    pub blocks: Vec<BasicBlock>,
+    pub edges: Vec<Edge>,
+}
+
+impl Cfg {
+    pub fn build(program: &Program) -> Cfg {
+        let count = program.code.len() / 2;
+        let address_of = |index: usize| program.start_address.wrapping_add((index * 2) as u8);
+        let index_of = |address: Address| {
+            let offset = address.wrapping_sub(program.start_address) as usize;
+            (offset.is_multiple_of(2) && offset / 2 < count).then_some(offset / 2)
+        };
+        let instructions: Vec<Instruction> = (0..count)
+            .map(|index| Instruction::decode(program.code[index * 2], program.code[index * 2 + 1], program.extensions))
+            .collect();
+
+        // Find the instructions that start a basic block
+        let mut leaders = BTreeSet::from([0]);
+        for (index, instruction) in instructions.iter().enumerate() {
+            match *instruction {
+                Instruction::Jump { address, .. }
+                | Instruction::JumpIf { address, .. }
+                | Instruction::Call { address } => {
+                    leaders.extend(index_of(address));
+                    leaders.insert(index + 1);
+                },
+                Instruction::Halt | Instruction::Return | Instruction::Invalid { .. } => {
+                    leaders.insert(index + 1);
+                },
+                _ => {},
+            }
+        }
+        leaders.retain(|&index| index < count);
+
+        let target = |address: Address| match index_of(address) {
+            Some(_) => Target::Block(address),
+            None => Target::Outside(address),
+        };
+
+        let leaders: Vec<usize> = leaders.into_iter().collect();
+        let mut blocks = Vec::new();
+        let mut edges = Vec::new();
+        for (position, &first) in leaders.iter().enumerate() {
+            let end = leaders.get(position + 1).copied().unwrap_or(count);
+            let block = BasicBlock {
+                start: address_of(first),
+                instructions: (first..end)
+                    .map(|index| {
+                        let bytes = [program.code[index * 2], program.code[index * 2 + 1]];
+                        (address_of(index), bytes, instructions[index])
+                    })
+                    .collect(),
+            };
+
+            let from = block.start;
+            let next = target(address_of(end));
+            match block.last() {
+                Instruction::Jump { r: 0, address } => {
+                    edges.push(Edge { from, to: target(address), kind: EdgeKind::Unconditional });
+                },
+                Instruction::Jump { r, address } => {
+                    let branch = Branch::Equal { r };
+                    edges.push(Edge { from, to: target(address), kind: EdgeKind::Taken(branch) });
+                    edges.push(Edge { from, to: next, kind: EdgeKind::NotTaken(branch) });
+                },
+                Instruction::JumpIf { condition, address } => {
+                    let branch = Branch::Flag(condition);
+                    edges.push(Edge { from, to: target(address), kind: EdgeKind::Taken(branch) });
+                    edges.push(Edge { from, to: next, kind: EdgeKind::NotTaken(branch) });
+                },
+                Instruction::Call { address } => {
+                    edges.push(Edge { from, to: target(address), kind: EdgeKind::Call });
+                    edges.push(Edge { from, to: next, kind: EdgeKind::FallThrough });
+                },
+                Instruction::Halt | Instruction::Return | Instruction::Invalid { .. } => {},
+                _ => edges.push(Edge { from, to: next, kind: EdgeKind::FallThrough }),
+            }
+
+            blocks.push(block);
+        }
+
+        Cfg {
+            name: program.name.clone(),
+            blocks,
+            edges,
+        }
+    }
+
+    // The blocks that can be reached from the start of the program
+    pub fn reachable(&self) -> Vec<&BasicBlock> {
+        let mut reached: Vec<Address> = self.blocks.first().map(|block| block.start).into_iter().collect();
+        let mut index = 0;
+        while index < reached.len() {
+            let from = reached[index];
+            for edge in self.edges.iter().filter(|edge| edge.from == from) {
+                if let Target::Block(address) = edge.to {
+                    if !reached.contains(&address) {
+                        reached.push(address);
+                    }
+                }
+            }
+            index += 1;
+        }
+
+        self.blocks.iter().filter(|block| reached.contains(&block.start)).collect()
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        writeln!(dot, "digraph \"Program {}\" {{", self.name).unwrap();
+        writeln!(dot, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+
+        for block in &self.blocks {
+            let mut label = String::new();
+            for (address, [high, low], instruction) in &block.instructions {
+                write!(label, "m0x{address:02X}  0x{high:02X}{low:02X}  {instruction}\\l").unwrap();
+            }
+            let style = match block.last() {
+                Instruction::Halt => ", peripheries=2",
+                Instruction::Invalid { .. } => ", color=red",
+                _ => "",
+            };
+            writeln!(dot, "    {} [label=\"{label}\"{style}];", node_name(Target::Block(block.start))).unwrap();
+        }
+
+        let mut outside: Vec<Address> = self.edges.iter()
+            .filter_map(|edge| match edge.to {
+                Target::Outside(address) => Some(address),
+                Target::Block(_) => None,
+            })
+            .collect();
+        outside.sort();
+        outside.dedup();
+        for address in outside {
+            writeln!(dot, "    {} [label=\"m0x{address:02X}\\n(outside program)\", shape=ellipse, style=dashed];",
+                node_name(Target::Outside(address))
+            ).unwrap();
+        }
+
+        for edge in &self.edges {
+            let attributes = match edge.kind {
+                EdgeKind::FallThrough => String::new(),
+                EdgeKind::Unconditional => String::from(" [label=\"always\", style=bold]"),
+                EdgeKind::Taken(branch) => format!(" [label=\"{}\"]", branch.describe(true)),
+                EdgeKind::NotTaken(branch) => format!(" [label=\"{}\", style=dashed]", branch.describe(false)),
+                EdgeKind::Call => String::from(" [label=\"call\", style=dotted]"),
+            };
+            writeln!(dot, "    {} -> {}{attributes};",
+                node_name(Target::Block(edge.from)),
+                node_name(edge.to)
+            ).unwrap();
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+fn node_name(target: Target) -> String {
+    match target {
+        Target::Block(address) => format!("m0x{address:02X}"),
+        Target::Outside(address) => format!("outside_m0x{address:02X}"),
+    }
+}
+
+// `vole-machine cfg <program> [output.dot]`
+pub fn cfg_command(args: &[String]) -> bool {
+    let Some(name) = args.first() else {
+        println!("Usage: vole-machine cfg <program> [output.dot]");
+        return false;
+    };
+    let program = match crate::load_program(name) {
+        Ok(program) => program,
+        Err(error) => {
+            println!("{error}");
+            return false;
+        },
+    };
+
+    let dot = Cfg::build(&program).to_dot();
+    match args.get(1) {
+        Some(path) => match std::fs::write(path, dot) {
+            Ok(()) => true,
+            Err(error) => {
+                println!("{path}: {error}");
+                false
+            },
+        },
+        None => {
+            print!("{dot}");
+            true
+        },
+    }
+}
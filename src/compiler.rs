@@ -0,0 +1,672 @@
+// Compiler for Tiny, a small structured language, down to classic Vole machine code
+//
+//     # Sum the bytes at m0x80 and m0x81 into m0x82
+//     a = [0x80]
+//     b = [0x81]
+//     sum = a + b
+//     [0x82] = sum
+//
+//     count = 0
+//     while count != 5 {
+//         count = count + 1
+//     }
+//
+//     if sum == 0x10 {
+//         flag = 1
+//     } else {
+//         flag = 0
+//     }
+//
+// Values are bytes: variables, decimal or `0x` hexadecimal constants, and memory cells written
+// as `[address]`. Expressions combine values with `+` (two's complement), `|`, `&` and `^` and are
+// evaluated left to right. Conditions compare two values with `==` or `!=`.
+//
+// Every variable lives in its own register from r1 upwards, the registers left over hold
+// temporary values, and r0 is reserved for the left side of comparisons since `jump` compares
+// against it. The program starts at m0x00 and halts after its last statement, and can be
+// relocated since the compiler knows which bytes are jump addresses.
+
+use std::fmt::Write;
+
+use crate::instruction::Instruction;
+use crate::{Address, Program};
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Token {
+    Identifier(String),
+    Number(u8),
+    Symbol(&'static str),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Identifier(name) => write!(f, "'{name}'"),
+            Token::Number(value) => write!(f, "'{value}'"),
+            Token::Symbol(symbol) => write!(f, "'{symbol}'"),
+        }
+    }
+}
+
+const SYMBOLS: [&str; 11] = ["==", "!=", "=", "+", "|", "&", "^", "[", "]", "{", "}"];
+const KEYWORDS: [&str; 3] = ["if", "else", "while"];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Operator {
+    Add,
+    Or,
+    And,
+    Xor,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Operand {
+    Variable(String),
+    Constant(u8),
+    Memory(Address),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Expression {
+    first: Operand,
+    rest: Vec<(Operator, Operand)>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Comparison {
+    left: Operand,
+    equal: bool, // `==` or `!=`
+    right: Operand,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Statement {
+    Assign { target: Operand, value: Expression, line: usize },
+    If { condition: Comparison, then: Vec<Statement>, otherwise: Vec<Statement>, line: usize },
+    While { condition: Comparison, body: Vec<Statement>, line: usize },
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CompileError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+fn error<T>(line: usize, message: String) -> Result<T, CompileError> {
+    Err(CompileError { line, message })
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, CompileError> {
+    let mut tokens = Vec::new();
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let text = text.split('#').next().unwrap_or_default();
+        let mut rest = text.trim_start();
+
+        while !rest.is_empty() {
+            if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+                tokens.push((Token::Symbol(symbol), line));
+                rest = rest[symbol.len()..].trim_start();
+                continue;
+            }
+
+            let length = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            if length == 0 {
+                return error(line, format!("unexpected character '{}'", rest.chars().next().unwrap_or_default()));
+            }
+
+            let word = &rest[..length];
+            if word.starts_with(|c: char| c.is_ascii_digit()) {
+                let value = match word.strip_prefix("0x") {
+                    Some(hex) => u8::from_str_radix(hex, 16),
+                    None => word.parse::<u8>(),
+                };
+                match value {
+                    Ok(value) => tokens.push((Token::Number(value), line)),
+                    Err(_) => return error(line, format!("'{word}' is not a byte (0 to 255)")),
+                }
+            } else {
+                tokens.push((Token::Identifier(word.to_string()), line));
+            }
+            rest = rest[length..].trim_start();
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens.get(self.position).or(self.tokens.last()).map(|&(_, line)| line).unwrap_or(1)
+    }
+
+    fn next(&mut self) -> Result<Token, CompileError> {
+        match self.tokens.get(self.position) {
+            Some((token, _)) => {
+                self.position += 1;
+                Ok(token.clone())
+            },
+            None => error(self.line(), String::from("unexpected end of the program")),
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), CompileError> {
+        let line = self.line();
+        match self.next()? {
+            Token::Symbol(found) if found == symbol => Ok(()),
+            found => error(line, format!("expected '{symbol}' but found {found}")),
+        }
+    }
+
+    fn statements(&mut self, until_brace: bool) -> Result<Vec<Statement>, CompileError> {
+        let mut statements = Vec::new();
+        loop {
+            match self.peek() {
+                None if until_brace => return error(self.line(), String::from("missing '}'")),
+                None => return Ok(statements),
+                Some(Token::Symbol("}")) if until_brace => {
+                    self.position += 1;
+                    return Ok(statements);
+                },
+                _ => statements.push(self.statement()?),
+            }
+        }
+    }
+
+    fn block(&mut self) -> Result<Vec<Statement>, CompileError> {
+        self.expect("{")?;
+        self.statements(true)
+    }
+
+    fn statement(&mut self) -> Result<Statement, CompileError> {
+        let line = self.line();
+        match self.peek() {
+            Some(Token::Identifier(keyword)) if keyword == "if" => {
+                self.position += 1;
+                let condition = self.comparison()?;
+                let then = self.block()?;
+                let otherwise = if matches!(self.peek(), Some(Token::Identifier(keyword)) if keyword == "else") {
+                    self.position += 1;
+                    if matches!(self.peek(), Some(Token::Identifier(keyword)) if keyword == "if") {
+                        vec![self.statement()?]
+                    } else {
+                        self.block()?
+                    }
+                } else {
+                    Vec::new()
+                };
+                Ok(Statement::If { condition, then, otherwise, line })
+            },
+            Some(Token::Identifier(keyword)) if keyword == "while" => {
+                self.position += 1;
+                let condition = self.comparison()?;
+                let body = self.block()?;
+                Ok(Statement::While { condition, body, line })
+            },
+            _ => {
+                let target = self.operand()?;
+                if let Operand::Constant(value) = target {
+                    return error(line, format!("can't assign to the constant {value}"));
+                }
+                self.expect("=")?;
+                let value = self.expression()?;
+                Ok(Statement::Assign { target, value, line })
+            },
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Comparison, CompileError> {
+        let left = self.operand()?;
+        let line = self.line();
+        let equal = match self.next()? {
+            Token::Symbol("==") => true,
+            Token::Symbol("!=") => false,
+            found => return error(line, format!("expected '==' or '!=' but found {found}")),
+        };
+        let right = self.operand()?;
+        Ok(Comparison { left, equal, right })
+    }
+
+    fn expression(&mut self) -> Result<Expression, CompileError> {
+        let first = self.operand()?;
+        let mut rest = Vec::new();
+        loop {
+            let operator = match self.peek() {
+                Some(Token::Symbol("+")) => Operator::Add,
+                Some(Token::Symbol("|")) => Operator::Or,
+                Some(Token::Symbol("&")) => Operator::And,
+                Some(Token::Symbol("^")) => Operator::Xor,
+                _ => return Ok(Expression { first, rest }),
+            };
+            self.position += 1;
+            rest.push((operator, self.operand()?));
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, CompileError> {
+        let line = self.line();
+        match self.next()? {
+            Token::Identifier(name) if KEYWORDS.contains(&name.as_str()) => {
+                error(line, format!("'{name}' is a keyword and can't be used as a variable"))
+            },
+            Token::Identifier(name) => Ok(Operand::Variable(name)),
+            Token::Number(value) => Ok(Operand::Constant(value)),
+            Token::Symbol("[") => {
+                let address = match self.next()? {
+                    Token::Number(address) => address,
+                    found => return error(line, format!("expected a memory address but found {found}")),
+                };
+                self.expect("]")?;
+                Ok(Operand::Memory(address))
+            },
+            found => error(line, format!("expected a value but found {found}")),
+        }
+    }
+}
+
+// The address of every instruction of a compiled program and the source line it came from
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct SourceMap {
+    pub entries: Vec<(Address, usize)>,
+}
+
+// An instruction waiting for the address of the label it jumps to
+enum Emitted {
+    Instruction(Instruction),
+    Jump { r: usize, label: usize },
+}
+
+struct Generator {
+    variables: Vec<String>, // The variable in r1 is first
+    code: Vec<(Emitted, usize)>,
+    labels: Vec<Option<usize>>, // Index of the instruction each label points at
+    temporaries_in_use: usize,
+    line: usize,
+}
+
+impl Generator {
+    fn register_of(&self, name: &str) -> usize {
+        self.variables.iter().position(|variable| variable == name).map(|index| index + 1).unwrap_or(0)
+    }
+
+    // Give every variable a register in the order they first appear
+    fn declare(&mut self, statements: &[Statement]) -> Result<(), CompileError> {
+        for statement in statements {
+            match statement {
+                Statement::Assign { target, value, line } => {
+                    self.declare_operand(target, *line)?;
+                    self.declare_operand(&value.first, *line)?;
+                    for (_, operand) in &value.rest {
+                        self.declare_operand(operand, *line)?;
+                    }
+                },
+                Statement::If { condition, then, otherwise, line } => {
+                    self.declare_operand(&condition.left, *line)?;
+                    self.declare_operand(&condition.right, *line)?;
+                    self.declare(then)?;
+                    self.declare(otherwise)?;
+                },
+                Statement::While { condition, body, line } => {
+                    self.declare_operand(&condition.left, *line)?;
+                    self.declare_operand(&condition.right, *line)?;
+                    self.declare(body)?;
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    fn declare_operand(&mut self, operand: &Operand, line: usize) -> Result<(), CompileError> {
+        if let Operand::Variable(name) = operand {
+            if !self.variables.contains(name) {
+                if self.variables.len() == 15 {
+                    return error(line, format!("'{name}' doesn't fit, only r1 to rF hold variables"));
+                }
+                self.variables.push(name.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.code.push((Emitted::Instruction(instruction), self.line));
+    }
+
+    fn emit_jump(&mut self, r: usize, label: usize) {
+        self.code.push((Emitted::Jump { r, label }, self.line));
+    }
+
+    fn new_label(&mut self) -> usize {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    fn place(&mut self, label: usize) {
+        self.labels[label] = Some(self.code.len());
+    }
+
+    // Temporaries are taken from rF downwards, above the registers of the variables
+    fn temporary(&mut self) -> Result<usize, CompileError> {
+        let r = 15 - self.temporaries_in_use;
+        if r <= self.variables.len() {
+            return error(self.line, String::from("out of registers for temporary values, use fewer variables"));
+        }
+        self.temporaries_in_use += 1;
+        Ok(r)
+    }
+
+    fn load(&mut self, operand: &Operand, r: usize) {
+        match operand {
+            Operand::Variable(name) => {
+                let source = self.register_of(name);
+                if source != r {
+                    self.emit(Instruction::Move { r: source, s: r });
+                }
+            },
+            Operand::Constant(value) => self.emit(Instruction::Load { r, value: *value }),
+            Operand::Memory(address) => self.emit(Instruction::LoadFrom { r, address: *address }),
+        }
+    }
+
+    // The register holding the value of the operand, loading it into a temporary if needed
+    fn register_for(&mut self, operand: &Operand) -> Result<usize, CompileError> {
+        match operand {
+            Operand::Variable(name) => Ok(self.register_of(name)),
+            _ => {
+                let r = self.temporary()?;
+                self.load(operand, r);
+                Ok(r)
+            },
+        }
+    }
+
+    fn evaluate(&mut self, expression: &Expression, destination: usize) -> Result<(), CompileError> {
+        // Accumulate in a temporary when the destination is still read after the first operand
+        let reads_destination = expression.rest.iter().any(|(_, operand)| {
+            matches!(operand, Operand::Variable(name) if self.register_of(name) == destination)
+        });
+        let accumulator = if reads_destination { self.temporary()? } else { destination };
+
+        self.load(&expression.first, accumulator);
+        for (operator, operand) in &expression.rest {
+            let in_use = self.temporaries_in_use;
+            let t = self.register_for(operand)?;
+            let (r, s) = (accumulator, accumulator);
+            self.emit(match operator {
+                Operator::Add => Instruction::AddTc { r, s, t },
+                Operator::Or => Instruction::Or { r, s, t },
+                Operator::And => Instruction::And { r, s, t },
+                Operator::Xor => Instruction::Xor { r, s, t },
+            });
+            self.temporaries_in_use = in_use;
+        }
+
+        if accumulator != destination {
+            self.emit(Instruction::Move { r: accumulator, s: destination });
+        }
+        Ok(())
+    }
+
+    // Jump to the label when the comparison has the given outcome
+    fn branch(&mut self, comparison: &Comparison, when: bool, label: usize) -> Result<(), CompileError> {
+        let in_use = self.temporaries_in_use;
+        self.load(&comparison.left, 0);
+        let r = self.register_for(&comparison.right)?;
+        self.temporaries_in_use = in_use;
+
+        if comparison.equal == when {
+            self.emit_jump(r, label);
+        } else {
+            // Jump over an unconditional jump to the label when the registers are equal
+            let skip = self.new_label();
+            self.emit_jump(r, skip);
+            self.emit_jump(0, label);
+            self.place(skip);
+        }
+        Ok(())
+    }
+
+    fn statements(&mut self, statements: &[Statement]) -> Result<(), CompileError> {
+        for statement in statements {
+            self.statement(statement)?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Result<(), CompileError> {
+        match statement {
+            Statement::Assign { target, value, line } => {
+                self.line = *line;
+                self.temporaries_in_use = 0;
+                match target {
+                    Operand::Variable(name) => {
+                        let r = self.register_of(name);
+                        self.evaluate(value, r)?;
+                    },
+                    Operand::Memory(address) => {
+                        let r = match (&value.first, value.rest.is_empty()) {
+                            (Operand::Variable(name), true) => self.register_of(name),
+                            _ => {
+                                let r = self.temporary()?;
+                                self.evaluate(value, r)?;
+                                r
+                            },
+                        };
+                        self.emit(Instruction::Store { r, address: *address });
+                    },
+                    Operand::Constant(_) => unreachable!("The parser rejects assignments to constants"),
+                }
+            },
+            Statement::If { condition, then, otherwise, line } => {
+                self.line = *line;
+                self.temporaries_in_use = 0;
+                let (else_label, end_label) = (self.new_label(), self.new_label());
+                self.branch(condition, false, else_label)?;
+                self.statements(then)?;
+                if !otherwise.is_empty() {
+                    self.line = *line;
+                    self.emit_jump(0, end_label);
+                }
+                self.place(else_label);
+                self.statements(otherwise)?;
+                self.place(end_label);
+            },
+            Statement::While { condition, body, line } => {
+                let (top_label, end_label) = (self.new_label(), self.new_label());
+                self.place(top_label);
+                self.line = *line;
+                self.temporaries_in_use = 0;
+                self.branch(condition, false, end_label)?;
+                self.statements(body)?;
+                self.line = *line;
+                self.emit_jump(0, top_label);
+                self.place(end_label);
+            },
+        }
+
+        Ok(())
+    }
+}
+
+pub fn compile(name: &str, source: &str) -> Result<(Program, SourceMap), CompileError> {
+    let mut parser = Parser { tokens: tokenize(source)?, position: 0 };
+    let statements = parser.statements(false)?;
+
+    let mut generator = Generator {
+        variables: Vec::new(),
+        code: Vec::new(),
+        labels: Vec::new(),
+        temporaries_in_use: 0,
+        line: 1,
+    };
+    generator.declare(&statements)?;
+    generator.statements(&statements)?;
+    generator.line = source.lines().count().max(1);
+    generator.emit(Instruction::Halt);
+
+    if generator.code.len() * 2 > 256 {
+        return error(generator.line, format!("the program needs {} bytes, more than fit in memory", generator.code.len() * 2));
+    }
+
+    let mut code = Vec::new();
+    let mut relocations = Vec::new();
+    let mut source_map = SourceMap::default();
+    for (index, (emitted, line)) in generator.code.iter().enumerate() {
+        let instruction = match *emitted {
+            Emitted::Instruction(instruction) => instruction,
+            Emitted::Jump { r, label } => {
+                let target = generator.labels[label].unwrap_or(generator.code.len() - 1);
+                relocations.push(index * 2 + 1);
+                Instruction::Jump { r, address: (target * 2) as Address }
+            },
+        };
+        code.extend(instruction.encode());
+        source_map.entries.push(((index * 2) as Address, *line));
+    }
+
+    // Memory operands are absolute, only the jumps move with the program
+    let program = Program::new(name.to_string(), code, 0x00).with_relocations(relocations);
+    Ok((program, source_map))
+}
+
+pub fn compile_file(path: &str) -> Result<(Program, SourceMap), String> {
+    let source = std::fs::read_to_string(path).map_err(|error| format!("{path}: {error}"))?;
+    let name = std::path::Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string());
+
+    compile(&name, &source).map_err(|error| format!("{path}: {error}"))
+}
+
+// The compiled program next to the source lines it came from
+pub fn listing(program: &Program, source_map: &SourceMap, source: &str) -> String {
+    let lines: Vec<&str> = source.lines().collect();
+    let mut listing = String::new();
+    let mut previous_line = 0;
+    for &(address, line) in &source_map.entries {
+        let offset = (address - program.start_address) as usize;
+        let (high, low) = (program.code[offset], program.code[offset + 1]);
+        let instruction = Instruction::decode(high, low, program.extensions);
+        let text = if line != previous_line {
+            format!("{line:>4}: {}", lines.get(line - 1).map(|text| text.trim()).unwrap_or_default())
+        } else {
+            String::new()
+        };
+        previous_line = line;
+
+        writeln!(listing, "m0x{address:02X}  0x{high:02X}{low:02X}  {:<34}{text}", instruction.to_string()).unwrap();
+    }
+
+    listing
+}
+
+// `vole-machine compile <source.tiny>`
+pub fn compile_command(args: &[String]) -> bool {
+    let Some(path) = args.first() else {
+        println!("Usage: vole-machine compile <source.tiny>");
+        return false;
+    };
+
+    match compile_file(path) {
+        Ok((program, source_map)) => {
+            let source = std::fs::read_to_string(path).unwrap_or_default();
+            print!("{}", listing(&program, &source_map, &source));
+            true
+        },
+        Err(error) => {
+            println!("{error}");
+            false
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cpu, Step};
+
+    const FIBONACCI: &str = include_str!("../programs/fibonacci.tiny");
+
+    fn run(program: Program, memory: &[(usize, u8)]) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.import(program);
+        for &(address, value) in memory {
+            cpu.memory[address] = value;
+        }
+        let mut end = Step::Continue;
+        while cpu.cycles < 10_000 && end == Step::Continue {
+            end = cpu.step();
+        }
+        assert_eq!(end, Step::Halt);
+        cpu
+    }
+
+    #[test]
+    fn fibonacci() {
+        let (program, source_map) = compile("fibonacci", FIBONACCI).unwrap();
+        assert_eq!(source_map.entries.len() * 2, program.code.len());
+        assert_eq!(run(program.clone(), &[]).memory[0x80..0x82], [89, 1]);
+
+        // The jumps move with the program, the memory operands don't
+        let relocated = program.relocate(0x40).unwrap();
+        assert_eq!(run(relocated, &[]).memory[0x80..0x82], [89, 1]);
+    }
+
+    #[test]
+    fn expressions_go_left_to_right() {
+        let source = "a = [0x80] + 3 ^ 0x0F & 0x3C | 1\n[0x81] = a\nb = a\nif b != a { [0x82] = 1 }";
+        let (program, _) = compile("expressions", source).unwrap();
+        let cpu = run(program, &[(0x80, 0x20)]);
+        assert_eq!(cpu.memory[0x81], ((0x20 + 3) ^ 0x0F) & 0x3C | 1);
+        assert_eq!(cpu.memory[0x82], 0);
+    }
+
+    #[test]
+    fn listing_shows_the_source() {
+        let source = "a = 1\n[0x10] = a";
+        let (program, source_map) = compile("listing", source).unwrap();
+        let listing = listing(&program, &source_map, source);
+        assert!(listing.starts_with("m0x00  0x2101  "), "{listing}");
+        assert!(listing.contains("   1: a = 1\n"), "{listing}");
+        assert!(listing.contains("   2: [0x10] = a\n"), "{listing}");
+    }
+
+    #[test]
+    fn malformed_source() {
+        let too_many: String = (0..16).map(|index| format!("v{index} = {index}\n")).collect();
+        for (source, line, message) in [
+            ("a = 1 $ 2", 1, String::from("unexpected character '$'")),
+            ("a = 256", 1, String::from("'256' is not a byte (0 to 255)")),
+            ("a =", 1, String::from("unexpected end of the program")),
+            ("a 1", 1, String::from("expected '=' but found '1'")),
+            ("while a != 1 {\na = 1", 2, String::from("missing '}'")),
+            ("\n1 = 2", 2, String::from("can't assign to the constant 1")),
+            ("if a = 1 { }", 1, String::from("expected '==' or '!=' but found '='")),
+            ("a = while", 1, String::from("'while' is a keyword and can't be used as a variable")),
+            ("[a] = 1", 1, String::from("expected a memory address but found 'a'")),
+            ("a = {", 1, String::from("expected a value but found '{'")),
+            (&too_many, 16, String::from("'v15' doesn't fit, only r1 to rF hold variables")),
+        ] {
+            let error = compile("bad", source).err();
+            assert_eq!(error, Some(CompileError { line, message }), "{source}");
+        }
+    }
+}
@@ -0,0 +1,581 @@
+// Debug Adapter Protocol server over stdin and stdout, for debugging programs in an editor
+//
+// The editor launches a program with `{"program": "<program>", "stopOnEntry": true}`, where the
+// program is anything the command line accepts. Programs built from assembly or Tiny source map
+// every instruction to its source line, so breakpoints can be set on lines and stepping goes
+// line by line. The registers and memory are shown as two scopes of variables. Requests are
+// read on their own thread, so a running or stepping program can still be paused.
+
+use std::io::{BufRead, Write};
+use std::sync::mpsc;
+
+use crate::compiler::SourceMap;
+use crate::events::Breakpoints;
+use crate::json::{self, Json};
+use crate::{Address, Cpu, Step};
+
+// Instructions run between checks for new requests
+const RUN_CHUNK: usize = 1000;
+const REGISTERS_REFERENCE: u64 = 1;
+const MEMORY_REFERENCE: u64 = 2;
+const THREAD_ID: u64 = 1;
+
+// The source file a program was built from
+struct Source {
+    path: String,
+    map: SourceMap,
+}
+
+impl Source {
+    fn line_of(&self, address: Address) -> Option<usize> {
+        self.map.entries.iter().find(|&&(start, _)| start == address).map(|&(_, line)| line)
+    }
+
+    fn is(&self, path: &str) -> bool {
+        std::fs::canonicalize(path).is_ok_and(|path| path.to_string_lossy() == self.path)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Stepping {
+    Instruction,
+    Line { over_calls: bool },
+    Out,
+}
+
+pub struct Adapter<W: Write> {
+    cpu: Cpu,
+    source: Option<Source>,
+    breakpoints: Breakpoints,
+    running: bool,
+    // Where a running program stops by itself, None when continuing
+    stepping: Option<Stepping>,
+    // The line and stack depth the step started from
+    step_start: (Option<usize>, u8),
+    halted: bool,
+    stop_on_entry: bool,
+    sequence: u64,
+    writer: W,
+}
+
+impl<W: Write> Adapter<W> {
+    pub fn new(writer: W) -> Adapter<W> {
+        Adapter {
+            cpu: Cpu::new(),
+            source: None,
+            breakpoints: Breakpoints::new(),
+            running: false,
+            stepping: None,
+            step_start: (None, 0),
+            halted: false,
+            stop_on_entry: false,
+            sequence: 0,
+            writer,
+        }
+    }
+
+    fn send(&mut self, message: Json) {
+        self.sequence += 1;
+        let mut fields = vec![(String::from("seq"), Json::from(self.sequence))];
+        if let Json::Object(message) = message {
+            fields.extend(message);
+        }
+        let _ = json::write_message(&mut self.writer, &Json::Object(fields));
+    }
+
+    fn event(&mut self, event: &str, body: Json) {
+        self.send(Json::object([("type", Json::from("event")), ("event", Json::from(event)), ("body", body)]));
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) {
+        let mut response = Json::object([
+            ("type", Json::from("response")),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("command", request.get("command").cloned().unwrap_or(Json::Null)),
+            ("success", Json::from(result.is_ok())),
+        ]);
+        match result {
+            Ok(body) => response.insert("body", body),
+            Err(error) => response.insert("message", Json::from(error)),
+        }
+        self.send(response);
+    }
+
+    fn output(&mut self, text: String) {
+        self.event("output", Json::object([("category", Json::from("console")), ("output", Json::from(text + "\n"))]));
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) {
+        self.running = false;
+        self.event("stopped", Json::object([
+            ("reason", Json::from(reason)),
+            ("threadId", Json::from(THREAD_ID)),
+            ("text", Json::from(text)),
+            ("allThreadsStopped", Json::from(true)),
+        ]));
+    }
+
+    // Serve requests from the input until the editor disconnects
+    pub fn serve(&mut self, mut input: impl BufRead + Send + 'static) {
+        let (sender, requests) = mpsc::channel();
+        std::thread::spawn(move || {
+            loop {
+                // A message that isn't JSON gets an error response, the next one is read as usual
+                let message = match json::read_message(&mut input) {
+                    Ok(Some(message)) => Ok(message),
+                    Err(error) if error.kind() == std::io::ErrorKind::InvalidData => Err(error.to_string()),
+                    Ok(None) | Err(_) => break,
+                };
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        loop {
+            let request = match self.running {
+                true => match requests.try_recv() {
+                    Ok(request) => request,
+                    Err(mpsc::TryRecvError::Empty) => {
+                        self.run();
+                        continue;
+                    },
+                    Err(mpsc::TryRecvError::Disconnected) => return,
+                },
+                false => match requests.recv() {
+                    Ok(request) => request,
+                    Err(_) => return,
+                },
+            };
+            match request {
+                Ok(request) if !self.handle(&request) => return,
+                Ok(_) => {},
+                Err(error) => self.respond(&Json::Null, Err(format!("Can't read the request: {error}"))),
+            }
+        }
+    }
+
+    // Handle one request, false once the session is over
+    fn handle(&mut self, request: &Json) -> bool {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or_default().to_string();
+        let arguments = request.get("arguments").cloned().unwrap_or(Json::Null);
+        match command.as_str() {
+            "initialize" => {
+                self.respond(request, Ok(Json::object([
+                    ("supportsConfigurationDoneRequest", Json::from(true)),
+                    ("supportsSteppingGranularity", Json::from(true)),
+                    ("supportsEvaluateForHovers", Json::from(true)),
+                ])));
+            },
+            "launch" => {
+                let result = self.launch(&arguments);
+                let launched = result.is_ok();
+                self.respond(request, result);
+                if launched {
+                    // The editor sends breakpoints once the program is loaded
+                    self.event("initialized", Json::object([]));
+                }
+            },
+            "setBreakpoints" => {
+                let result = self.set_breakpoints(&arguments);
+                self.respond(request, result);
+            },
+            "configurationDone" => {
+                self.respond(request, Ok(Json::object([])));
+                match self.stop_on_entry {
+                    true => self.stopped("entry", None),
+                    false => self.resume(None),
+                }
+            },
+            "threads" => {
+                let thread = Json::object([("id", Json::from(THREAD_ID)), ("name", Json::from(self.cpu.program_name.clone()))]);
+                self.respond(request, Ok(Json::object([("threads", Json::from(vec![thread]))])));
+            },
+            "stackTrace" => {
+                let body = self.stack_trace();
+                self.respond(request, Ok(body));
+            },
+            "scopes" => {
+                let scope = |name: &str, reference: u64| {
+                    Json::object([
+                        ("name", Json::from(name)),
+                        ("variablesReference", Json::from(reference)),
+                        ("expensive", Json::from(false)),
+                    ])
+                };
+                let scopes = vec![scope("Registers", REGISTERS_REFERENCE), scope("Memory", MEMORY_REFERENCE)];
+                self.respond(request, Ok(Json::object([("scopes", Json::from(scopes))])));
+            },
+            "variables" => {
+                let reference = arguments.get("variablesReference").and_then(Json::as_u64).unwrap_or_default();
+                let variables = self.variables(reference);
+                self.respond(request, Ok(Json::object([("variables", Json::from(variables))])));
+            },
+            "evaluate" => {
+                let expression = arguments.get("expression").and_then(Json::as_str).unwrap_or_default();
+                let result = match crate::taint::parse_location(expression) {
+                    Some(crate::Location::Register(r)) => Ok(format!("0x{:02X}", self.cpu.register[r])),
+                    Some(crate::Location::Memory(address)) => Ok(format!("0x{:02X}", self.cpu.memory[address as usize])),
+                    None => Err(format!("'{expression}' is not a register (r0 to rF) or memory cell (m0x00 to m0xFF)")),
+                };
+                let result = result.map(|value| Json::object([("result", Json::from(value)), ("variablesReference", Json::from(0u64))]));
+                self.respond(request, result);
+            },
+            "continue" => {
+                self.respond(request, Ok(Json::object([("allThreadsContinued", Json::from(true))])));
+                self.resume(None);
+            },
+            "next" | "stepIn" | "stepOut" => {
+                self.respond(request, Ok(Json::object([])));
+                let instruction = arguments.get("granularity").and_then(Json::as_str) == Some("instruction");
+                let stepping = match command.as_str() {
+                    _ if instruction || self.source.is_none() => Stepping::Instruction,
+                    "next" => Stepping::Line { over_calls: true },
+                    "stepIn" => Stepping::Line { over_calls: false },
+                    _ => Stepping::Out,
+                };
+                self.resume(Some(stepping));
+            },
+            "pause" => {
+                self.respond(request, Ok(Json::object([])));
+                if self.running {
+                    self.stopped("pause", None);
+                }
+            },
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(Json::object([])));
+                return false;
+            },
+            _ => self.respond(request, Err(format!("'{command}' is not supported"))),
+        }
+        true
+    }
+
+    fn launch(&mut self, arguments: &Json) -> Result<Json, String> {
+        let source = arguments.get("program").and_then(Json::as_str).ok_or("launch needs the program to debug")?;
+        let program = crate::load_program(source)?;
+        self.stop_on_entry = arguments.get("stopOnEntry").and_then(Json::as_bool).unwrap_or_default();
+        self.source = source_of(source, program.start_address);
+
+        let spec = match crate::spec::Spec::find(&program.name) {
+            Ok(spec) => spec,
+            Err(error) => {
+                self.output(format!("Ignoring the spec for program {}: {error}", program.name));
+                None
+            },
+        };
+        self.cpu = Cpu::new();
+        self.cpu.import(program);
+        if let Some(spec) = &spec {
+            spec.prepare(&mut self.cpu);
+        }
+        Ok(Json::object([]))
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Json) -> Result<Json, String> {
+        let path = arguments.get("source").and_then(|source| source.get("path")).and_then(Json::as_str).unwrap_or_default();
+        let lines: Vec<usize> = arguments.get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|breakpoint| breakpoint.get("line").and_then(Json::as_u64))
+            .map(|line| line as usize)
+            .collect();
+
+        // There is only one source file, setting its breakpoints replaces all of them
+        let source = self.source.as_ref().filter(|source| source.is(path));
+        if source.is_some() {
+            self.breakpoints.addresses.clear();
+        }
+        let mut breakpoints = Vec::new();
+        for line in lines {
+            // A line without code gets the breakpoint on the next line with code
+            let entry = source.and_then(|source| {
+                source.map.entries.iter().filter(|&&(_, entry_line)| entry_line >= line).min_by_key(|&&(_, entry_line)| entry_line)
+            });
+            breakpoints.push(match entry {
+                Some(&(address, entry_line)) => {
+                    self.breakpoints.addresses.insert(address);
+                    Json::object([("verified", Json::from(true)), ("line", Json::from(entry_line))])
+                },
+                None => Json::object([
+                    ("verified", Json::from(false)),
+                    ("line", Json::from(line)),
+                    ("message", Json::from("No instruction on or after this line of the launched program")),
+                ]),
+            });
+        }
+        Ok(Json::object([("breakpoints", Json::from(breakpoints))]))
+    }
+
+    fn stack_trace(&self) -> Json {
+        let line = self.source.as_ref().and_then(|source| source.line_of(self.cpu.pc));
+        let mut frame = Json::object([
+            ("id", Json::from(0u64)),
+            ("name", Json::from(format!("{} at m0x{:02X}", self.cpu.program_name, self.cpu.pc))),
+            ("line", Json::from(line.unwrap_or_default())),
+            ("column", Json::from(1u64)),
+            ("instructionPointerReference", Json::from(format!("0x{:02X}", self.cpu.pc))),
+        ]);
+        if let Some(source) = &self.source {
+            frame.insert("source", Json::object([("path", Json::from(source.path.clone()))]));
+        }
+        Json::object([("stackFrames", Json::from(vec![frame])), ("totalFrames", Json::from(1u64))])
+    }
+
+    fn variables(&self, reference: u64) -> Vec<Json> {
+        let variable = |name: String, value: String| {
+            Json::object([("name", Json::from(name)), ("value", Json::from(value)), ("variablesReference", Json::from(0u64))])
+        };
+        match reference {
+            REGISTERS_REFERENCE => {
+                let mut variables: Vec<Json> = self.cpu.register.iter().enumerate()
+                    .map(|(r, value)| variable(format!("r{r:X}"), format!("0x{value:02X}")))
+                    .collect();
+                variables.push(variable(String::from("pc"), format!("0x{:02X}", self.cpu.pc)));
+                if self.cpu.extensions.stack {
+                    variables.push(variable(String::from("sp"), format!("0x{:02X}", self.cpu.sp)));
+                }
+                if self.cpu.extensions.flags {
+                    variables.push(variable(String::from("CVZN"), self.cpu.status.to_string()));
+                }
+                variables
+            },
+            MEMORY_REFERENCE => self.cpu.memory.chunks(16).enumerate()
+                .map(|(row, bytes)| {
+                    let bytes: Vec<String> = bytes.iter().map(|byte| format!("{byte:02X}")).collect();
+                    variable(format!("m0x{:02X}", row * 16), bytes.join(" "))
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Let the program run, continuing or until the step is done
+    fn resume(&mut self, stepping: Option<Stepping>) {
+        // A halted program stays terminated, the editor is told again so it doesn't wait
+        if self.halted {
+            self.event("terminated", Json::object([]));
+            return;
+        }
+        let line = self.source.as_ref().and_then(|source| source.line_of(self.cpu.pc));
+        self.stepping = stepping;
+        self.step_start = (line, self.cpu.sp.wrapping_neg());
+        self.running = true;
+    }
+
+    // Run a chunk of instructions, and stop where the step is done
+    fn run(&mut self) {
+        let (line, depth) = self.step_start;
+        for _ in 0..RUN_CHUNK {
+            match self.cpu.step_with(&mut self.breakpoints) {
+                Step::Continue => {},
+                Step::Halt => {
+                    self.running = false;
+                    self.halted = true;
+                    self.output(format!("Program {} halted after {} cycles.", self.cpu.program_name, self.cpu.cycles));
+                    self.event("terminated", Json::object([]));
+                    return;
+                },
+                Step::Fault(fault) => {
+                    self.stopped("exception", Some(fault.to_string()));
+                    return;
+                },
+            }
+            if self.breakpoints.hit().is_some() {
+                self.stopped("breakpoint", None);
+                return;
+            }
+
+            let current_line = self.source.as_ref().and_then(|source| source.line_of(self.cpu.pc));
+            let current_depth = self.cpu.sp.wrapping_neg();
+            let done = match self.stepping {
+                None => false,
+                Some(Stepping::Instruction) => true,
+                Some(Stepping::Line { over_calls }) => {
+                    current_line.is_some() && current_line != line && (!over_calls || current_depth <= depth)
+                },
+                Some(Stepping::Out) => current_depth < depth || depth == 0,
+            };
+            if done {
+                self.stopped("step", None);
+                return;
+            }
+        }
+    }
+}
+
+// The source file and line map of a program built from assembly or Tiny, moved to where it's loaded
+fn source_of(source: &str, start_address: Address) -> Option<Source> {
+    let path = match source.rsplit_once('@') {
+        Some((path, address)) if u8::from_str_radix(address, 16).is_ok() => path,
+        _ => source,
+    };
+    let (program, map) = if path.ends_with(".asm") {
+        crate::assembler::assemble_file(path).ok()?
+    } else if path.ends_with(".tiny") {
+        crate::compiler::compile_file(path).ok()?
+    } else {
+        return None;
+    };
+
+    let offset = start_address.wrapping_sub(program.start_address);
+    let entries = map.entries.iter().map(|&(address, line)| (address.wrapping_add(offset), line)).collect();
+    let path = std::fs::canonicalize(path).ok()?.to_string_lossy().into_owned();
+    Some(Source { path, map: SourceMap { entries } })
+}
+
+// `vole-machine dap`
+pub fn dap_command(_args: &[String]) -> bool {
+    Adapter::new(std::io::stdout()).serve(std::io::BufReader::new(std::io::stdin()));
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The messages the adapter sent since the last call
+    fn sent(adapter: &mut Adapter<Vec<u8>>) -> Vec<Json> {
+        let output = std::mem::take(&mut adapter.writer);
+        let mut input = output.as_slice();
+        std::iter::from_fn(|| json::read_message(&mut input).unwrap()).collect()
+    }
+
+    fn request(adapter: &mut Adapter<Vec<u8>>, command: &str, arguments: Json) -> Vec<Json> {
+        adapter.handle(&Json::object([
+            ("seq", Json::from(1u64)),
+            ("type", Json::from("request")),
+            ("command", Json::from(command)),
+            ("arguments", arguments),
+        ]));
+        while adapter.running {
+            adapter.run();
+        }
+        sent(adapter)
+    }
+
+    fn field<'a>(message: &'a Json, path: &[&str]) -> Option<&'a Json> {
+        path.iter().try_fold(message, |message, key| message.get(key))
+    }
+
+    fn events(messages: &[Json]) -> Vec<&str> {
+        messages.iter().filter_map(|message| field(message, &["event"]).and_then(Json::as_str)).collect()
+    }
+
+    // The copy program of programs/copy.asm, stopped on entry
+    fn launched() -> Adapter<Vec<u8>> {
+        let mut adapter = Adapter::new(Vec::new());
+        request(&mut adapter, "initialize", Json::object([]));
+        let messages = request(&mut adapter, "launch", Json::object([
+            ("program", Json::from("programs/copy.asm")),
+            ("stopOnEntry", Json::from(true)),
+        ]));
+        assert_eq!(field(&messages[0], &["success"]), Some(&Json::from(true)));
+        assert_eq!(events(&messages), ["initialized"]);
+        adapter
+    }
+
+    #[test]
+    fn breakpoints_on_lines() {
+        let mut adapter = launched();
+        let path = std::fs::canonicalize("programs/copy.asm").unwrap();
+        let lines = [1u64, 8, 20].map(|line| Json::object([("line", Json::from(line))]));
+        let messages = request(&mut adapter, "setBreakpoints", Json::object([
+            ("source", Json::object([("path", Json::from(path.to_string_lossy().into_owned()))])),
+            ("breakpoints", Json::from(lines.to_vec())),
+        ]));
+        let verified: Vec<(Option<&Json>, Option<&Json>)> = field(&messages[0], &["body", "breakpoints"])
+            .and_then(Json::as_array)
+            .unwrap()
+            .iter()
+            .map(|breakpoint| (breakpoint.get("verified"), breakpoint.get("line")))
+            .collect();
+        assert_eq!(verified, [
+            (Some(&Json::from(true)), Some(&Json::from(4u64))),
+            (Some(&Json::from(true)), Some(&Json::from(8u64))),
+            (Some(&Json::from(false)), Some(&Json::from(20u64))),
+        ]);
+
+        assert_eq!(events(&request(&mut adapter, "configurationDone", Json::Null)), ["stopped"]);
+        let messages = request(&mut adapter, "continue", Json::Null);
+        assert_eq!(field(&messages[1], &["body", "reason"]), Some(&Json::from("breakpoint")));
+        let messages = request(&mut adapter, "stackTrace", Json::Null);
+        let frame = &field(&messages[0], &["body", "stackFrames"]).and_then(Json::as_array).unwrap()[0];
+        assert_eq!(frame.get("line"), Some(&Json::from(8u64)));
+        assert_eq!(field(frame, &["source", "path"]), Some(&Json::from(path.to_string_lossy().into_owned())));
+
+        let messages = request(&mut adapter, "evaluate", Json::object([("expression", Json::from("r3"))]));
+        assert_eq!(field(&messages[0], &["body", "result"]), Some(&Json::from("0x10")));
+    }
+
+    #[test]
+    fn stepping_until_it_halts() {
+        let mut adapter = launched();
+        request(&mut adapter, "configurationDone", Json::Null);
+        let messages = request(&mut adapter, "next", Json::Null);
+        assert_eq!(field(&messages[1], &["body", "reason"]), Some(&Json::from("step")));
+        assert_eq!(adapter.cpu.pc, 0x32);
+        request(&mut adapter, "stepIn", Json::object([("granularity", Json::from("instruction"))]));
+        assert_eq!(adapter.cpu.pc, 0x34);
+
+        assert_eq!(events(&request(&mut adapter, "continue", Json::Null)), ["output", "terminated"]);
+        assert_eq!(adapter.cpu.pc, 0x48);
+        // Nothing is left to run, the editor is told so again
+        assert_eq!(events(&request(&mut adapter, "next", Json::Null)), ["terminated"]);
+        assert_eq!(events(&request(&mut adapter, "continue", Json::Null)), ["terminated"]);
+    }
+
+    #[test]
+    fn variables() {
+        let mut adapter = launched();
+        let messages = request(&mut adapter, "variables", Json::object([("variablesReference", Json::from(REGISTERS_REFERENCE))]));
+        let registers = field(&messages[0], &["body", "variables"]).and_then(Json::as_array).unwrap();
+        assert_eq!(registers.len(), 17);
+        assert_eq!(registers[16].to_string(), r#"{"name":"pc","value":"0x30","variablesReference":0}"#);
+
+        let messages = request(&mut adapter, "variables", Json::object([("variablesReference", Json::from(MEMORY_REFERENCE))]));
+        let memory = field(&messages[0], &["body", "variables"]).and_then(Json::as_array).unwrap();
+        assert_eq!(memory.len(), 16);
+        assert_eq!(memory[3].get("name"), Some(&Json::from("m0x30")));
+        assert!(memory[3].get("value").and_then(Json::as_str).unwrap().starts_with("20 03 21 01"));
+    }
+
+    #[test]
+    fn malformed_requests() {
+        let mut adapter = Adapter::new(Vec::new());
+        for (command, arguments, message) in [
+            ("launch", Json::object([]), "launch needs the program to debug"),
+            ("launch", Json::object([("program", Json::from("nothing"))]), "No program named 'nothing' in the library"),
+            ("evaluate", Json::object([("expression", Json::from("rG"))]), "'rG' is not a register (r0 to rF) or memory cell (m0x00 to m0xFF)"),
+            ("restart", Json::Null, "'restart' is not supported"),
+        ] {
+            let messages = request(&mut adapter, command, arguments);
+            assert_eq!(field(&messages[0], &["success"]), Some(&Json::from(false)), "{command}");
+            let text = field(&messages[0], &["message"]).and_then(Json::as_str).unwrap();
+            assert!(text.starts_with(message), "{text}");
+        }
+    }
+
+    #[test]
+    fn a_malformed_message_gets_an_error_response() {
+        let input: String = [
+            "{oops",
+            r#"{"seq":2,"type":"request","command":"x"}"#,
+            r#"{"seq":3,"type":"request","command":"disconnect"}"#,
+        ].map(|body| format!("Content-Length: {}\r\n\r\n{body}", body.len())).concat();
+        let mut adapter = Adapter::new(Vec::new());
+        adapter.serve(std::io::Cursor::new(input.into_bytes()));
+        let messages = sent(&mut adapter);
+        let answers: Vec<(Option<&Json>, Option<&Json>)> = messages.iter()
+            .map(|message| (message.get("request_seq"), message.get("success")))
+            .collect();
+        assert_eq!(answers, [
+            (Some(&Json::Null), Some(&Json::from(false))),
+            (Some(&Json::from(2u64)), Some(&Json::from(false))),
+            (Some(&Json::from(3u64)), Some(&Json::from(true))),
+        ]);
+        let text = field(&messages[0], &["message"]).and_then(Json::as_str).unwrap();
+        assert!(text.starts_with("Can't read the request: "), "{text}");
+    }
+}
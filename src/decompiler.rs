@@ -0,0 +1,456 @@
+// Decompiler from Vole machine code to structured pseudocode
+//
+// The instructions are structured over ranges of the program:
+//   - a jump back to an earlier instruction closes a loop, `loop { }` when it always jumps and
+//     `do { } while` when it is conditional, and jumps to just after the loop become `break`,
+//   - `if c goto T; A; T:` becomes `if !c { A }`,
+//   - `if c goto T; A; goto E; T: B; E:` becomes `if !c { A } else { B }`,
+//   - `if c goto T; goto E; T:` is read as a single `if !c goto E`, which is how `0xB` jumps
+//     usually express "jump when not equal",
+// and every other jump is printed as a `goto` to a label.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::cfg::Branch;
+use crate::instruction::Instruction;
+use crate::{Address, Program};
+
+#[derive(Clone, PartialEq, Debug)]
+enum Statement {
+    Simple { address: Address, text: String },
+    If { address: Address, condition: String, then: Vec<Statement>, otherwise: Vec<Statement> },
+    Loop { address: Address, body: Vec<Statement>, until: Option<String> },
+    Goto { address: Address, target: Address, condition: Option<String> },
+}
+
+impl Statement {
+    fn address(&self) -> Address {
+        match *self {
+            Statement::Simple { address, .. }
+            | Statement::If { address, .. }
+            | Statement::Loop { address, .. }
+            | Statement::Goto { address, .. } => address,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct LoopContext {
+    header: usize,
+    exit: usize,
+}
+
+struct Decompiler<'a> {
+    program: &'a Program,
+    instructions: Vec<Instruction>,
+    names: BTreeMap<Address, String>,
+    // Instructions whose bytes are overwritten by a store of the program
+    patched: Vec<Address>,
+}
+
+impl Decompiler<'_> {
+    fn address_of(&self, index: usize) -> Address {
+        self.program.start_address.wrapping_add((index * 2) as u8)
+    }
+
+    fn index_of(&self, address: Address) -> Option<usize> {
+        let offset = address.wrapping_sub(self.program.start_address) as usize;
+        (offset.is_multiple_of(2) && offset / 2 <= self.instructions.len()).then_some(offset / 2)
+    }
+
+    fn name(&self, address: Address) -> String {
+        self.names.get(&address).cloned().unwrap_or_else(|| format!("m0x{address:02X}"))
+    }
+
+    // What the conditional jump at the index tests and where it jumps to
+    fn branch(&self, index: usize) -> Option<(Branch, Address)> {
+        match self.instructions.get(index)? {
+            Instruction::Jump { r, address } if *r != 0 => Some((Branch::Equal { r: *r }, *address)),
+            Instruction::JumpIf { condition, address } => Some((Branch::Flag(*condition), *address)),
+            _ => None,
+        }
+    }
+
+    fn unconditional_target(&self, index: usize) -> Option<Address> {
+        match self.instructions.get(index)? {
+            Instruction::Jump { r: 0, address } => Some(*address),
+            _ => None,
+        }
+    }
+
+    // The last jump in the range back to the instruction at the index
+    fn back_edge(&self, index: usize, end: usize) -> Option<usize> {
+        let header = self.address_of(index);
+        (index..end).rev().find(|&candidate| {
+            self.branch(candidate).map(|(_, target)| target) == Some(header)
+                || self.unconditional_target(candidate) == Some(header)
+        })
+    }
+
+    fn structure(&self, start: usize, end: usize, context: Option<LoopContext>) -> Vec<Statement> {
+        let mut statements = Vec::new();
+        let mut index = start;
+
+        while index < end {
+            let address = self.address_of(index);
+
+            if let Some(back) = self.back_edge(index, end) {
+                let inner = Some(LoopContext { header: index, exit: back + 1 });
+                let mut body = self.structure(index, back, inner);
+                let until = match self.branch(back) {
+                    Some((branch, _)) => Some(branch.describe(true)),
+                    // A loop that ends in `if c { break }` repeats while `c` doesn't hold
+                    None => match body.last() {
+                        Some(Statement::If { condition, then, otherwise, .. })
+                            if otherwise.is_empty() && then.len() == 1 && is_break(&then[0]) =>
+                        {
+                            let condition = negate(condition);
+                            body.pop();
+                            Some(condition)
+                        },
+                        _ => None,
+                    },
+                };
+                statements.push(Statement::Loop { address, body, until });
+                index = back + 1;
+                continue;
+            }
+
+            if let Some((branch, target)) = self.branch(index) {
+                // `if c goto T; goto E; T:` jumps to E when c doesn't hold
+                let (mut condition, mut target, mut next) = (branch.describe(true), target, index + 1);
+                if let Some(skip) = self.unconditional_target(index + 1) {
+                    if target == self.address_of(index + 2) && index + 2 <= end {
+                        (condition, target, next) = (branch.describe(false), skip, index + 2);
+                    }
+                }
+
+                let target_index = self.index_of(target);
+                if let (Some(context), Some(target_index)) = (context, target_index) {
+                    if target_index == context.exit || target_index == context.header {
+                        let keyword = if target_index == context.exit { "break" } else { "continue" };
+                        statements.push(Statement::If {
+                            address,
+                            condition,
+                            then: vec![Statement::Simple { address, text: String::from(keyword) }],
+                            otherwise: Vec::new(),
+                        });
+                        index = next;
+                        continue;
+                    }
+                }
+
+                match target_index {
+                    Some(target_index) if target_index > next && target_index <= end => {
+                        // The code up to the target runs when the condition doesn't hold
+                        let last = target_index - 1;
+                        let else_end = self.unconditional_target(last)
+                            .and_then(|end_address| self.index_of(end_address))
+                            .filter(|&else_end| else_end > target_index && else_end <= end);
+                        let condition = negate(&condition);
+                        match else_end {
+                            Some(else_end) => {
+                                statements.push(Statement::If {
+                                    address,
+                                    condition,
+                                    then: self.structure(next, last, context),
+                                    otherwise: self.structure(target_index, else_end, context),
+                                });
+                                index = else_end;
+                            },
+                            None => {
+                                statements.push(Statement::If {
+                                    address,
+                                    condition,
+                                    then: self.structure(next, target_index, context),
+                                    otherwise: Vec::new(),
+                                });
+                                index = target_index;
+                            },
+                        }
+                    },
+                    Some(target_index) if target_index == next => index = next,
+                    _ => {
+                        statements.push(Statement::Goto { address, target, condition: Some(condition) });
+                        index = next;
+                    },
+                }
+                continue;
+            }
+
+            match self.instructions[index] {
+                Instruction::Jump { address: target, .. } => {
+                    let keyword = match (context, self.index_of(target)) {
+                        (Some(context), Some(target)) if target == context.exit => Some("break"),
+                        (Some(context), Some(target)) if target == context.header => Some("continue"),
+                        _ => None,
+                    };
+                    match keyword {
+                        Some(keyword) => statements.push(Statement::Simple { address, text: String::from(keyword) }),
+                        None => statements.push(Statement::Goto { address, target, condition: None }),
+                    }
+                },
+                Instruction::NoOp => {},
+                instruction => {
+                    let mut text = self.expression(instruction);
+                    if self.patched.contains(&address) {
+                        text.push_str("  // patched by the program while it runs");
+                    }
+                    statements.push(Statement::Simple { address, text });
+                },
+            }
+            index += 1;
+        }
+
+        statements
+    }
+
+    fn expression(&self, instruction: Instruction) -> String {
+        match instruction {
+            Instruction::NoOp => String::from("nop"),
+            Instruction::LoadFrom { r, address } => format!("r{r:X} = {}", self.name(address)),
+            Instruction::Load { r, value } => format!("r{r:X} = 0x{value:02X}"),
+            Instruction::Store { r, address } => format!("{} = r{r:X}", self.name(address)),
+            Instruction::Move { r, s } => format!("r{s:X} = r{r:X}"),
+            Instruction::AddTc { r, s, t } => format!("r{r:X} = r{s:X} + r{t:X}"),
+            Instruction::AddFl { r, s, t } => format!("r{r:X} = float(r{s:X}) + float(r{t:X})"),
+            Instruction::Or { r, s, t } => format!("r{r:X} = r{s:X} | r{t:X}"),
+            Instruction::And { r, s, t } => format!("r{r:X} = r{s:X} & r{t:X}"),
+            Instruction::Xor { r, s, t } => format!("r{r:X} = r{s:X} ^ r{t:X}"),
+            Instruction::Rotate { r, bits } => format!("r{r:X} = rotate_right(r{r:X}, {bits})"),
+            Instruction::Jump { address, .. } | Instruction::JumpIf { address, .. } => format!("goto m0x{address:02X}"),
+            Instruction::Halt => String::from("halt"),
+            Instruction::Call { address } => format!("sub_{address:02X}()"),
+            Instruction::Return => String::from("return"),
+            Instruction::Push { r } => format!("push(r{r:X})"),
+            Instruction::Pop { r } => format!("r{r:X} = pop()"),
+            Instruction::Invalid { opcode } => format!("invalid(0x{opcode:X})"),
+        }
+    }
+}
+
+fn is_break(statement: &Statement) -> bool {
+    matches!(statement, Statement::Simple { text, .. } if text == "break")
+}
+
+fn negate(condition: &str) -> String {
+    if let Some((left, right)) = condition.split_once(" == ") {
+        return format!("{left} != {right}");
+    }
+    if let Some((left, right)) = condition.split_once(" != ") {
+        return format!("{left} == {right}");
+    }
+    match condition.strip_prefix("not ").or_else(|| condition.strip_prefix("no ")) {
+        Some(flag) => flag.to_string(),
+        None if condition == "zero" || condition == "negative" => format!("not {condition}"),
+        None => format!("no {condition}"),
+    }
+}
+
+fn collect_targets(statements: &[Statement], targets: &mut Vec<Address>) {
+    for statement in statements {
+        match statement {
+            Statement::Goto { target, .. } => targets.push(*target),
+            Statement::If { then, otherwise, .. } => {
+                collect_targets(then, targets);
+                collect_targets(otherwise, targets);
+            },
+            Statement::Loop { body, .. } => collect_targets(body, targets),
+            Statement::Simple { .. } => {},
+        }
+    }
+}
+
+struct Labels {
+    targets: Vec<Address>,
+    subroutines: Vec<Address>,
+}
+
+fn render(statements: &[Statement], depth: usize, labels: &Labels, output: &mut String) {
+    let indent = "    ".repeat(depth);
+    let outdent = "    ".repeat(depth.saturating_sub(1));
+    for statement in statements {
+        let address = statement.address();
+        if labels.subroutines.contains(&address) {
+            writeln!(output, "{outdent}sub_{address:02X}:").unwrap();
+        } else if labels.targets.contains(&address) {
+            writeln!(output, "{outdent}m0x{address:02X}:").unwrap();
+        }
+        match statement {
+            Statement::Simple { text, .. } => writeln!(output, "{indent}{text}").unwrap(),
+            Statement::Goto { target, condition: Some(condition), .. } => {
+                writeln!(output, "{indent}if {condition} {{ goto m0x{target:02X} }}").unwrap()
+            },
+            Statement::Goto { target, condition: None, .. } => writeln!(output, "{indent}goto m0x{target:02X}").unwrap(),
+            Statement::If { condition, then, otherwise, .. } => {
+                if then.len() == 1 && otherwise.is_empty() && matches!(then[0], Statement::Simple { .. }) {
+                    if let Statement::Simple { text, .. } = &then[0] {
+                        writeln!(output, "{indent}if {condition} {{ {text} }}").unwrap();
+                        continue;
+                    }
+                }
+                writeln!(output, "{indent}if {condition} {{").unwrap();
+                render(then, depth + 1, labels, output);
+                if !otherwise.is_empty() {
+                    writeln!(output, "{indent}}} else {{").unwrap();
+                    render(otherwise, depth + 1, labels, output);
+                }
+                writeln!(output, "{indent}}}").unwrap();
+            },
+            Statement::Loop { body, until: None, .. } => {
+                writeln!(output, "{indent}loop {{").unwrap();
+                render(body, depth + 1, labels, output);
+                writeln!(output, "{indent}}}").unwrap();
+            },
+            Statement::Loop { body, until: Some(condition), .. } => {
+                writeln!(output, "{indent}do {{").unwrap();
+                render(body, depth + 1, labels, output);
+                writeln!(output, "{indent}}} while {condition}").unwrap();
+            },
+        }
+    }
+}
+
+pub fn decompile(program: &Program) -> String {
+    let count = program.code.len() / 2;
+    let instructions: Vec<Instruction> = (0..count)
+        .map(|index| Instruction::decode(program.code[index * 2], program.code[index * 2 + 1], program.extensions))
+        .collect();
+    let in_code = |address: Address| {
+        (address.wrapping_sub(program.start_address) as usize) < count * 2
+    };
+
+    // Name the memory the program reads and writes
+    let mut accesses: BTreeMap<Address, (bool, bool)> = BTreeMap::new();
+    let mut patched = Vec::new();
+    for instruction in &instructions {
+        if let Some(address) = instruction.memory_read() {
+            accesses.entry(address).or_default().0 = true;
+        }
+        if let Some(address) = instruction.memory_written() {
+            accesses.entry(address).or_default().1 = true;
+            if in_code(address) {
+                let offset = address.wrapping_sub(program.start_address) & !1;
+                patched.push(program.start_address.wrapping_add(offset));
+            }
+        }
+    }
+    let names = accesses.keys()
+        .map(|&address| {
+            let prefix = if in_code(address) { "code" } else { "data" };
+            (address, format!("{prefix}_{address:02X}"))
+        })
+        .collect();
+
+    let decompiler = Decompiler { program, instructions, names, patched };
+    let statements = decompiler.structure(0, count, None);
+    let mut labels = Labels {
+        targets: Vec::new(),
+        subroutines: decompiler.instructions.iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Call { address } => Some(*address),
+                _ => None,
+            })
+            .collect(),
+    };
+    collect_targets(&statements, &mut labels.targets);
+
+    let mut output = String::new();
+    writeln!(output, "// Program {}, loaded at m0x{:02X}", program.name, program.start_address).unwrap();
+    if !accesses.is_empty() {
+        writeln!(output, "// Memory:").unwrap();
+        for (&address, &(read, written)) in &accesses {
+            let usage = match (read, written) {
+                (true, true) => "read and written",
+                (true, false) => "read",
+                _ => "written",
+            };
+            let note = if in_code(address) { ", part of the program's own code" } else { "" };
+            writeln!(output, "//   {}  m0x{address:02X}  {usage}{note}", decompiler.name(address)).unwrap();
+        }
+    }
+    output.push('\n');
+    render(&statements, 0, &labels, &mut output);
+    output
+}
+
+// `vole-machine decompile <program>`
+pub fn decompile_command(args: &[String]) -> bool {
+    let Some(source) = args.first() else {
+        println!("Usage: vole-machine decompile <program>");
+        return false;
+    };
+
+    match crate::load_program(source) {
+        Ok(program) => {
+            print!("{}", decompile(&program));
+            true
+        },
+        Err(error) => {
+            println!("{error}");
+            false
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProgramLibrary;
+
+    fn body(output: &str) -> &str {
+        output.split_once("\n\n").map(|(_, body)| body).unwrap_or_default()
+    }
+
+    #[test]
+    fn loops_and_self_modifying_code() {
+        let output = decompile(&ProgramLibrary::init().get("A").unwrap());
+        assert!(output.starts_with("// Program A, loaded at m0x30\n"), "{output}");
+        assert!(output.contains("//   code_39  m0x39  written, part of the program's own code\n"), "{output}");
+        assert!(body(&output).ends_with("\
+            do {\n    \
+                r4 = data_00  // patched by the program while it runs\n    \
+                data_10 = r4  // patched by the program while it runs\n    \
+                r2 = r2 + r1\n    \
+                r3 = r3 + r1\n    \
+                code_39 = r2\n    \
+                code_3B = r3\n\
+            } while r2 != r0\n\
+            halt\n"
+        ), "{output}");
+    }
+
+    // The structures the compiler emits come back out as structures
+    #[test]
+    fn compiled_structures() {
+        let source = "a = 0\nwhile a != 3 {\n  a = a + 1\n}\nif a == 3 {\n  [0x80] = a\n} else {\n  [0x81] = a\n}";
+        let (program, _) = crate::compiler::compile("structures", source).unwrap();
+        assert_eq!(body(&decompile(&program)), "\
+            r1 = 0x00\n\
+            loop {\n    \
+                r0 = r1\n    \
+                rF = 0x03\n    \
+                if rF == r0 { break }\n    \
+                rF = 0x01\n    \
+                r1 = r1 + rF\n\
+            }\n\
+            r0 = r1\n\
+            rF = 0x03\n\
+            if rF == r0 {\n    \
+                data_80 = r1\n\
+            } else {\n    \
+                data_81 = r1\n\
+            }\n\
+            halt\n"
+        );
+    }
+
+    #[test]
+    fn invalid_code() {
+        // An invalid instruction, a jump out of the program and a stray last byte
+        let program = Program::new(String::from("bad"), vec![0xFF, 0xFF, 0xB0, 0xF0, 0x21, 0x05, 0xC0], 0x00);
+        assert_eq!(body(&decompile(&program)), "invalid(0xF)\ngoto m0xF0\nr1 = 0x05\n");
+        assert_eq!(decompile(&Program::new(String::from("empty"), Vec::new(), 0x00)), "// Program empty, loaded at m0x00\n\n");
+    }
+}
@@ -0,0 +1,179 @@
+// Execution events, and the observers that build the heat map, the trace and breakpoints from them
+//
+// While it executes an instruction the CPU collects what happens as events. When the instruction
+// is done, they go in order to its heat map and trace, then to the observer passed to
+// `Cpu::step_with`. Frontends observe execution the same way, without touching the CPU.
+
+use std::collections::{BTreeSet, VecDeque};
+
+use crate::instruction::Instruction;
+use crate::{Address, Fault, HeatLevel, Location, TraceEntry, TraceEvent, MAX_HEAT, TRACE_LENGTH};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Event {
+    InstructionFetched { address: Address, instruction: Instruction },
+    RegisterWritten { r: usize, value: u8 },
+    MemoryRead { address: Address, value: u8 },
+    MemoryWritten { address: Address, old: u8, new: u8 },
+    // A jump, call or return moved the program counter
+    JumpTaken { from: Address, to: Address },
+    // A write changed the bytes of an instruction that has been or will be executed
+    CodeModified { pc: Address, address: Address, old: u8, new: u8, instruction_address: Address, instruction: Instruction },
+    // Only reported when the sanitizer warns instead of faulting
+    UninitializedRead { pc: Address, location: Location },
+    Halted { address: Address },
+    Faulted(Fault),
+}
+
+pub trait Observer {
+    // Called for every event, with the cycle it happened in
+    fn notify(&mut self, cycle: u128, event: &Event);
+}
+
+// Observes nothing
+impl Observer for () {
+    fn notify(&mut self, _cycle: u128, _event: &Event) {}
+}
+
+// How recently every register and memory cell was written, from MAX_HEAT down to 0
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct HeatMap {
+    pub register: [HeatLevel; 16],
+    pub memory: [HeatLevel; 256],
+    // The instruction being executed, its kind decides what cools down and heats up
+    executing: Option<Instruction>,
+}
+
+impl HeatMap {
+    pub fn new() -> HeatMap {
+        HeatMap { register: [0; 16], memory: [0; 256], executing: None }
+    }
+
+    fn cool_down(&mut self) {
+        self.register.iter_mut().for_each(|heat| *heat = heat.saturating_sub(1));
+        self.memory.iter_mut().for_each(|heat| *heat = heat.saturating_sub(1));
+    }
+}
+
+impl Observer for HeatMap {
+    fn notify(&mut self, _cycle: u128, event: &Event) {
+        match *event {
+            // Every instruction cools everything down, a jump once more, calls and returns don't
+            Event::InstructionFetched { instruction, .. } => {
+                self.executing = Some(instruction);
+                self.cool_down();
+            },
+            Event::JumpTaken { .. } => {
+                if matches!(self.executing, Some(Instruction::Jump { .. } | Instruction::JumpIf { .. })) {
+                    self.cool_down();
+                }
+            },
+            // A move heats the register it copies from, not the one it writes
+            Event::RegisterWritten { r, .. } => match self.executing {
+                Some(Instruction::Move { r: source, .. }) => self.register[source] = MAX_HEAT,
+                _ => self.register[r] = MAX_HEAT,
+            },
+            Event::MemoryWritten { address, .. } => self.memory[address as usize] = MAX_HEAT,
+            _ => {},
+        }
+    }
+}
+
+// The last TRACE_LENGTH executed instructions, self-modifying writes and uninitialized reads
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Trace {
+    entries: VecDeque<TraceEntry>,
+}
+
+impl Trace {
+    pub fn new() -> Trace {
+        Trace { entries: VecDeque::new() }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &TraceEntry> {
+        self.entries.iter()
+    }
+}
+
+impl Observer for Trace {
+    fn notify(&mut self, cycle: u128, event: &Event) {
+        let (address, event) = match *event {
+            Event::InstructionFetched { address, instruction } => (address, TraceEvent::Executed(instruction)),
+            Event::CodeModified { pc, address, old, new, instruction_address, instruction } => {
+                (pc, TraceEvent::SelfModified { address, old, new, instruction_address, instruction })
+            },
+            Event::UninitializedRead { pc, location } => (pc, TraceEvent::UninitializedRead(location)),
+            _ => return,
+        };
+        if self.entries.len() == TRACE_LENGTH {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(TraceEntry { cycle, address, event });
+    }
+}
+
+// Addresses to stop at before the instruction there is executed
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Breakpoints {
+    pub addresses: BTreeSet<Address>,
+    // Where the program counter went after the last instruction
+    next: Option<Address>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Breakpoints {
+        Breakpoints::default()
+    }
+
+    // The breakpoint the last instruction arrived at
+    pub fn hit(&self) -> Option<Address> {
+        self.next.filter(|address| self.addresses.contains(address))
+    }
+}
+
+impl Observer for Breakpoints {
+    fn notify(&mut self, _cycle: u128, event: &Event) {
+        match *event {
+            Event::InstructionFetched { address, .. } => self.next = Some(address.wrapping_add(2)),
+            Event::JumpTaken { to, .. } => self.next = Some(to),
+            // The program counter stays on a halt or a fault
+            Event::Halted { .. } | Event::Faulted(_) => self.next = None,
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cpu, Program, Step};
+
+    // The CPU's heat map after running the program to its halt
+    fn heat_after(code: Vec<u8>) -> HeatMap {
+        let mut cpu = Cpu::new();
+        cpu.import(Program::new(String::from("heat"), code, 0x00));
+        while cpu.step() == Step::Continue {}
+        cpu.heat
+    }
+
+    #[test]
+    fn move_heats_its_source() {
+        // Load r1, move r1 to r2, halt
+        let heat = heat_after(vec![0x21, 0x05, 0x40, 0x12, 0xC0, 0x00]);
+        assert_eq!(heat.register[1], MAX_HEAT - 1);
+        assert_eq!(heat.register[2], 0);
+    }
+
+    #[test]
+    fn jumps_cool_down_once_more() {
+        // Store r1, jump to the next instruction, halt
+        let heat = heat_after(vec![0x31, 0x80, 0xB0, 0x04, 0xC0, 0x00]);
+        assert_eq!(heat.memory[0x80], MAX_HEAT - 3);
+
+        let mut heat = HeatMap::new();
+        heat.register[1] = MAX_HEAT;
+        heat.notify(0, &Event::InstructionFetched { address: 0x00, instruction: Instruction::Call { address: 0x10 } });
+        heat.notify(0, &Event::JumpTaken { from: 0x00, to: 0x10 });
+        assert_eq!(heat.register[1], MAX_HEAT - 1);
+    }
+}
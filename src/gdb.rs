@@ -0,0 +1,483 @@
+// GDB remote serial protocol stub: lets GDB, or any client speaking the protocol, debug a program
+//
+// The stub listens on a local TCP port and serves one client. Packets are `$data#checksum`, each
+// acknowledged with `+`. Registers are numbered 0 to 15 for r0 to rF and 16 for the program
+// counter, every register is one byte. Breakpoints are kept by the stub, so memory reads still
+// show the program's own code. While the program runs, the client can interrupt it with Ctrl-C.
+// A halt is reported as the program exiting.
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::events::Breakpoints;
+use crate::{Address, Cpu, Fault, Step};
+
+const DEFAULT_PORT: u16 = 1234;
+const PC_REGISTER: usize = 16;
+// How many instructions run between checks for an interrupt from the client
+const INTERRUPT_INTERVAL: u128 = 4096;
+const INTERRUPT: u8 = 0x03;
+// The register layout, for clients that ask for a target description
+const TARGET_XML: &str = concat!(
+    "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\"><target><feature name=\"org.vole.core\">",
+    "<reg name=\"r0\" bitsize=\"8\" regnum=\"0\"/><reg name=\"r1\" bitsize=\"8\"/><reg name=\"r2\" bitsize=\"8\"/>",
+    "<reg name=\"r3\" bitsize=\"8\"/><reg name=\"r4\" bitsize=\"8\"/><reg name=\"r5\" bitsize=\"8\"/>",
+    "<reg name=\"r6\" bitsize=\"8\"/><reg name=\"r7\" bitsize=\"8\"/><reg name=\"r8\" bitsize=\"8\"/>",
+    "<reg name=\"r9\" bitsize=\"8\"/><reg name=\"rA\" bitsize=\"8\"/><reg name=\"rB\" bitsize=\"8\"/>",
+    "<reg name=\"rC\" bitsize=\"8\"/><reg name=\"rD\" bitsize=\"8\"/><reg name=\"rE\" bitsize=\"8\"/>",
+    "<reg name=\"rF\" bitsize=\"8\"/><reg name=\"pc\" bitsize=\"8\" type=\"code_ptr\"/>",
+    "</feature></target>",
+);
+
+// Signals reported when the program stops
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+// Reported when the program halts
+const EXIT_STATUS: u8 = 0;
+
+pub struct Stub {
+    cpu: Cpu,
+    breakpoints: Breakpoints,
+    stream: TcpStream,
+}
+
+enum Packet {
+    Command(String),
+    Interrupt,
+    Closed,
+}
+
+impl Stub {
+    pub fn new(cpu: Cpu, stream: TcpStream) -> Stub {
+        Stub { cpu, breakpoints: Breakpoints::new(), stream }
+    }
+
+    fn read_byte(&mut self) -> std::io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    fn read_packet(&mut self) -> std::io::Result<Packet> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(Packet::Closed),
+                Some(INTERRUPT) => return Ok(Packet::Interrupt),
+                Some(b'$') => {},
+                // Acknowledgements, and anything between packets
+                Some(_) => continue,
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(Packet::Closed),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+            if expected != Some(checksum_of(&data)) {
+                self.stream.write_all(b"-")?;
+                continue;
+            }
+            self.stream.write_all(b"+")?;
+            return Ok(Packet::Command(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    fn send(&mut self, reply: &str) -> std::io::Result<()> {
+        let packet = format!("${reply}#{:02x}", checksum_of(reply.as_bytes()));
+        self.stream.write_all(packet.as_bytes())?;
+        // Wait for the acknowledgement, and send again if the client asks for it
+        loop {
+            match self.read_byte()? {
+                Some(b'-') => self.stream.write_all(packet.as_bytes())?,
+                Some(INTERRUPT) | Some(b'+') | None => return Ok(()),
+                Some(_) => {},
+            }
+        }
+    }
+
+    // Serve the client until it detaches, kills the program or disconnects
+    pub fn serve(&mut self) -> std::io::Result<()> {
+        loop {
+            let command = match self.read_packet()? {
+                Packet::Command(command) => command,
+                Packet::Interrupt => {
+                    self.send(&format!("S{SIGINT:02x}"))?;
+                    continue;
+                },
+                Packet::Closed => return Ok(()),
+            };
+            match command.as_bytes().first() {
+                Some(b'k') => return Ok(()),
+                Some(b'D') => return self.send("OK"),
+                _ => {},
+            }
+            let reply = self.handle(&command)?;
+            self.send(&reply)?;
+        }
+    }
+
+    fn handle(&mut self, command: &str) -> std::io::Result<String> {
+        // An empty packet, or one that starts inside a character, is a command nobody supports
+        let Some((kind, args)) = command.split_at_checked(1) else {
+            return Ok(String::new());
+        };
+        let reply = match kind {
+            "?" => format!("S{SIGTRAP:02x}"),
+            "g" => {
+                let mut registers: String = self.cpu.register.iter().map(|value| format!("{value:02x}")).collect();
+                registers.push_str(&format!("{:02x}", self.cpu.pc));
+                registers
+            },
+            "G" => match parse_bytes(args) {
+                Some(values) if values.len() == PC_REGISTER + 1 => {
+                    self.cpu.register.copy_from_slice(&values[..PC_REGISTER]);
+                    self.cpu.initialized_register = [true; 16];
+                    self.cpu.pc = values[PC_REGISTER];
+                    String::from("OK")
+                },
+                _ => String::from("E01"),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(PC_REGISTER) => format!("{:02x}", self.cpu.pc),
+                Ok(r) if r < PC_REGISTER => format!("{:02x}", self.cpu.register[r]),
+                _ => String::from("E01"),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(r, value)| {
+                    Some((usize::from_str_radix(r, 16).ok()?, u8::from_str_radix(value, 16).ok()?))
+                });
+                match parsed {
+                    Some((PC_REGISTER, value)) => {
+                        self.cpu.pc = value;
+                        String::from("OK")
+                    },
+                    Some((r, value)) if r < PC_REGISTER => {
+                        self.cpu.register[r] = value;
+                        self.cpu.initialized_register[r] = true;
+                        String::from("OK")
+                    },
+                    _ => String::from("E01"),
+                }
+            },
+            "m" => match parse_range(args) {
+                Some((address, length)) => self.cpu.memory[address..address + length].iter()
+                    .map(|value| format!("{value:02x}"))
+                    .collect(),
+                None => String::from("E01"),
+            },
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(range, data)| Some((parse_range(range)?, parse_bytes(data)?)));
+                match parsed {
+                    Some(((address, length), bytes)) if bytes.len() == length => {
+                        self.cpu.memory[address..address + length].copy_from_slice(&bytes);
+                        self.cpu.initialized_memory[address..address + length].fill(true);
+                        String::from("OK")
+                    },
+                    _ => String::from("E01"),
+                }
+            },
+            "s" | "c" => {
+                if let Ok(address) = u8::from_str_radix(args, 16) {
+                    self.cpu.pc = address;
+                }
+                self.resume(kind == "s")?
+            },
+            "Z" | "z" => match breakpoint_address(args) {
+                Some(address) => {
+                    match kind {
+                        "Z" => self.breakpoints.addresses.insert(address),
+                        _ => self.breakpoints.addresses.remove(&address),
+                    };
+                    String::from("OK")
+                },
+                // Only software breakpoints are supported
+                None => String::new(),
+            },
+            "H" => String::from("OK"),
+            "q" if args.starts_with("Supported") => String::from("PacketSize=1000;qXfer:features:read+"),
+            "q" if args.starts_with("Xfer:features:read:target.xml:") => {
+                // `offset,length` of the part to send, `l` marks the last part
+                let part = args.rsplit(':').next().and_then(|range| {
+                    let (offset, length) = range.split_once(',')?;
+                    Some((usize::from_str_radix(offset, 16).ok()?, usize::from_str_radix(length, 16).ok()?))
+                });
+                match part {
+                    Some((offset, length)) if offset.saturating_add(length) >= TARGET_XML.len() => {
+                        format!("l{}", &TARGET_XML[offset.min(TARGET_XML.len())..])
+                    },
+                    Some((offset, length)) => format!("m{}", &TARGET_XML[offset..offset + length]),
+                    None => String::from("E01"),
+                }
+            },
+            "q" if args == "Attached" => String::from("1"),
+            "q" if args == "fThreadInfo" => String::from("m1"),
+            "q" if args == "sThreadInfo" => String::from("l"),
+            "q" if args == "C" => String::from("QC1"),
+            // Unsupported packets get an empty reply
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    // Execute one instruction, or run until a breakpoint, halt, fault or interrupt, and say why it stopped
+    fn resume(&mut self, single_step: bool) -> std::io::Result<String> {
+        let start = self.cpu.cycles;
+        loop {
+            match self.cpu.step_with(&mut self.breakpoints) {
+                Step::Continue => {},
+                Step::Halt => {
+                    println!("Program {} halted at m0x{:02X}.", self.cpu.program_name, self.cpu.pc);
+                    // A halted program has exited, so GDB doesn't offer to continue it
+                    return Ok(format!("W{EXIT_STATUS:02x}"));
+                },
+                Step::Fault(fault) => {
+                    println!("{fault}");
+                    let signal = match fault {
+                        Fault::InvalidOpCode { .. } => SIGILL,
+                        _ => SIGSEGV,
+                    };
+                    return Ok(format!("S{signal:02x}"));
+                },
+            }
+            if single_step || self.breakpoints.hit().is_some() {
+                return Ok(format!("S{SIGTRAP:02x}"));
+            }
+            if (self.cpu.cycles - start).is_multiple_of(INTERRUPT_INTERVAL) && self.interrupted()? {
+                return Ok(format!("S{SIGINT:02x}"));
+            }
+        }
+    }
+
+    // Whether the client sent Ctrl-C, without waiting for it
+    fn interrupted(&mut self) -> std::io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let read = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+        match read {
+            Ok(1) => Ok(byte[0] == INTERRUPT),
+            Ok(_) => Ok(false),
+            Err(error) if error.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum: u8, &byte| sum.wrapping_add(byte))
+}
+
+fn parse_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// `address,length` inside memory
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let (address, length) = text.split_once(',')?;
+    let address = usize::from_str_radix(address, 16).ok()?;
+    let length = usize::from_str_radix(length, 16).ok()?;
+    address.checked_add(length).filter(|&end| end <= 256).map(|_| (address, length))
+}
+
+// `0,address,kind` for a software breakpoint
+fn breakpoint_address(args: &str) -> Option<Address> {
+    let mut fields = args.split(',');
+    if fields.next()? != "0" {
+        return None;
+    }
+    u8::from_str_radix(fields.next()?, 16).ok()
+}
+
+// `vole-machine gdb <program> [port]`
+pub fn gdb_command(args: &[String]) -> bool {
+    let [source, rest @ ..] = args else {
+        println!("Usage: vole-machine gdb <program> [port]");
+        return false;
+    };
+    let port = match rest.first().map(|port| port.parse::<u16>()) {
+        None => DEFAULT_PORT,
+        Some(Ok(port)) => port,
+        Some(Err(_)) => {
+            println!("'{}' is not a port number.", rest[0]);
+            return false;
+        },
+    };
+    let program = match crate::load_program(source) {
+        Ok(program) => program,
+        Err(error) => {
+            println!("{error}");
+            return false;
+        },
+    };
+    let spec = match crate::spec::Spec::find(&program.name) {
+        Ok(spec) => spec,
+        Err(error) => {
+            println!("Ignoring the spec for program {}: {error}", program.name);
+            None
+        },
+    };
+
+    let mut cpu = Cpu::new();
+    cpu.import(program);
+    if let Some(spec) = &spec {
+        spec.prepare(&mut cpu);
+    }
+
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(error) => {
+            println!("Can't listen on port {port}: {error}");
+            return false;
+        },
+    };
+    println!("Program {} is waiting for GDB on localhost:{port} (target remote localhost:{port})", cpu.program_name);
+    let stream = match listener.accept() {
+        Ok((stream, address)) => {
+            println!("{address} attached.");
+            stream
+        },
+        Err(error) => {
+            println!("{error}");
+            return false;
+        },
+    };
+    // Packets are small, send them right away
+    let _ = stream.set_nodelay(true);
+
+    let mut stub = Stub::new(cpu, stream);
+    match stub.serve() {
+        Ok(()) => {
+            println!("GDB detached.");
+            true
+        },
+        Err(error) => {
+            println!("Connection lost: {error}");
+            false
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProgramLibrary;
+
+    // A stub for program A and the client end of its connection
+    fn connect() -> (Stub, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut cpu = Cpu::new();
+        cpu.import(ProgramLibrary::init().get("A").unwrap());
+        (Stub::new(cpu, stream), client)
+    }
+
+    fn handle(stub: &mut Stub, command: &str) -> String {
+        stub.handle(command).unwrap()
+    }
+
+    #[test]
+    fn registers() {
+        let (mut stub, _client) = connect();
+        assert_eq!(handle(&mut stub, "g"), format!("{}30", "00".repeat(16)));
+        assert_eq!(handle(&mut stub, "P1=ff"), "OK");
+        assert_eq!(handle(&mut stub, "P10=40"), "OK");
+        assert_eq!(handle(&mut stub, "p1"), "ff");
+        assert_eq!(handle(&mut stub, "p10"), "40");
+        assert_eq!(handle(&mut stub, &format!("G{}", "01".repeat(17))), "OK");
+        assert_eq!((stub.cpu.register[15], stub.cpu.pc), (0x01, 0x01));
+
+        for command in ["p11", "pzz", "P11=00", "P1=100", "P1", "G0102", "G0g"] {
+            assert_eq!(handle(&mut stub, command), "E01", "{command}");
+        }
+    }
+
+    #[test]
+    fn memory() {
+        let (mut stub, _client) = connect();
+        assert_eq!(handle(&mut stub, "m30,4"), "20032101");
+        assert_eq!(handle(&mut stub, "M80,2:abcd"), "OK");
+        assert_eq!(stub.cpu.memory[0x80..0x82], [0xAB, 0xCD]);
+        assert_eq!(handle(&mut stub, "mff,1"), "00");
+
+        for command in ["mff,2", "m0,ffffffffffffffff", "m100,1", "m30", "M80,2:ab", "M80,1:zz", "Mff,2:0000"] {
+            assert_eq!(handle(&mut stub, command), "E01", "{command}");
+        }
+        assert_eq!(parse_range("ff,1"), Some((0xFF, 1)));
+        assert_eq!(parse_range("ffffffffffffffff,1"), None);
+    }
+
+    #[test]
+    fn stepping_and_breakpoints() {
+        let (mut stub, _client) = connect();
+        assert_eq!(handle(&mut stub, "s"), "S05");
+        assert_eq!(stub.cpu.pc, 0x32);
+
+        assert_eq!(handle(&mut stub, "Z0,38,2"), "OK");
+        assert_eq!(handle(&mut stub, "c"), "S05");
+        assert_eq!(stub.cpu.pc, 0x38);
+        assert_eq!(handle(&mut stub, "z0,38,2"), "OK");
+        assert_eq!(handle(&mut stub, "Z1,38,2"), "");
+
+        assert_eq!(handle(&mut stub, "c"), "W00");
+        // Stepping from the last cell wraps around to m0x00
+        assert_eq!(handle(&mut stub, "sff"), "S05");
+        assert_eq!(stub.cpu.pc, 0x01);
+    }
+
+    #[test]
+    fn queries() {
+        let (mut stub, _client) = connect();
+        assert_eq!(handle(&mut stub, "qSupported:multiprocess+"), "PacketSize=1000;qXfer:features:read+");
+        assert_eq!(handle(&mut stub, "qXfer:features:read:target.xml:0,5"), "m<?xml");
+        assert_eq!(handle(&mut stub, "qXfer:features:read:target.xml:0,fff"), format!("l{TARGET_XML}"));
+        assert_eq!(handle(&mut stub, "qXfer:features:read:target.xml:ffffffffffffffff,ffffffffffffffff"), "l");
+        assert_eq!(handle(&mut stub, "qXfer:features:read:target.xml:x"), "E01");
+        // Unsupported, empty or cut off inside a character
+        for command in ["vMustReplyEmpty", "", "\u{e9}"] {
+            assert_eq!(handle(&mut stub, command), "", "{command}");
+        }
+    }
+
+    #[test]
+    fn packets() {
+        let (mut stub, mut client) = connect();
+        let server = std::thread::spawn(move || stub.serve());
+
+        let mut exchange = |packet: &[u8]| {
+            client.write_all(packet).unwrap();
+            let mut reply = Vec::new();
+            let mut byte = [0];
+            while reply.len() < 3 || reply[reply.len() - 3] != b'#' {
+                client.read_exact(&mut byte).unwrap();
+                reply.push(byte[0]);
+            }
+            client.write_all(b"+").unwrap();
+            String::from_utf8(reply).unwrap()
+        };
+        assert_eq!(exchange(b"$?#3f"), "+$S05#b8");
+        // A bad checksum is answered with `-`, then the packet is sent again
+        assert_eq!(exchange(b"$p10#00$p10#d1"), "-+$30#63");
+        assert_eq!(exchange(b"\x03"), "$S02#b5");
+
+        client.write_all(b"$D#44").unwrap();
+        let mut reply = [0; 7];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"+$OK#9a");
+        client.write_all(b"+").unwrap();
+        server.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,245 @@
+// Memory images: a program loaded together with named segments of code and data
+//
+// An image is a text file of `name = value` lines, with `#` starting a comment:
+//
+//     run = A                         # The program that runs, also loaded as a segment
+//     table = @00 11 22 33            # Data bytes loaded at m0x00
+//     double = double.asm@60          # Another program, loaded but not run
+//
+// A segment is either `@XY` followed by hex bytes, or any program the command line accepts, with
+// paths relative to the image file. The program that runs can be another image, whose segments
+// are loaded too, as long as no image ends up loading itself. Segments may not overlap each other
+// or the program that runs.
+
+use std::path::{Path, PathBuf};
+
+use crate::{Address, Program, ProgramLibrary};
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Segment {
+    pub name: String,
+    pub address: Address,
+    pub bytes: Vec<u8>,
+}
+
+impl Segment {
+    fn end(&self) -> usize {
+        self.address as usize + self.bytes.len()
+    }
+}
+
+// Every segment of the program, its own code first
+pub fn segments(program: &Program) -> Vec<Segment> {
+    let code = Segment { name: program.name.clone(), address: program.start_address, bytes: program.code.clone() };
+    std::iter::once(code).chain(program.segments.iter().cloned()).collect()
+}
+
+// Whether the program or one of its segments has the name
+fn segments_named(program: &Program, name: &str) -> bool {
+    program.name == name || program.segments.iter().any(|segment| segment.name == name)
+}
+
+pub fn check_overlaps(program: &Program) -> Result<(), String> {
+    let segments = segments(program);
+    for (index, segment) in segments.iter().enumerate() {
+        if segment.end() > 256 {
+            return Err(format!("Segment {} at m0x{:02X} runs past the end of memory.", segment.name, segment.address));
+        }
+        for other in &segments[index + 1..] {
+            let start = segment.address.max(other.address) as usize;
+            if start < segment.end().min(other.end()) {
+                return Err(format!("Segments {} (m0x{:02X}..m0x{:02X}) and {} (m0x{:02X}..m0x{:02X}) overlap at m0x{start:02X}.",
+                    segment.name,
+                    segment.address,
+                    segment.end() - 1,
+                    other.name,
+                    other.address,
+                    other.end() - 1
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Parse an image from `directory`, while the images in `loading` are being loaded
+fn parse(text: &str, directory: &Path, loading: &mut Vec<PathBuf>) -> Result<Program, String> {
+    let mut program: Option<Program> = None;
+    let mut segments: Vec<Segment> = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: String| format!("line {line_number}: {message}");
+        let Some((name, value)) = line.split_once('=') else {
+            return Err(error(format!("expected `name = value`, found '{line}'")));
+        };
+        let (name, value) = (name.trim(), value.trim());
+        // The program that runs may be an image too, with segments of its own
+        let taken = program.as_ref().is_some_and(|program| segments_named(program, name))
+            || segments.iter().any(|segment| segment.name == name);
+
+        if name == "run" {
+            if program.is_some() {
+                return Err(error(String::from("only one program can run")));
+            }
+            let run = load_part(value, directory, loading).map_err(error)?;
+            if let Some(segment) = segments.iter().find(|segment| segments_named(&run, &segment.name)) {
+                return Err(error(format!("there's already a segment named {}", segment.name)));
+            }
+            program = Some(run);
+            continue;
+        }
+        if taken {
+            return Err(error(format!("there's already a segment named {name}")));
+        }
+
+        match value.strip_prefix('@') {
+            Some(data) => {
+                let mut words = data.split_whitespace();
+                let address = words.next()
+                    .and_then(|address| u8::from_str_radix(address, 16).ok())
+                    .ok_or_else(|| error(format!("'{value}' should start with the address, like @00")))?;
+                let bytes = words
+                    .map(|byte| u8::from_str_radix(byte, 16).map_err(|_| error(format!("'{byte}' is not a hex byte"))))
+                    .collect::<Result<Vec<u8>, String>>()?;
+                segments.push(Segment { name: name.to_string(), address, bytes });
+            },
+            None => {
+                let loaded = load_part(value, directory, loading).map_err(error)?;
+                segments.push(Segment { name: name.to_string(), address: loaded.start_address, bytes: loaded.code.clone() });
+                segments.extend(loaded.segments);
+            },
+        }
+    }
+
+    let Some(mut program) = program else {
+        return Err(String::from("the image doesn't say which program to run (run = <program>)"));
+    };
+    program.segments.extend(segments);
+    check_overlaps(&program)?;
+    Ok(program)
+}
+
+pub fn load(path: &str) -> Result<Program, String> {
+    load_nested(path, &mut Vec::new())
+}
+
+fn load_nested(path: &str, loading: &mut Vec<PathBuf>) -> Result<Program, String> {
+    let canonical = std::fs::canonicalize(path).map_err(|error| format!("{path}: {error}"))?;
+    if loading.contains(&canonical) {
+        return Err(format!("{path} is already being loaded, an image can't load itself"));
+    }
+    let text = std::fs::read_to_string(path).map_err(|error| format!("{path}: {error}"))?;
+    let directory = canonical.parent().map(Path::to_path_buf).unwrap_or_default();
+
+    loading.push(canonical);
+    let program = parse(&text, &directory, loading).map_err(|error| format!("{path}: {error}"));
+    loading.pop();
+    program
+}
+
+// A program named in an image: a library program, or a file relative to the image, moved to `@XY`
+fn load_part(source: &str, directory: &Path, loading: &mut Vec<PathBuf>) -> Result<Program, String> {
+    if let Some((source, address)) = source.rsplit_once('@') {
+        if let Ok(address) = u8::from_str_radix(address, 16) {
+            return load_part(source, directory, loading)?.relocate(address);
+        }
+    }
+    if ProgramLibrary::init().get(source).is_some() {
+        return crate::load_program(source);
+    }
+
+    let path = directory.join(source);
+    let path = path.to_string_lossy();
+    match path.ends_with(".image") {
+        true => load_nested(&path, loading),
+        false => crate::load_program(&path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cpu, Step};
+
+    // A directory of its own for every test that writes images
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("vole-image-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(directory.join("nested")).unwrap();
+        directory
+    }
+
+    fn parse_text(text: &str) -> Result<Program, String> {
+        parse(text, Path::new("programs"), &mut Vec::new())
+    }
+
+    #[test]
+    fn copy_image_runs() {
+        let program = load("programs/copy.image").unwrap();
+        assert_eq!((program.name.as_str(), program.start_address), ("copy", 0x30));
+        assert_eq!(program.segments, vec![Segment { name: String::from("table"), address: 0x00, bytes: vec![0x11, 0x22, 0x33] }]);
+
+        let mut cpu = Cpu::new();
+        cpu.import(program);
+        while cpu.cycles < 1_000 && cpu.step() == Step::Continue {}
+        assert_eq!(cpu.memory[0x10..0x13], [0x11, 0x22, 0x33]);
+    }
+
+    #[test]
+    fn segments_of_programs_and_data() {
+        let program = parse_text("run = A\ncopy = copy.asm@80\nempty = @F0").unwrap();
+        assert_eq!(program.name, "A");
+        let names: Vec<(&str, Address, usize)> = program.segments.iter()
+            .map(|segment| (segment.name.as_str(), segment.address, segment.bytes.len()))
+            .collect();
+        assert_eq!(names, vec![("copy", 0x80, 0x1A), ("empty", 0xF0, 0)]);
+    }
+
+    #[test]
+    fn malformed_images() {
+        for (text, error) in [
+            ("run A", "line 1: expected `name = value`, found 'run A'"),
+            ("run = A\nrun = B", "line 2: only one program can run"),
+            ("run = A\ndata = @00 11\ndata = @10 22", "line 3: there's already a segment named data"),
+            ("data = @00 11\nA = @10 22\nrun = A", "line 3: there's already a segment named A"),
+            ("run = A\ndata = @XY 11", "line 2: '@XY 11' should start with the address, like @00"),
+            ("run = A\ndata = @00 1G", "line 2: '1G' is not a hex byte"),
+            ("data = @00 11", "the image doesn't say which program to run (run = <program>)"),
+            ("run = A\ndata = @FF 11 22", "Segment data at m0xFF runs past the end of memory."),
+        ] {
+            assert_eq!(parse_text(text).err().as_deref(), Some(error), "{text}");
+        }
+        assert!(parse_text("run = A\ndata = @31 11").err().unwrap().starts_with("Segments A (m0x30..m0x"));
+        assert!(parse_text("run = missing.asm").err().unwrap().starts_with("line 1: programs/missing.asm: "));
+    }
+
+    #[test]
+    fn nested_images_are_relative_to_their_file() {
+        let directory = directory("nested");
+        std::fs::write(directory.join("nested/inner.image"), "run = A\ninner = @00 01").unwrap();
+        std::fs::write(directory.join("outer.image"), "run = nested/inner.image\nouter = @10 02").unwrap();
+
+        let program = load(&directory.join("outer.image").to_string_lossy());
+        std::fs::remove_dir_all(&directory).unwrap();
+        let names: Vec<String> = program.unwrap().segments.into_iter().map(|segment| segment.name).collect();
+        assert_eq!(names, ["inner", "outer"]);
+    }
+
+    #[test]
+    fn images_cant_load_themselves() {
+        let directory = directory("cycles");
+        std::fs::write(directory.join("self.image"), "run = self.image").unwrap();
+        std::fs::write(directory.join("first.image"), "run = A\nother = nested/second.image").unwrap();
+        std::fs::write(directory.join("nested/second.image"), "run = ../first.image").unwrap();
+
+        let itself = load(&directory.join("self.image").to_string_lossy());
+        let mutual = load(&directory.join("first.image").to_string_lossy());
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert!(itself.err().unwrap().ends_with("self.image is already being loaded, an image can't load itself"));
+        assert!(mutual.err().unwrap().ends_with("first.image is already being loaded, an image can't load itself"));
+    }
+}
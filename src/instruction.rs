@@ -0,0 +1,192 @@
+// Decoding and encoding of the 16-bit Vole instructions
+
+use crate::{Address, Condition, Extensions};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Instruction {
+    NoOp,                                       // 0x0000       :: No Operation
+    LoadFrom { r: usize, address: Address },    // 0x1[RXY]     :: Load from m0xXY into rR
+    Load { r: usize, value: u8 },               // 0x2[RXY]     :: Load 0xXY into rR
+    Store { r: usize, address: Address },       // 0x3[RXY]     :: Store from rR into m0xXY
+    Move { r: usize, s: usize },                // 0x40[RS]     :: Move from rR to rS
+    AddTc { r: usize, s: usize, t: usize },     // 0x5[RST]     :: rS + rT into rR (Two's Complement)
+    AddFl { r: usize, s: usize, t: usize },     // 0x6[RST]     :: rS + rT into rR (Floating Point)
+    Or { r: usize, s: usize, t: usize },        // 0x7[RST]     :: rS | rT into R
+    And { r: usize, s: usize, t: usize },       // 0x8[RST]     :: rS & rT into rR
+    Xor { r: usize, s: usize, t: usize },       // 0x9[RST]     :: rS ^ rT into rR
+    Rotate { r: usize, bits: u8 },              // 0xA[R]0[X]   :: rR >> 0xX // Rotate Right X bits
+    Jump { r: usize, address: Address },        // 0xB[RXY]     :: if rR == r0 then PC = m0xXY
+    Halt,                                       // 0xC000       :: Halt
+    // Stack extension
+    Call { address: Address },                  // 0xD0XY       :: Push PC + 2, then PC = m0xXY
+    Return,                                     // 0xD100       :: Pop PC
+    Push { r: usize },                          // 0xE[R]00     :: Push rR
+    Pop { r: usize },                           // 0xE[R]01     :: Pop into rR
+    // Flags extension
+    JumpIf { condition: Condition, address: Address }, // 0xF[C]XY :: if condition C then PC = m0xXY
+    Invalid { opcode: u8 },                     // 0xD - 0xF    :: Not part of the (enabled) instruction set
+}
+
+impl Instruction {
+    // Instructions of extensions that aren't enabled decode as invalid
+    pub fn decode(high: u8, low: u8, extensions: Extensions) -> Instruction {
+        let r = (high & 0x0F) as usize; // The lower 4 bits of byte 1
+        let s = (low >> 4) as usize; // The upper 4 bits of byte 2
+        let t = (low & 0x0F) as usize; // The lower 4 bits of byte 2
+
+        match high >> 4 {
+            0x0 => Instruction::NoOp,
+            0x1 => Instruction::LoadFrom { r, address: low },
+            0x2 => Instruction::Load { r, value: low },
+            0x3 => Instruction::Store { r, address: low },
+            0x4 => Instruction::Move { r: s, s: t },
+            0x5 => Instruction::AddTc { r, s, t },
+            0x6 => Instruction::AddFl { r, s, t },
+            0x7 => Instruction::Or { r, s, t },
+            0x8 => Instruction::And { r, s, t },
+            0x9 => Instruction::Xor { r, s, t },
+            0xA => Instruction::Rotate { r, bits: low & 0x0F },
+            0xB => Instruction::Jump { r, address: low },
+            0xC => Instruction::Halt,
+            0xD if extensions.stack && r == 0 => Instruction::Call { address: low },
+            0xD if extensions.stack && r == 1 && low == 0 => Instruction::Return,
+            0xE if extensions.stack && low == 0x00 => Instruction::Push { r },
+            0xE if extensions.stack && low == 0x01 => Instruction::Pop { r },
+            0xF if extensions.flags => match Condition::from_nibble(r as u8) {
+                Some(condition) => Instruction::JumpIf { condition, address: low },
+                None => Instruction::Invalid { opcode: 0xF },
+            },
+            opcode => Instruction::Invalid { opcode },
+        }
+    }
+
+    pub fn encode(&self) -> [u8; 2] {
+        let rst = |opcode: u8, r: usize, s: usize, t: usize| {
+            [opcode << 4 | r as u8, (s as u8) << 4 | t as u8]
+        };
+
+        match *self {
+            Instruction::NoOp => [0x00, 0x00],
+            Instruction::LoadFrom { r, address } => [0x10 | r as u8, address],
+            Instruction::Load { r, value } => [0x20 | r as u8, value],
+            Instruction::Store { r, address } => [0x30 | r as u8, address],
+            Instruction::Move { r, s } => rst(0x4, 0, r, s),
+            Instruction::AddTc { r, s, t } => rst(0x5, r, s, t),
+            Instruction::AddFl { r, s, t } => rst(0x6, r, s, t),
+            Instruction::Or { r, s, t } => rst(0x7, r, s, t),
+            Instruction::And { r, s, t } => rst(0x8, r, s, t),
+            Instruction::Xor { r, s, t } => rst(0x9, r, s, t),
+            Instruction::Rotate { r, bits } => [0xA0 | r as u8, bits & 0x0F],
+            Instruction::Jump { r, address } => [0xB0 | r as u8, address],
+            Instruction::Halt => [0xC0, 0x00],
+            Instruction::Call { address } => [0xD0, address],
+            Instruction::Return => [0xD1, 0x00],
+            Instruction::Push { r } => [0xE0 | r as u8, 0x00],
+            Instruction::Pop { r } => [0xE0 | r as u8, 0x01],
+            Instruction::JumpIf { condition, address } => [0xF0 | condition as u8, address],
+            Instruction::Invalid { opcode } => [opcode << 4, 0x00],
+        }
+    }
+
+    // The registers the instruction reads from
+    pub fn registers_read(&self) -> Vec<usize> {
+        match *self {
+            Instruction::Store { r, .. }
+            | Instruction::Move { r, .. }
+            | Instruction::Rotate { r, .. }
+            | Instruction::Push { r } => vec![r],
+            Instruction::AddTc { s, t, .. }
+            | Instruction::AddFl { s, t, .. }
+            | Instruction::Or { s, t, .. }
+            | Instruction::And { s, t, .. }
+            | Instruction::Xor { s, t, .. } => if s == t { vec![s] } else { vec![s, t] },
+            Instruction::Jump { r: 0, .. } => vec![0],
+            Instruction::Jump { r, .. } => vec![r, 0],
+            _ => vec![],
+        }
+    }
+
+    pub fn register_written(&self) -> Option<usize> {
+        match *self {
+            Instruction::LoadFrom { r, .. }
+            | Instruction::Load { r, .. }
+            | Instruction::AddTc { r, .. }
+            | Instruction::AddFl { r, .. }
+            | Instruction::Or { r, .. }
+            | Instruction::And { r, .. }
+            | Instruction::Xor { r, .. }
+            | Instruction::Rotate { r, .. }
+            | Instruction::Pop { r } => Some(r),
+            Instruction::Move { s, .. } => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn memory_read(&self) -> Option<Address> {
+        match *self {
+            Instruction::LoadFrom { address, .. } => Some(address),
+            _ => None,
+        }
+    }
+
+    pub fn memory_written(&self) -> Option<Address> {
+        match *self {
+            Instruction::Store { address, .. } => Some(address),
+            _ => None,
+        }
+    }
+
+    pub fn opcode(&self) -> u8 {
+        match *self {
+            Instruction::NoOp => 0x0,
+            Instruction::LoadFrom { .. } => 0x1,
+            Instruction::Load { .. } => 0x2,
+            Instruction::Store { .. } => 0x3,
+            Instruction::Move { .. } => 0x4,
+            Instruction::AddTc { .. } => 0x5,
+            Instruction::AddFl { .. } => 0x6,
+            Instruction::Or { .. } => 0x7,
+            Instruction::And { .. } => 0x8,
+            Instruction::Xor { .. } => 0x9,
+            Instruction::Rotate { .. } => 0xA,
+            Instruction::Jump { .. } => 0xB,
+            Instruction::Halt => 0xC,
+            Instruction::Call { .. } | Instruction::Return => 0xD,
+            Instruction::Push { .. } | Instruction::Pop { .. } => 0xE,
+            Instruction::JumpIf { .. } => 0xF,
+            Instruction::Invalid { opcode } => opcode,
+        }
+    }
+
+    // Decode the instruction stored at an address, the second byte wraps around the end of memory
+    pub fn fetch(memory: &[u8; 256], address: Address, extensions: Extensions) -> Instruction {
+        Instruction::decode(memory[address as usize], memory[address.wrapping_add(1) as usize], extensions)
+    }
+}
+
+// Describes the instruction the same way the comments in `ProgramLibrary::init` do
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Instruction::NoOp => write!(f, "No Operation"),
+            Instruction::LoadFrom { r, address } => write!(f, "Load from m0x{address:02X} into r{r:X}"),
+            Instruction::Load { r, value } => write!(f, "Load 0x{value:02X} into r{r:X}"),
+            Instruction::Store { r, address } => write!(f, "Store from r{r:X} into m0x{address:02X}"),
+            Instruction::Move { r, s } => write!(f, "Move from r{r:X} to r{s:X}"),
+            Instruction::AddTc { r, s, t } => write!(f, "r{s:X} + r{t:X} into r{r:X}"),
+            Instruction::AddFl { r, s, t } => write!(f, "r{s:X} + r{t:X} into r{r:X} (Floating Point)"),
+            Instruction::Or { r, s, t } => write!(f, "r{s:X} | r{t:X} into r{r:X}"),
+            Instruction::And { r, s, t } => write!(f, "r{s:X} & r{t:X} into r{r:X}"),
+            Instruction::Xor { r, s, t } => write!(f, "r{s:X} ^ r{t:X} into r{r:X}"),
+            Instruction::Rotate { r, bits } => write!(f, "Rotate r{r:X} right {bits} bits"),
+            Instruction::Jump { r, address } => write!(f, "Jump to m0x{address:02X} if r{r:X} == r0"),
+            Instruction::Halt => write!(f, "Halt"),
+            Instruction::Call { address } => write!(f, "Call the subroutine at m0x{address:02X}"),
+            Instruction::Return => write!(f, "Return from the subroutine"),
+            Instruction::Push { r } => write!(f, "Push r{r:X} onto the stack"),
+            Instruction::Pop { r } => write!(f, "Pop from the stack into r{r:X}"),
+            Instruction::JumpIf { condition, address } => write!(f, "Jump to m0x{address:02X} if {condition}"),
+            Instruction::Invalid { opcode } => write!(f, "Invalid OpCode 0x{opcode:X}"),
+        }
+    }
+}
@@ -0,0 +1,370 @@
+// JSON values, and the `Content-Length` framed messages the debug adapter and language server use
+//
+// Just enough JSON for the protocols: numbers are f64, and objects keep their keys in order.
+
+use std::fmt::Write as _;
+use std::io::{BufRead, Write};
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object<const N: usize>(fields: [(&str, Json); N]) -> Json {
+        Json::Object(fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+    }
+
+    // Add a field to an object, other values stay as they are
+    pub fn insert(&mut self, key: &str, value: Json) {
+        if let Json::Object(fields) = self {
+            fields.push((key.to_string(), value));
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::Number(number) if *number >= 0.0 && number.fract() == 0.0 => Some(*number as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser { chars: text.chars().collect(), position: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        match parser.position == parser.chars.len() {
+            true => Ok(value),
+            false => Err(format!("unexpected '{}' after the value", parser.chars[parser.position])),
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Json {
+        Json::Bool(value)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(text: &str) -> Json {
+        Json::String(text.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(text: String) -> Json {
+        Json::String(text)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(values: Vec<Json>) -> Json {
+        Json::Array(values)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Json {
+        value.map(Into::into).unwrap_or(Json::Null)
+    }
+}
+
+macro_rules! json_from_number {
+    ($($number:ty),*) => {$(
+        impl From<$number> for Json {
+            fn from(number: $number) -> Json {
+                Json::Number(number as f64)
+            }
+        }
+    )*};
+}
+json_from_number!(u8, u64, u128, usize, i64);
+
+impl std::fmt::Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{value}"),
+            Json::Number(number) if number.fract() == 0.0 && number.abs() < 1e15 => write!(f, "{}", *number as i64),
+            Json::Number(number) => write!(f, "{number}"),
+            Json::String(text) => write!(f, "{}", quote(text)),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in fields.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{value}", quote(key))?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+fn quote(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.position).is_some_and(|c| c.is_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    fn next(&mut self) -> Result<char, String> {
+        let c = self.chars.get(self.position).copied().ok_or("unexpected end of JSON")?;
+        self.position += 1;
+        Ok(c)
+    }
+
+    fn expect(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        for expected in word.chars() {
+            if self.next()? != expected {
+                return Err(format!("expected '{word}'"));
+            }
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.chars.get(self.position) {
+            Some('n') => self.expect("null", Json::Null),
+            Some('t') => self.expect("true", Json::Bool(true)),
+            Some('f') => self.expect("false", Json::Bool(false)),
+            Some('"') => self.string().map(Json::String),
+            Some('[') => {
+                self.position += 1;
+                let mut values = Vec::new();
+                self.skip_whitespace();
+                if self.chars.get(self.position) == Some(&']') {
+                    self.position += 1;
+                    return Ok(Json::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.next()? {
+                        ',' => continue,
+                        ']' => return Ok(Json::Array(values)),
+                        c => return Err(format!("expected ',' or ']' but found '{c}'")),
+                    }
+                }
+            },
+            Some('{') => {
+                self.position += 1;
+                let mut fields = Vec::new();
+                self.skip_whitespace();
+                if self.chars.get(self.position) == Some(&'}') {
+                    self.position += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    if self.next()? != ':' {
+                        return Err(format!("expected ':' after \"{key}\""));
+                    }
+                    fields.push((key, self.value()?));
+                    self.skip_whitespace();
+                    match self.next()? {
+                        ',' => continue,
+                        '}' => return Ok(Json::Object(fields)),
+                        c => return Err(format!("expected ',' or '}}' but found '{c}'")),
+                    }
+                }
+            },
+            Some(c) if *c == '-' || c.is_ascii_digit() => {
+                let start = self.position;
+                while self.chars.get(self.position).is_some_and(|c| matches!(c, '-' | '+' | '.' | 'e' | 'E' | '0'..='9')) {
+                    self.position += 1;
+                }
+                let number: String = self.chars[start..self.position].iter().collect();
+                number.parse().map(Json::Number).map_err(|_| format!("'{number}' is not a number"))
+            },
+            Some(c) => Err(format!("unexpected '{c}'")),
+            None => Err(String::from("unexpected end of JSON")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.next()? != '"' {
+            return Err(String::from("expected a string"));
+        }
+        let mut text = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(text),
+                '\\' => match self.next()? {
+                    'n' => text.push('\n'),
+                    'r' => text.push('\r'),
+                    't' => text.push('\t'),
+                    'b' => text.push('\u{8}'),
+                    'f' => text.push('\u{c}'),
+                    'u' => {
+                        let hex: String = (0..4).map(|_| self.next()).collect::<Result<_, _>>()?;
+                        let code = u32::from_str_radix(&hex, 16).map_err(|_| format!("'\\u{hex}' is not an escape"))?;
+                        text.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                    },
+                    c => text.push(c),
+                },
+                c => text.push(c),
+            }
+        }
+    }
+}
+
+// Read one `Content-Length: N` framed message, None at the end of the input
+pub fn read_message(input: &mut impl BufRead) -> std::io::Result<Option<Json>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() && length.is_some() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let mut body = vec![0; length.unwrap_or_default()];
+    input.read_exact(&mut body)?;
+    let body = String::from_utf8_lossy(&body);
+    Json::parse(&body)
+        .map(Some)
+        .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))
+}
+
+pub fn write_message(output: &mut impl Write, message: &Json) -> std::io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let text = r#"{"null":null,"bool":true,"numbers":[0,-3,1.5,1e20],"text":"a \"quoted\"\\ line\n\t\u0001","empty":{},"list":[]}"#;
+        let value = Json::parse(text).unwrap();
+        assert_eq!(value.get("bool").and_then(Json::as_bool), Some(true));
+        assert_eq!(value.get("text").and_then(Json::as_str), Some("a \"quoted\"\\ line\n\t\u{1}"));
+        assert_eq!(Json::parse(&value.to_string()), Ok(value.clone()));
+        assert_eq!(value.to_string(), r#"{"null":null,"bool":true,"numbers":[0,-3,1.5,100000000000000000000],"text":"a \"quoted\"\\ line\n\t\u0001","empty":{},"list":[]}"#);
+    }
+
+    #[test]
+    fn whitespace_and_escapes() {
+        let value = Json::parse(" { \"a\" : [ 1 , 2 ] , \"b\" : \"\\u00e9\\/\" } ").unwrap();
+        assert_eq!(value.get("a"), Some(&Json::from(vec![Json::from(1u8), Json::from(2u8)])));
+        assert_eq!(value.get("b").and_then(Json::as_str), Some("\u{e9}/"));
+        assert_eq!(Json::from(3u64).as_u64(), Some(3));
+        assert_eq!(Json::Number(-1.0).as_u64(), None);
+        assert_eq!(Json::Number(1.5).as_u64(), None);
+    }
+
+    #[test]
+    fn malformed_json() {
+        for (text, error) in [
+            ("", "unexpected end of JSON"),
+            ("[1, 2", "unexpected end of JSON"),
+            ("[1 2]", "expected ',' or ']' but found '2'"),
+            ("{\"a\" 1}", "expected ':' after \"a\""),
+            ("{\"a\": 1 \"b\": 2}", "expected ',' or '}' but found '\"'"),
+            ("{1: 2}", "expected a string"),
+            ("nul", "unexpected end of JSON"),
+            ("trve", "expected 'true'"),
+            ("1.2.3", "'1.2.3' is not a number"),
+            ("\"\\uZZZZ\"", "'\\uZZZZ' is not an escape"),
+            ("@", "unexpected '@'"),
+            ("1 2", "unexpected '2' after the value"),
+        ] {
+            assert_eq!(Json::parse(text), Err(String::from(error)), "{text}");
+        }
+    }
+
+    #[test]
+    fn framed_messages() {
+        let mut output = Vec::new();
+        write_message(&mut output, &Json::object([("seq", Json::from(1u64))])).unwrap();
+        assert_eq!(output, b"Content-Length: 9\r\n\r\n{\"seq\":1}");
+
+        // Other headers are skipped, and the input ends after the last message
+        let mut input = &b"Content-Type: json\r\nContent-Length: 2\r\n\r\n{}Content-Length: 4\r\n\r\n{x}}"[..];
+        assert_eq!(read_message(&mut input).unwrap(), Some(Json::object([])));
+        assert_eq!(read_message(&mut input).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(read_message(&mut input).unwrap(), None);
+        assert_eq!(read_message(&mut &b"Content-Length: 9\r\n\r\n{}"[..]).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
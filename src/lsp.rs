@@ -0,0 +1,327 @@
+// Language server for Vole assembly over stdin and stdout
+//
+// Every change to an open `.asm` document is assembled. An error becomes a diagnostic on its
+// line. Hovering over an instruction explains it in the words of the library programs' comments,
+// like "Load 0x03 into r0". Go to definition jumps from a label to the line defining it. The encoded hex word of every
+// instruction is shown at the end of its line as an inlay hint.
+
+use std::collections::HashMap;
+use std::io::{BufRead, ErrorKind, Write};
+
+use crate::compiler::SourceMap;
+use crate::instruction::Instruction;
+use crate::json::{self, Json};
+use crate::Program;
+
+// Error codes from the JSON-RPC specification
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+struct Document {
+    text: String,
+    // The assembled program, or the error that stopped the assembler
+    assembled: Result<(Program, SourceMap), crate::compiler::CompileError>,
+}
+
+impl Document {
+    fn new(uri: &str, text: String) -> Document {
+        let name = uri.rsplit('/').next().and_then(|file| file.split('.').next()).unwrap_or("document");
+        let assembled = crate::assembler::assemble(name, &text);
+        Document { text, assembled }
+    }
+
+    // The instruction assembled from a line (numbered from 1) and its address
+    fn instruction_on(&self, line: usize) -> Option<(crate::Address, [u8; 2], Instruction)> {
+        let (program, source_map) = self.assembled.as_ref().ok()?;
+        let &(address, _) = source_map.entries.iter().find(|&&(_, entry_line)| entry_line == line)?;
+        let offset = address.wrapping_sub(program.start_address) as usize;
+        let (high, low) = (program.code[offset], program.code[offset + 1]);
+        Some((address, [high, low], Instruction::decode(high, low, program.extensions)))
+    }
+
+    // The label, register or number under the cursor
+    fn word_at(&self, line: usize, character: usize) -> Option<&str> {
+        let text = self.text.lines().nth(line)?;
+        let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+        let position = text.char_indices().nth(character).map(|(position, _)| position).unwrap_or(text.len());
+        let start = text[..position].char_indices().rev()
+            .find(|&(_, c)| !is_word(c))
+            .map(|(start, c)| start + c.len_utf8())
+            .unwrap_or(0);
+        let end = text[position..].find(|c: char| !is_word(c)).map(|end| position + end).unwrap_or(text.len());
+        (start < end).then(|| &text[start..end])
+    }
+}
+
+pub struct Server<W: Write> {
+    documents: HashMap<String, Document>,
+    writer: W,
+}
+
+fn position(line: usize, character: usize) -> Json {
+    Json::object([("line", Json::from(line)), ("character", Json::from(character))])
+}
+
+// The whole of a line, numbered from 0
+fn line_range(text: &str, line: usize) -> Json {
+    let length = text.lines().nth(line).map(|text| text.chars().count()).unwrap_or_default();
+    Json::object([("start", position(line, 0)), ("end", position(line, length))])
+}
+
+impl<W: Write> Server<W> {
+    pub fn new(writer: W) -> Server<W> {
+        Server { documents: HashMap::new(), writer }
+    }
+
+    fn send(&mut self, message: Json) {
+        let _ = json::write_message(&mut self.writer, &message);
+    }
+
+    fn error(&mut self, id: Json, code: i64, message: String) {
+        self.send(Json::object([
+            ("jsonrpc", Json::from("2.0")),
+            ("id", id),
+            ("error", Json::object([("code", Json::from(code)), ("message", Json::from(message))])),
+        ]));
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str) {
+        let Some(document) = self.documents.get(uri) else {
+            return;
+        };
+        let diagnostics = match &document.assembled {
+            Ok(_) => Vec::new(),
+            Err(error) => vec![Json::object([
+                ("range", line_range(&document.text, error.line.saturating_sub(1))),
+                ("severity", Json::from(1u64)),
+                ("source", Json::from("vole-machine")),
+                ("message", Json::from(error.message.clone())),
+            ])],
+        };
+        self.send(Json::object([
+            ("jsonrpc", Json::from("2.0")),
+            ("method", Json::from("textDocument/publishDiagnostics")),
+            ("params", Json::object([("uri", Json::from(uri)), ("diagnostics", Json::from(diagnostics))])),
+        ]));
+    }
+
+    // Serve the editor from the input until it says to exit
+    pub fn serve(&mut self, mut input: impl BufRead) {
+        loop {
+            // A message that isn't JSON gets an error response, the next one is read as usual
+            let message = match json::read_message(&mut input) {
+                Ok(Some(message)) => message,
+                Err(error) if error.kind() == ErrorKind::InvalidData => {
+                    self.error(Json::Null, PARSE_ERROR, format!("Can't read the message: {error}"));
+                    continue;
+                },
+                Ok(None) | Err(_) => return,
+            };
+            let method = message.get("method").and_then(Json::as_str).unwrap_or_default();
+            let params = message.get("params").cloned().unwrap_or(Json::Null);
+            if method == "exit" {
+                return;
+            }
+            let result = self.handle(method, &params);
+            // Requests have an id and get a response, notifications don't
+            match (message.get("id"), result) {
+                (Some(id), Some(result)) => {
+                    self.send(Json::object([("jsonrpc", Json::from("2.0")), ("id", id.clone()), ("result", result)]));
+                },
+                (Some(id), None) => self.error(id.clone(), METHOD_NOT_FOUND, format!("'{method}' is not supported")),
+                (None, _) => {},
+            }
+        }
+    }
+
+    // The result of a request, None for unsupported ones
+    fn handle(&mut self, method: &str, params: &Json) -> Option<Json> {
+        let uri = params.get("textDocument").and_then(|document| document.get("uri")).and_then(Json::as_str).unwrap_or_default();
+        let (line, character) = params.get("position")
+            .map(|position| {
+                let field = |name| position.get(name).and_then(Json::as_u64).unwrap_or_default() as usize;
+                (field("line"), field("character"))
+            })
+            .unwrap_or_default();
+
+        match method {
+            "initialize" => Some(Json::object([
+                ("capabilities", Json::object([
+                    ("textDocumentSync", Json::from(1u64)), // The whole document on every change
+                    ("hoverProvider", Json::from(true)),
+                    ("definitionProvider", Json::from(true)),
+                    ("inlayHintProvider", Json::from(true)),
+                ])),
+                ("serverInfo", Json::object([("name", Json::from("vole-machine"))])),
+            ])),
+            "shutdown" | "initialized" => Some(Json::Null),
+            "textDocument/didOpen" => {
+                let text = params.get("textDocument").and_then(|document| document.get("text")).and_then(Json::as_str);
+                self.documents.insert(uri.to_string(), Document::new(uri, text.unwrap_or_default().to_string()));
+                self.publish_diagnostics(uri);
+                Some(Json::Null)
+            },
+            "textDocument/didChange" => {
+                let changes = params.get("contentChanges").and_then(Json::as_array).unwrap_or_default();
+                if let Some(text) = changes.last().and_then(|change| change.get("text")).and_then(Json::as_str) {
+                    self.documents.insert(uri.to_string(), Document::new(uri, text.to_string()));
+                    self.publish_diagnostics(uri);
+                }
+                Some(Json::Null)
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                Some(Json::Null)
+            },
+            "textDocument/hover" => {
+                let hover = self.documents.get(uri)
+                    .and_then(|document| document.instruction_on(line + 1))
+                    .map(|(address, [high, low], instruction)| {
+                        Json::object([
+                            ("contents", Json::object([
+                                ("kind", Json::from("markdown")),
+                                ("value", Json::from(format!("`0x{high:02X}{low:02X}` at m0x{address:02X}: {instruction}"))),
+                            ])),
+                            ("range", line_range(&self.documents[uri].text, line)),
+                        ])
+                    });
+                Some(Json::from(hover))
+            },
+            "textDocument/definition" => {
+                let definition = self.documents.get(uri).and_then(|document| {
+                    let word = document.word_at(line, character)?;
+                    let &(_, defined_on) = crate::assembler::label_definitions(&document.text).iter().find(|(label, _)| *label == word)?;
+                    Some(Json::object([("uri", Json::from(uri)), ("range", line_range(&document.text, defined_on - 1))]))
+                });
+                Some(Json::from(definition))
+            },
+            "textDocument/inlayHint" => {
+                let Some(document) = self.documents.get(uri) else {
+                    return Some(Json::Array(Vec::new()));
+                };
+                let hints = document.text.lines().enumerate()
+                    .filter_map(|(index, text)| {
+                        let (_, [high, low], _) = document.instruction_on(index + 1)?;
+                        Some(Json::object([
+                            ("position", position(index, text.chars().count())),
+                            ("label", Json::from(format!("0x{high:02X}{low:02X}"))),
+                            ("paddingLeft", Json::from(true)),
+                        ]))
+                    })
+                    .collect();
+                Some(Json::Array(hints))
+            },
+            _ => None,
+        }
+    }
+}
+
+// `vole-machine lsp`
+pub fn lsp_command(_args: &[String]) -> bool {
+    Server::new(std::io::stdout()).serve(std::io::stdin().lock());
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///copy.asm";
+
+    // The messages the server sends for the framed messages, up to the end of the input
+    fn exchange(messages: &[Json]) -> Vec<Json> {
+        let input: String = messages.iter()
+            .map(|message| {
+                let body = message.to_string();
+                format!("Content-Length: {}\r\n\r\n{body}", body.len())
+            })
+            .collect();
+        let mut server = Server::new(Vec::new());
+        server.serve(input.as_bytes());
+        let mut output = server.writer.as_slice();
+        std::iter::from_fn(|| json::read_message(&mut output).unwrap()).collect()
+    }
+
+    fn request(id: u64, method: &str, params: Json) -> Json {
+        Json::object([("jsonrpc", Json::from("2.0")), ("id", Json::from(id)), ("method", Json::from(method)), ("params", params)])
+    }
+
+    fn open(text: &str) -> Json {
+        let document = Json::object([("uri", Json::from(URI)), ("languageId", Json::from("vole")), ("text", Json::from(text))]);
+        Json::object([("jsonrpc", Json::from("2.0")), ("method", Json::from("textDocument/didOpen")), ("params", Json::object([("textDocument", document)]))])
+    }
+
+    fn at(line: u64, character: u64) -> Json {
+        Json::object([
+            ("textDocument", Json::object([("uri", Json::from(URI))])),
+            ("position", Json::object([("line", Json::from(line)), ("character", Json::from(character))])),
+        ])
+    }
+
+    fn result(message: &Json) -> String {
+        message.get("result").unwrap().to_string()
+    }
+
+    #[test]
+    fn hover_definition_and_hints() {
+        let messages = exchange(&[
+            open(include_str!("../programs/copy.asm")),
+            request(1, "textDocument/hover", at(3, 10)),
+            request(2, "textDocument/definition", at(14, 18)),
+            request(3, "textDocument/inlayHint", at(0, 0)),
+            request(4, "textDocument/hover", at(0, 0)),
+        ]);
+        assert_eq!(
+            messages[0].get("params").and_then(|params| params.get("diagnostics")),
+            Some(&Json::Array(Vec::new()))
+        );
+        assert!(result(&messages[1]).contains(r#""value":"`0x2003` at m0x30: Load 0x03 into r0""#), "{}", result(&messages[1]));
+        assert_eq!(result(&messages[2]), format!(
+            r#"{{"uri":"{URI}","range":{{"start":{{"line":7,"character":0}},"end":{{"line":7,"character":23}}}}}}"#
+        ));
+        let hints = messages[3].get("result").and_then(Json::as_array).unwrap();
+        assert_eq!(hints.len(), 13);
+        assert_eq!(hints[0].get("label"), Some(&Json::from("0x2003")));
+        // A comment line has no instruction
+        assert_eq!(result(&messages[4]), "null");
+    }
+
+    #[test]
+    fn errors_become_diagnostics() {
+        let messages = exchange(&[open("load r1, 1\nfrob r2\n")]);
+        let diagnostic = &messages[0].get("params").and_then(|params| params.get("diagnostics")).and_then(Json::as_array).unwrap()[0];
+        assert_eq!(diagnostic.to_string(), concat!(
+            r#"{"range":{"start":{"line":1,"character":0},"end":{"line":1,"character":7}},"#,
+            r#""severity":1,"source":"vole-machine","message":"unknown instruction 'frob'"}"#,
+        ));
+    }
+
+    #[test]
+    fn malformed_messages() {
+        let mut input = String::from("Content-Length: 5\r\n\r\n{oops");
+        let body = request(1, "workspace/symbol", Json::Null).to_string();
+        input.push_str(&format!("Content-Length: {}\r\n\r\n{body}", body.len()));
+        let mut server = Server::new(Vec::new());
+        server.serve(input.as_bytes());
+
+        let mut output = server.writer.as_slice();
+        let messages: Vec<Json> = std::iter::from_fn(|| json::read_message(&mut output).unwrap()).collect();
+        let errors: Vec<(Option<&Json>, Option<&Json>)> = messages.iter()
+            .map(|message| (message.get("id"), message.get("error").and_then(|error| error.get("code"))))
+            .collect();
+        assert_eq!(errors, [
+            (Some(&Json::Null), Some(&Json::from(PARSE_ERROR))),
+            (Some(&Json::from(1u64)), Some(&Json::from(METHOD_NOT_FOUND))),
+        ]);
+    }
+
+    #[test]
+    fn words_under_the_cursor() {
+        let document = Document::new(URI, String::from("  jump r2, done_1 # é\n"));
+        assert_eq!(document.word_at(0, 13), Some("done_1"));
+        assert_eq!(document.word_at(0, 7), Some("r2"));
+        assert_eq!(document.word_at(0, 1), None);
+        assert_eq!(document.word_at(0, 200), None);
+        assert_eq!(document.word_at(5, 0), None);
+    }
+}
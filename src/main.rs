@@ -1,24 +1,99 @@
 //#![allow(warnings)]
 
+mod assembler;
+mod cfg;
+mod compiler;
+mod dap;
+mod decompiler;
+mod events;
+mod gdb;
+mod image;
+mod instruction;
+mod json;
+mod lsp;
+mod optimizer;
+mod render;
+mod rpc;
+mod screen;
+mod spec;
+mod superoptimizer;
+mod symbolic;
+mod taint;
+mod tui;
+mod verify;
+mod web;
+
 // Imports for sleeping
-use std::io::Write;
+use std::io::{IsTerminal, Write};
 use std::thread;
 use std::time::{Duration, Instant};
 
+use events::Observer;
+use instruction::Instruction;
+use render::{Cell, Marker, Renderer, Table};
+
 // Aliases
 type Address = u8;
 type HeatLevel = u8;
 
 // Sleep for 0.5 seconds
 const SLEEP_DURATION: Duration = Duration::from_millis(500);
+// Instructions per second a program can run at, None for as fast as the machine goes
+const SPEEDS: [Option<u32>; 8] = [Some(1), Some(2), Some(5), Some(10), Some(50), Some(200), Some(1000), None];
+// 2 per second, one instruction every SLEEP_DURATION
+const DEFAULT_SPEED: usize = 1;
+// How often the screen is redrawn when running faster than it can show
+const FRAME_DURATION: Duration = Duration::from_millis(20);
+// How often keys are checked while waiting for the next cycle
+const KEY_POLL_DURATION: Duration = Duration::from_millis(10);
 const MAX_HEAT: HeatLevel = 5;
+// Number of entries kept in the trace log
+const TRACE_LENGTH: usize = 1000;
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(command) = args.first() {
+        command_line(command, &args[1..]);
+        return;
+    }
+
     introduction();
     let mut cpu = Cpu::new();
+    let mut keep_memory = false;
     loop {
-        cpu.import(program());
-        cpu.run();
+        let program = program();
+        let spec = match spec::Spec::find(&program.name) {
+            Ok(spec) => spec,
+            Err(error) => {
+                println!("Ignoring the spec for program {}: {error}", program.name);
+                None
+            }
+        };
+
+        let warnings = verify::verify(&program);
+        if !warnings.is_empty() {
+            Terminal::clear();
+            verify::print_warnings(&program.name, &warnings);
+            println!();
+            Terminal::continue_prompt();
+        }
+
+        // Garbage only fills memory at power-on, not memory kept from the last run
+        if cpu.update_sanitizer() && !keep_memory {
+            cpu.fill_with_garbage();
+        }
+        cpu.update_taint();
+        cpu.import(program);
+        if let Some(spec) = &spec {
+            spec.prepare(&mut cpu);
+            cpu.spec_watch = Some(spec.watch());
+        }
+        let end = cpu.run();
+
+        // The spec is checked against the run that was just shown
+        if let Some(spec) = &spec {
+            spec.report(&mut cpu, &end).print();
+        }
 
         if prompt("\nWould you like to run another program? (y/n)\n> ",
             &mut |input, modify: &mut bool| -> bool {
@@ -29,10 +104,78 @@ fn main() {
             break;
         }
 
+        keep_memory = prompt("\nKeep the memory of this run for the next program? (y/n)\n> ",
+            &mut |input, modify: &mut bool| -> bool {
+                *modify = matches!(input, 'y' | 'Y');
+                matches!(input, 'y' | 'n' | 'Y' | 'N')
+            }
+        );
+        match keep_memory {
+            true => cpu.reset_keeping_memory(),
+            false => cpu.reset(),
+        }
+
         Terminal::clear();
     }
 }
 
+fn command_line(command: &str, args: &[String]) {
+    let succeeded = match command {
+        "check" => spec::check_command(args),
+        "cfg" => cfg::cfg_command(args),
+        "verify" => verify::verify_command(args),
+        "compile" => compiler::compile_command(args),
+        "assemble" => assembler::assemble_command(args),
+        "decompile" => decompiler::decompile_command(args),
+        "optimize" => optimizer::optimize_command(args),
+        "superoptimize" => superoptimizer::superoptimize_command(args),
+        "symbolic" => symbolic::symbolic_command(args),
+        "why" => taint::why_command(args),
+        "gdb" => gdb::gdb_command(args),
+        "dap" => dap::dap_command(args),
+        "rpc" => rpc::rpc_command(args),
+        "web" => web::web_command(args),
+        "lsp" => lsp::lsp_command(args),
+        "snapshot" => render::snapshot_command(args),
+        "tui" => tui::tui_command(args),
+        _ => {
+            println!("Unknown command '{command}'.");
+            println!("Usage:");
+            println!("  vole-machine                      Run the interactive emulator");
+            println!("  vole-machine check <spec>...      Check programs against assignment specs");
+            println!("  vole-machine cfg <program>        Print the control-flow graph of a program as DOT");
+            println!("  vole-machine verify <program>...  Check programs for likely mistakes without running them");
+            println!("  vole-machine compile <file.tiny>  Compile a Tiny program and list the code for each line");
+            println!("  vole-machine assemble <file.asm>  Assemble a program and list the code for each line");
+            println!("  vole-machine decompile <program>  Print a program as structured pseudocode");
+            println!("  vole-machine optimize <program>   Remove instructions that have no effect");
+            println!("  vole-machine superoptimize <program> <inputs> <outputs> [length]");
+            println!("                                    Find the shortest sequences computing the same outputs");
+            println!("  vole-machine symbolic <program> <symbols> reach <address> | halt [<location>=<value>]...");
+            println!("                                    Find inputs that reach an address or halt with given outputs");
+            println!("  vole-machine why <program> <location>...");
+            println!("                                    Show which inputs and instructions produced a value");
+            println!("  vole-machine gdb <program> [port] Debug a program with GDB over the remote protocol");
+            println!("  vole-machine dap                  Serve the Debug Adapter Protocol on stdin and stdout");
+            println!("  vole-machine rpc [<host:port> | unix:<path>]");
+            println!("                                    Serve JSON-RPC to load, run and inspect programs");
+            println!("  vole-machine web <program> [port] Show a program running in the browser");
+            println!("  vole-machine lsp                  Serve the Language Server Protocol for assembly on stdin and stdout");
+            println!("  vole-machine snapshot <program> [ansi | plain | html] [cycles]");
+            println!("                                    Run a program and print its final state");
+            println!("  vole-machine tui <program>        Step, run and edit a program in a full-screen interface");
+            println!("A <program> is a name from the library, a Tiny (.tiny) or assembly (.asm) source file, a memory");
+            println!("image (.image), or a file of hex bytes, optionally starting with @XY to load them at m0xXY. Add @XY");
+            println!("to the name of a relocatable program to load it at m0xXY instead, e.g. A@80.");
+            false
+        }
+    };
+
+    if !succeeded {
+        std::process::exit(1);
+    }
+}
+
 fn introduction() {
     Terminal::clear();
     println!("{0:=^33} Vole-Machine {0:=^33}", "");
@@ -41,10 +184,9 @@ fn introduction() {
     Terminal::continue_prompt();
     Terminal::clear();
     
-    println!("{line1}\n{line2}\n{line3}",
-        line1 = "When printing the memory and registers, the color of the text",
-        line2 = "indicates the \"heat\" of the memory or register. The hotter the",
-        line3 = "color, the more recent the memory or register was accessed.",
+    println!("When printing the memory and registers, the color of the text\n\
+        indicates the \"heat\" of the memory or register. The hotter the\n\
+        color, the more recent the memory or register was accessed."
     );
     println!("{:>80}", "2 of 4");
     Terminal::continue_prompt();
@@ -65,43 +207,42 @@ fn introduction() {
     let t_width = 9;
     
     println!("Color Key:");
-    println!("  {w}{color:<c_width$}{ra}::  {temp:<t_width$}::  {desc}",
+    println!("  {w}{color:<c_width$}{ra}::  {temp:<t_width$}::  Last modified at least 6 cycles ago or never",
         color = "White",
         temp = "Coldest",
-        desc = "Last modified at least 6 cycles ago or never",
     );
-    println!("  {b}{color:<c_width$}{ra}::  {temp:<t_width$}::  {desc}",
+    println!("  {b}{color:<c_width$}{ra}::  {temp:<t_width$}::  Last modified 5 cycles ago",
         color = "Blue",
         temp = "Cold",
-        desc = "Last modified 5 cycles ago",
     );
-    println!("  {c}{color:<c_width$}{ra}::  {temp:<t_width$}::  {desc}",
+    println!("  {c}{color:<c_width$}{ra}::  {temp:<t_width$}::  Last modified 4 cycles ago",
         color = "Cyan",
         temp = "Cool",
-        desc = "Last modified 4 cycles ago",
     );
-    println!("  {g}{color:<c_width$}{ra}::  {temp:<t_width$}::  {desc}",
+    println!("  {g}{color:<c_width$}{ra}::  {temp:<t_width$}::  Last modified 3 cycles ago",
         color = "Green",
         temp = "Warm",
-        desc = "Last modified 3 cycles ago",
     );
-    println!("  {y}{color:<c_width$}{ra}::  {temp:<t_width$}::  {desc}",
+    println!("  {y}{color:<c_width$}{ra}::  {temp:<t_width$}::  Last modified 2 cycles ago",
         color = "Yellow",
         temp = "Hot",
-        desc = "Last modified 2 cycles ago",
     );
-    println!("  {r}{color:<c_width$}{ra}::  {temp:<t_width$}::  {desc}",
+    println!("  {r}{color:<c_width$}{ra}::  {temp:<t_width$}::  Last modified 1 cycle ago",
         color = "Red",
         temp = "Hottest",
-        desc = "Last modified 1 cycle ago",
     );
     println!("{:>80}", "3 of 4");
     Terminal::continue_prompt();
     Terminal::clear();
 
-    println!("{}\n{}{m}Magenta{ra}",
-        "The memory address the program counter is pointing to is indicated by",
-        "an asterisk (*) next to the value in memory colored in ",
+    println!("The memory address the program counter is pointing to is indicated by\n\
+        an asterisk (*) next to the value in memory colored in {m}Magenta{ra}"
+    );
+    println!("Instructions the program has overwritten while running are indicated\n\
+        by an exclamation mark (!) next to the value in memory."
+    );
+    println!("For programs using the stack extension, the bytes on the stack are\n\
+        indicated by a caret (^) next to the value in memory."
     );
     println!("{:>80}", "4 of 4");
     Terminal::continue_prompt();
@@ -111,76 +252,299 @@ fn introduction() {
 #[derive(PartialEq, Eq)]
 struct Cpu {
     register: [u8; 16],
-    heated_register: [HeatLevel; 16],
     memory: [u8; 256],
-    heated_memory: [HeatLevel; 256],
+    heat: events::HeatMap,
     pc: Address, // Program Counter
     program_name: String,
     cycles: u128,
     iterate_by: IterationFormat,
+    speed: usize, // Index into SPEEDS for the automatic mode
+    instruction_at: [Option<Address>; 256], // For every code byte, the address of its instruction
+    modified_code: [bool; 256],
+    trace: events::Trace,
+    events: Vec<events::Event>, // What the instruction being executed did so far
+    sanitizer: Sanitizer,
+    initialized_register: [bool; 16],
+    initialized_memory: [bool; 256],
+    extensions: Extensions,
+    sp: Address, // Stack Pointer, 0x00 while the stack is empty
+    status: Flags, // Status Register
+    taint: Option<taint::Taint>, // Where every value comes from, when tracked
+    spec_watch: Option<spec::Watch>, // What the spec of the program checks while it runs
 }
 
 impl Cpu {
     fn new() -> Cpu {
         Cpu {
             register: [0; 16],
-            heated_register: [0; 16],
             memory: [0; 256],
-            heated_memory: [0; 256],
+            heat: events::HeatMap::new(),
             pc: 0,
             program_name: String::new(),
             cycles: 0,
             iterate_by: IterationFormat::Auto,
+            speed: DEFAULT_SPEED,
+            instruction_at: [None; 256],
+            modified_code: [false; 256],
+            trace: events::Trace::new(),
+            events: Vec::new(),
+            sanitizer: Sanitizer::Off,
+            initialized_register: [false; 16],
+            initialized_memory: [false; 256],
+            extensions: Extensions::default(),
+            sp: 0,
+            status: Flags::default(),
+            taint: None,
+            spec_watch: None,
         }
     }
 
-    fn run(&mut self) {
+    // Run the program the way the user chose, and return how it ended
+    fn run(&mut self) -> Step {
         self.update_iteration_format();
         let start_time = Instant::now();
 
+        let end = match self.iterate_by {
+            IterationFormat::FullScreen => match tui::run(self) {
+                Ok(end) => end,
+                Err(error) => {
+                    println!("{error}");
+                    Terminal::continue_prompt();
+                    self.iterate_by = IterationFormat::NoCycles;
+                    self.run_cycles()
+                },
+            },
+            _ => self.run_cycles(),
+        };
+
+        self.print();
+        let mut renderer = Terminal::renderer();
+        self.render_trace_events(renderer.as_mut(), false);
+        print!("{}", renderer.finish());
+        if let Step::Fault(fault) = end {
+            println!("\n{fault}");
+        }
+        match end {
+            // Stopping the program from the keyboard leaves it where it was
+            Step::Continue => println!("\nProgram {} was stopped at m0x{:02X} after {:.2} seconds.",
+                self.program_name,
+                self.pc,
+                start_time.elapsed().as_secs_f32()
+            ),
+            Step::Halt | Step::Fault(_) => println!("\nProgram {} completed in {:.2} seconds.",
+                self.program_name,
+                start_time.elapsed().as_secs_f32()
+            ),
+        }
+        self.ask_why();
+        end
+    }
+
+    // Run to the halt or a fault, showing the cycles the way the user chose, or until the user
+    // stops the program and Continue is returned
+    fn run_cycles(&mut self) -> Step {
+        // Cycles are drawn on the alternate screen, unless the output is piped somewhere
+        let mut screen = (self.iterate_by != IterationFormat::NoCycles && std::io::stdout().is_terminal())
+            .then(screen::Screen::enter);
+        // On the screen, keys pause, resume and change the speed
+        let keys = screen.as_ref().and_then(|_| screen::RawMode::enter().ok());
+        let mut last_frame = Instant::now();
         loop {
-            self.print_iteration();
-            self.cool_down();
-            self.cycles += 1;
-            
-            let mut possible_jump_address: Option<Address> = None;
-            match self.memory[self.pc as usize] >> 4 {
-                // Match against the upper 4 bits of the current byte
-                0x0 => self.no_op(),     // 0x0000 :: No Operation
-                0x1 => self.load_from(), // 0x1[RXY] :: Load from m0xXY into rR
-                0x2 => self.load(),      // 0x2[RXY] :: Load 0xXY into rR
-                0x3 => self.store(),     // 0x3[RXY] :: Store from rR into m0xXY
-                0x4 => self.move_op(),   // 0x40[RS] :: Move from rR to rS
-                0x5 => self.add_tc(),    // 0x5[RST] :: rS + rT into rR (Two's Complement)
-                0x6 => self.add_fl(),    // 0x6[RST] :: rS + rT into rR (Floating Point)
-                0x7 => self.or(),        // 0x7[RST] :: rS | rT into R
-                0x8 => self.and(),       // 0x8[RST] :: rS & rT into rR
-                0x9 => self.xor(),       // 0x9[RST] :: rS ^ rT into rR
-                0xA => self.rotate(),    // 0xA[R]0[X] :: rR >> 0xX // Rotate Right X bits
-                0xB => possible_jump_address = self.jump(), // 0xB[RXY] :: if rR == r0 then PC = m0xXY
-                0xC => break,            // 0xC000 :: Stop the CPU
-                _ => panic!("Invalid OpCode"),
+            match (screen.as_mut(), &keys) {
+                (Some(screen), Some(keys)) => {
+                    if !self.wait_for_cycle(screen, keys, &mut last_frame) {
+                        return Step::Continue;
+                    }
+                },
+                (screen, _) => self.print_iteration(screen),
             }
+            match self.step() {
+                Step::Continue => {},
+                end => return end,
+            }
+        }
+    }
+
+    // Show the cycle and wait until it's time for the next, or for the user to step in the manual
+    // mode. Space or p switches between the modes, + and - change the speed. Ctrl-C pauses, and
+    // stops the program when it's already paused, which returns false.
+    fn wait_for_cycle(&mut self, screen: &mut screen::Screen, keys: &screen::RawMode, last_frame: &mut Instant) -> bool {
+        use screen::Key;
 
-            match possible_jump_address {
-                Some(address) => self.pc = address,
-                None => self.pc = self.pc.wrapping_add(2)
+        let waiting_since = Instant::now();
+        loop {
+            let manual = self.iterate_by == IterationFormat::User;
+            // At full speed the screen is only drawn as often as it can be seen
+            if manual || SPEEDS[self.speed].is_some() || last_frame.elapsed() >= FRAME_DURATION {
+                let help = match manual {
+                    true => format!("Paused. Enter steps, Space runs at {}, + and - change the speed, Ctrl-C stops",
+                        describe_speed(self.speed)
+                    ),
+                    false => format!("Running at {}. Space pauses, + and - change the speed",
+                        describe_speed(self.speed)
+                    ),
+                };
+                let mut renderer = render::Ansi::new();
+                self.render(&mut renderer);
+                renderer.line(&help);
+                screen.draw(renderer.take_lines());
+                *last_frame = Instant::now();
+            }
+
+            for key in keys.read_keys() {
+                match key {
+                    Key::Char(' ') | Key::Char('p') => self.iterate_by = match manual {
+                        true => IterationFormat::Auto,
+                        false => IterationFormat::User,
+                    },
+                    Key::Interrupt if manual => return false,
+                    Key::Interrupt => self.iterate_by = IterationFormat::User,
+                    Key::Enter | Key::Char('s') if manual => return true,
+                    Key::Char('+') | Key::Char('=') => self.speed = (self.speed + 1).min(SPEEDS.len() - 1),
+                    Key::Char('-') => self.speed = self.speed.saturating_sub(1),
+                    _ => {},
+                }
+            }
+
+            if self.iterate_by == IterationFormat::Auto {
+                let interval = speed_interval(self.speed);
+                if waiting_since.elapsed() >= interval {
+                    return true;
+                }
+                thread::sleep(KEY_POLL_DURATION.min(interval.saturating_sub(waiting_since.elapsed())));
+            } else {
+                thread::sleep(KEY_POLL_DURATION);
             }
         }
+    }
 
-        self.print();
-        println!("\nProgram {} completed in {:.2} seconds.",
-            self.program_name,
-            start_time.elapsed().as_secs_f32()
-        );
+    // Print where values came from until the user moves on
+    fn ask_why(&self) {
+        let Some(taint) = &self.taint else {
+            return;
+        };
+        loop {
+            let location = prompt(
+                "\nAsk why a register or memory cell holds its value (e.g. r2 or m0x10), or press Enter to continue\n> ",
+                &mut |input: &String, modify: &mut Option<Location>| -> bool {
+                    *modify = taint::parse_location(input);
+                    input.is_empty() || modify.is_some()
+                },
+            );
+            match location {
+                Some(location) => print!("\n{}", taint.why(self, location)),
+                None => break,
+            }
+        }
+    }
 
-        self.reset();
+    // Execute the instruction the program counter is pointing to
+    fn step(&mut self) -> Step {
+        self.step_with(&mut ())
+    }
+
+    // Execute an instruction, then tell the heat map, the trace and the observer what it did
+    fn step_with(&mut self, observer: &mut dyn events::Observer) -> Step {
+        let step = self.execute();
+        match step {
+            Step::Continue => {},
+            Step::Halt => self.events.push(events::Event::Halted { address: self.pc }),
+            Step::Fault(fault) => self.events.push(events::Event::Faulted(fault)),
+        }
+
+        let mut events = std::mem::take(&mut self.events);
+        for event in &events {
+            self.heat.notify(self.cycles, event);
+            self.trace.notify(self.cycles, event);
+            if let Some(watch) = &mut self.spec_watch {
+                watch.notify(self.cycles, event);
+            }
+            observer.notify(self.cycles, event);
+        }
+        events.clear();
+        self.events = events;
+        step
+    }
+
+    fn execute(&mut self) -> Step {
+        self.cycles += 1;
+        self.mark_executable(self.pc);
+        let instruction = Instruction::fetch(&self.memory, self.pc, self.extensions);
+        self.events.push(events::Event::InstructionFetched { address: self.pc, instruction });
+        if let Some(fault) = self.sanitize(&instruction) {
+            return Step::Fault(fault);
+        }
+        if let Some(mut taint) = self.taint.take() {
+            taint.record(self, instruction);
+            self.taint = Some(taint);
+        }
+        if let Some(r) = instruction.register_written() {
+            self.initialized_register[r] = true;
+        }
+        if let Some(address) = instruction.memory_written() {
+            self.initialized_memory[address as usize] = true;
+        }
+
+        let mut possible_jump_address: Option<Address> = None;
+        match self.memory[self.pc as usize] >> 4 {
+            // Match against the upper 4 bits of the current byte
+            0x0 => self.no_op(),     // 0x0000 :: No Operation
+            0x1 => self.load_from(), // 0x1[RXY] :: Load from m0xXY into rR
+            0x2 => self.load(),      // 0x2[RXY] :: Load 0xXY into rR
+            0x3 => self.store(),     // 0x3[RXY] :: Store from rR into m0xXY
+            0x4 => self.move_op(),   // 0x40[RS] :: Move from rR to rS
+            0x5 => self.add_tc(),    // 0x5[RST] :: rS + rT into rR (Two's Complement)
+            0x6 => self.add_fl(),    // 0x6[RST] :: rS + rT into rR (Floating Point)
+            0x7 => self.or(),        // 0x7[RST] :: rS | rT into R
+            0x8 => self.and(),       // 0x8[RST] :: rS & rT into rR
+            0x9 => self.xor(),       // 0x9[RST] :: rS ^ rT into rR
+            0xA => self.rotate(),    // 0xA[R]0[X] :: rR >> 0xX // Rotate Right X bits
+            0xB => possible_jump_address = self.jump(), // 0xB[RXY] :: if rR == r0 then PC = m0xXY
+            0xC => return Step::Halt, // 0xC000 :: Stop the CPU
+            0xF if self.extensions.flags => match instruction {
+                // 0xF[C]XY :: if condition C then PC = m0xXY
+                Instruction::JumpIf { condition, address } => possible_jump_address = self.jump_if(condition, address),
+                _ => return Step::Fault(Fault::InvalidOpCode { address: self.pc, opcode: 0xF }),
+            },
+            0xD | 0xE if self.extensions.stack => {
+                let result = match instruction {
+                    Instruction::Call { address } => self.call(address),  // 0xD0XY :: Push PC + 2, then PC = m0xXY
+                    Instruction::Return => self.return_op(),              // 0xD100 :: Pop PC
+                    Instruction::Push { r } => self.push(r),              // 0xE[R]00 :: Push rR
+                    Instruction::Pop { r } => self.pop(r),                // 0xE[R]01 :: Pop into rR
+                    _ => Err(Fault::InvalidOpCode { address: self.pc, opcode: instruction.opcode() }),
+                };
+                match result {
+                    Ok(address) => possible_jump_address = address,
+                    Err(fault) => return Step::Fault(fault),
+                }
+            },
+            opcode => return Step::Fault(Fault::InvalidOpCode { address: self.pc, opcode }),
+        }
+
+        match possible_jump_address {
+            Some(address) => {
+                self.events.push(events::Event::JumpTaken { from: self.pc, to: address });
+                self.pc = address;
+            },
+            None => self.pc = self.pc.wrapping_add(2)
+        }
+
+        Step::Continue
     }
 
     fn reset(&mut self) {
         *self = Cpu::new();
     }
 
+    // Reset everything but the memory, so the next program sees what this one left behind
+    fn reset_keeping_memory(&mut self) {
+        let (memory, initialized_memory) = (self.memory, self.initialized_memory);
+        self.reset();
+        self.memory = memory;
+        self.initialized_memory = initialized_memory;
+    }
+
     fn no_op(&mut self) {
         // 0x0000 :: No Operation
         // Do nothing
@@ -190,216 +554,506 @@ impl Cpu {
     fn load_from(&mut self) {
         // 0x1[RXY] :: Load from m0xXY into rR
         let r = (self.memory[self.pc as usize] & 0x0F) as usize; // The lower 4 bits of byte 1 are the register
-        let addr = self.memory[self.pc as usize + 1]; // The next byte is the address to load from
+        let addr = self.memory[self.pc.wrapping_add(1) as usize]; // The next byte is the address to load from
         self.register[r] = self.memory[addr as usize]; // Load the value from memory into the register
-        
-        self.heated_register[r] = MAX_HEAT;
+
+        self.events.push(events::Event::MemoryRead { address: addr, value: self.register[r] });
+        self.register_written(r);
     }
 
     // Load a value into a register
     fn load(&mut self) {
         // 0x2[RXY] :: Load 0xXY into rR
         let r = (self.memory[self.pc as usize] & 0x0F) as usize; // The lower 4 bits of byte 1 are the register
-        let value = self.memory[self.pc as usize + 1]; // The next byte is the value to load
+        let value = self.memory[self.pc.wrapping_add(1) as usize]; // The next byte is the value to load
         self.register[r] = value; // Load the value into the register
 
-        self.heated_register[r] = MAX_HEAT;
+        self.register_written(r);
     }
 
     // Store a value from a register into memory
     fn store(&mut self) {
         // 0x3[RXY] :: Store from rR into m0xXY
         let r = (self.memory[self.pc as usize] & 0x0F) as usize; // The lower 4 bits of byte 1 are the register
-        let addr = self.memory[self.pc as usize + 1]; // The next byte is the address to store into
+        let addr = self.memory[self.pc.wrapping_add(1) as usize]; // The next byte is the address to store into
+        let old = self.memory[addr as usize];
         self.memory[addr as usize] = self.register[r]; // Store the value from the register into memory
-        
-        self.heated_memory[addr as usize] = MAX_HEAT;
+
+        self.memory_written(addr, old);
     }
 
     // Move a value from one register to another
     fn move_op(&mut self) {
         // 0x40[RS] :: Move from rR to rS
-        let r1 = (self.memory[self.pc as usize + 1] >> 4) as usize; // The upper 4 bits of byte 2 are the first register
-        let r2 = (self.memory[self.pc as usize + 1] & 0x0F) as usize; // The lower 4 bits of byte 2 are the second register
+        let r1 = (self.memory[self.pc.wrapping_add(1) as usize] >> 4) as usize; // The upper 4 bits of byte 2 are the first register
+        let r2 = (self.memory[self.pc.wrapping_add(1) as usize] & 0x0F) as usize; // The lower 4 bits of byte 2 are the second register
         self.register[r2] = self.register[r1]; // Move the value from r1 to r2
-        
-        self.heated_register[r1] = MAX_HEAT;
+
+        self.register_written(r2);
     }
 
     // Add two values together using two's complement
     fn add_tc(&mut self) {
         // 0x5[RST] :: rS + rT into rR (Two's Complement)
         let r1 = (self.memory[self.pc as usize] & 0x0F) as usize; // The lower 4 bits of byte 1 are the first register
-        let r2 = (self.memory[self.pc as usize + 1] >> 4) as usize; // The upper 4 bits of byte 2 are the second register
-        let r3 = (self.memory[self.pc as usize + 1] & 0x0F) as usize; // The lower 4 bits of byte 2 are the third register
-        let sum = (self.register[r2] as i8).wrapping_add(self.register[r3] as i8); // Add the two values together
+        let r2 = (self.memory[self.pc.wrapping_add(1) as usize] >> 4) as usize; // The upper 4 bits of byte 2 are the second register
+        let r3 = (self.memory[self.pc.wrapping_add(1) as usize] & 0x0F) as usize; // The lower 4 bits of byte 2 are the third register
+        // Read both values first, the result may overwrite one of them
+        let (a, b) = (self.register[r2], self.register[r3]);
+        let sum = (a as i8).wrapping_add(b as i8); // Add the two values together
         self.register[r1] = sum as u8; // Store the result in the first register
-        
-        self.heated_register[r1] = MAX_HEAT;
+
+        self.update_flags(r1,
+            a.checked_add(b).is_none(), // Carry out of bit 7
+            (a as i8).checked_add(b as i8).is_none() // Signed overflow
+        );
+        self.register_written(r1);
     }
 
     // Add two values together using floating point
     fn add_fl(&mut self) {
         // 0x6[RST] :: rS + rT into rR Add (Floating Point)
         let r1 = (self.memory[self.pc as usize] & 0x0F) as usize; // The lower 4 bits of byte 1 are the first register
-        let r2 = (self.memory[self.pc as usize + 1] >> 4) as usize; // The upper 4 bits of byte 2 are the second register
-        let r3 = (self.memory[self.pc as usize + 1] & 0x0F) as usize; // The lower 4 bits of byte 2 are the third register
+        let r2 = (self.memory[self.pc.wrapping_add(1) as usize] >> 4) as usize; // The upper 4 bits of byte 2 are the second register
+        let r3 = (self.memory[self.pc.wrapping_add(1) as usize] & 0x0F) as usize; // The lower 4 bits of byte 2 are the third register
         let sum = self.register[r2] as f32 + self.register[r3] as f32; // Add the two values together
         self.register[r1] = sum as u8; // Store the result in the first register
         
-        self.heated_register[r1] = MAX_HEAT;
+        let out_of_range = sum > u8::MAX as f32; // The sum didn't fit and was clamped
+        self.update_flags(r1, out_of_range, out_of_range);
+        self.register_written(r1);
     }
 
     // Bitwise OR two values together
     fn or(&mut self) {
         // 0x7[RST] :: rS | rT into R
         let r1 = (self.memory[self.pc as usize] & 0x0F) as usize; // The lower 4 bits of byte 1 are the first register
-        let r2 = (self.memory[self.pc as usize + 1] >> 4) as usize; // The upper 4 bits of byte 2 are the second register
-        let r3 = (self.memory[self.pc as usize + 1] & 0x0F) as usize; // The lower 4 bits of byte 2 are the third register
+        let r2 = (self.memory[self.pc.wrapping_add(1) as usize] >> 4) as usize; // The upper 4 bits of byte 2 are the second register
+        let r3 = (self.memory[self.pc.wrapping_add(1) as usize] & 0x0F) as usize; // The lower 4 bits of byte 2 are the third register
         self.register[r1] = self.register[r2] | self.register[r3]; // OR the two values together and store the result in the first register
         
-        self.heated_register[r1] = MAX_HEAT;
+        self.update_flags(r1, false, false);
+        self.register_written(r1);
     }
 
     // Bitwise AND two values together
     fn and(&mut self) {
         // 0x8[RST] :: rS & rT into rR
         let r1 = (self.memory[self.pc as usize] & 0x0F) as usize; // The lower 4 bits of byte 1 are the first register
-        let r2 = (self.memory[self.pc as usize + 1] >> 4) as usize; // The upper 4 bits of byte 2 are the second register
-        let r3 = (self.memory[self.pc as usize + 1] & 0x0F) as usize; // The lower 4 bits of byte 2 are the third register
+        let r2 = (self.memory[self.pc.wrapping_add(1) as usize] >> 4) as usize; // The upper 4 bits of byte 2 are the second register
+        let r3 = (self.memory[self.pc.wrapping_add(1) as usize] & 0x0F) as usize; // The lower 4 bits of byte 2 are the third register
         self.register[r1] = self.register[r2] & self.register[r3]; // AND the two values together and store the result in the first register
         
-        self.heated_register[r1] = MAX_HEAT;
+        self.update_flags(r1, false, false);
+        self.register_written(r1);
     }
 
     // Bitwise XOR two values together
     fn xor(&mut self) {
         // 0x9[RST] :: rS ^ rT into rR
         let r1 = (self.memory[self.pc as usize] & 0x0F) as usize; // The lower 4 bits of byte 1 are the first register
-        let r2 = (self.memory[self.pc as usize + 1] >> 4) as usize; // The upper 4 bits of byte 2 are the second register
-        let r3 = (self.memory[self.pc as usize + 1] & 0x0F) as usize; // The lower 4 bits of byte 2 are the third register
+        let r2 = (self.memory[self.pc.wrapping_add(1) as usize] >> 4) as usize; // The upper 4 bits of byte 2 are the second register
+        let r3 = (self.memory[self.pc.wrapping_add(1) as usize] & 0x0F) as usize; // The lower 4 bits of byte 2 are the third register
         self.register[r1] = self.register[r2] ^ self.register[r3]; // XOR the two values together and store the result in the first register
         
-        self.heated_register[r1] = MAX_HEAT;
+        self.update_flags(r1, false, false);
+        self.register_written(r1);
     }
 
     // Rotate a value right by a number of bits
     fn rotate(&mut self) {
         // 0xA[R]0[X] :: rR >> 0xX // Rotate Right X bits
         let r = (self.memory[self.pc as usize] & 0x0F) as usize; // The lower 4 bits of byte 1 are the register
-        let bits = (self.memory[self.pc as usize + 1] & 0x0F) as u32; // The lower 4 bits of byte 2 is the number of bits to rotate by
+        let bits = (self.memory[self.pc.wrapping_add(1) as usize] & 0x0F) as u32; // The lower 4 bits of byte 2 is the number of bits to rotate by
         self.register[r] = self.register[r].rotate_right(bits); // Rotate the value in the register right by the number of bits
         
-        self.heated_register[r] = MAX_HEAT;
+        let carry = bits != 0 && self.register[r] & 0x80 != 0; // The last bit rotated out of bit 0 lands in bit 7
+        self.update_flags(r, carry, false);
+        self.register_written(r);
     }
 
     // Jump to an address if a condition is met
     fn jump(&mut self) -> Option<Address> {
         // 0xB[RXY] :: if rR == r0 then PC = m0xXY
         let r = (self.memory[self.pc as usize] & 0x0F) as usize; // The lower 4 bits of byte 1 are the register
-        let address = self.memory[self.pc as usize + 1]; // The next byte is the address to jump to
+        let address = self.memory[self.pc.wrapping_add(1) as usize]; // The next byte is the address to jump to
         if self.register[r] == self.register[0] {
             // Check if the value in the register is equal to the value in r0
             // Return the address to Jump to if the condition is met
-            
-            self.cool_down();
             return Some(address);
         }
 
         None
     }
 
-    fn cool_down(&mut self) {
-        for i in 0..16 {
-            self.heated_register[i] = self.heated_register[i].saturating_sub(1);
+    // Remember which bytes hold an instruction that has been or will be executed
+    fn mark_executable(&mut self, address: Address) {
+        self.instruction_at[address as usize] = Some(address);
+        self.instruction_at[address.wrapping_add(1) as usize] = Some(address);
+    }
+
+    fn detect_self_modification(&mut self, address: Address, old: u8) {
+        let new = self.memory[address as usize];
+        let Some(instruction_address) = self.instruction_at[address as usize] else {
+            return;
+        };
+        if old == new {
+            return;
+        }
+
+        self.modified_code[address as usize] = true;
+        self.events.push(events::Event::CodeModified {
+            pc: self.pc,
+            address,
+            old,
+            new,
+            instruction_address,
+            instruction: Instruction::fetch(&self.memory, instruction_address, self.extensions),
+        });
+    }
+
+    // Check that the instruction, and every register and memory cell it reads, was initialized
+    fn sanitize(&mut self, instruction: &Instruction) -> Option<Fault> {
+        if self.sanitizer == Sanitizer::Off {
+            return None;
         }
 
-        for i in 0..256 {
-            self.heated_memory[i] = self.heated_memory[i].saturating_sub(1);
+        let mut reads = vec![Location::Memory(self.pc), Location::Memory(self.pc.wrapping_add(1))];
+        reads.extend(instruction.registers_read().into_iter().map(Location::Register));
+        reads.extend(instruction.memory_read().map(Location::Memory));
+
+        for location in reads {
+            if self.is_initialized(location) {
+                continue;
+            }
+
+            match self.sanitizer {
+                Sanitizer::Fault => return Some(Fault::UninitializedRead { address: self.pc, location }),
+                _ => {
+                    // Each location is only reported once
+                    self.events.push(events::Event::UninitializedRead { pc: self.pc, location });
+                    self.set_initialized(location);
+                }
+            }
         }
+
+        None
     }
 
-    fn print_registers(&self) {
-        println!();
-        for i in 0..16 {
-            print!(" r{i:02X}");
+    fn is_initialized(&self, location: Location) -> bool {
+        match location {
+            Location::Register(r) => self.initialized_register[r],
+            Location::Memory(address) => self.initialized_memory[address as usize],
         }
+    }
 
-        println!();
-        for (i, &register) in self.register.iter().enumerate() {
-            print!("  {}{register:02X}{}",
-                Terminal::get_fg_color(Foreground::heat_from(self.heated_register[i])),
-                Terminal::get_reset_all()
-            );
+    fn set_initialized(&mut self, location: Location) {
+        match location {
+            Location::Register(r) => self.initialized_register[r] = true,
+            Location::Memory(address) => self.initialized_memory[address as usize] = true,
         }
+    }
 
-        println!();
+    // Mimic real hardware, where memory and registers hold whatever they held at power-on
+    fn fill_with_garbage(&mut self) {
+        let mut seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or_default() | 1;
+        let mut garbage = || {
+            // xorshift64
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed >> 24) as u8
+        };
+
+        self.register.iter_mut().for_each(|value| *value = garbage());
+        self.memory.iter_mut().for_each(|value| *value = garbage());
     }
 
-    fn print_memory(&self) {
-        // Print the memory, 16 bytes per line with a '*' prefix indicating the program counter
+    fn register_written(&mut self, r: usize) {
+        self.events.push(events::Event::RegisterWritten { r, value: self.register[r] });
+    }
 
-        print!("\n{:<4}", " ");
-        for i in 0..16 {
-            print!(" m{i:02X}");
+    // Record a write to memory, and whether it changed the program's code
+    fn memory_written(&mut self, address: Address, old: u8) {
+        self.events.push(events::Event::MemoryWritten { address, old, new: self.memory[address as usize] });
+        self.detect_self_modification(address, old);
+    }
+
+    // Jump to an address if a status flag condition is met
+    fn jump_if(&mut self, condition: Condition, address: Address) -> Option<Address> {
+        // 0xF[C]XY :: if condition C then PC = m0xXY
+        if condition.holds(self.status) {
+            return Some(address);
         }
 
-        for (i, &byte) in self.memory.iter().enumerate() {
-            let pc_marker = if i == self.pc as usize {
-                format!(" *{}", //" 👉"
-                    Terminal::get_fg_color(Foreground::Magenta),
-                )
-            } else {
-                format!("  {}",
-                    Terminal::get_fg_color(Foreground::heat_from(self.heated_memory[i]))
-                )
-            };
-            let first_address = i - i % 16;
+        None
+    }
 
-            if i % 16 == 0 {
-                print!("\n m{first_address:02X}");
-            }
-            print!("{pc_marker}{byte:02X}{}",
-                Terminal::get_reset_all()
-            );
+    // Set the status register from the result written into a register
+    fn update_flags(&mut self, r: usize, carry: bool, overflow: bool) {
+        if !self.extensions.flags {
+            return;
         }
 
-        println!();
+        self.status = Flags {
+            carry,
+            overflow,
+            zero: self.register[r] == 0,
+            negative: self.register[r] & 0x80 != 0,
+        };
     }
-    
+
+    // Call a subroutine, remembering the instruction after the call on the stack
+    fn call(&mut self, address: Address) -> Result<Option<Address>, Fault> {
+        // 0xD0XY :: Push PC + 2, then PC = m0xXY
+        self.push_byte(self.pc.wrapping_add(2))?;
+        Ok(Some(address))
+    }
+
+    // Return from a subroutine to the address on top of the stack
+    fn return_op(&mut self) -> Result<Option<Address>, Fault> {
+        // 0xD100 :: Pop PC
+        self.pop_byte().map(Some)
+    }
+
+    // Push the value of a register onto the stack
+    fn push(&mut self, r: usize) -> Result<Option<Address>, Fault> {
+        // 0xE[R]00 :: Push rR
+        self.push_byte(self.register[r])?;
+        Ok(None)
+    }
+
+    // Pop the value on top of the stack into a register
+    fn pop(&mut self, r: usize) -> Result<Option<Address>, Fault> {
+        // 0xE[R]01 :: Pop into rR
+        self.register[r] = self.pop_byte()?;
+
+        self.register_written(r);
+        Ok(None)
+    }
+
+    // The stack grows down from the end of memory, the stack pointer points at its top byte
+    fn push_byte(&mut self, value: u8) -> Result<(), Fault> {
+        let sp = self.sp.wrapping_sub(1);
+        if sp == 0 {
+            return Err(Fault::StackOverflow { address: self.pc });
+        }
+
+        let old = self.memory[sp as usize];
+        self.sp = sp;
+        self.memory[sp as usize] = value;
+        self.initialized_memory[sp as usize] = true;
+        self.memory_written(sp, old);
+        Ok(())
+    }
+
+    fn pop_byte(&mut self) -> Result<u8, Fault> {
+        if self.sp == 0 {
+            return Err(Fault::StackUnderflow { address: self.pc });
+        }
+
+        let value = self.memory[self.sp as usize];
+        self.events.push(events::Event::MemoryRead { address: self.sp, value });
+        self.sp = self.sp.wrapping_add(1);
+        Ok(value)
+    }
+
+    fn in_stack(&self, address: Address) -> bool {
+        self.extensions.stack && self.sp != 0 && address >= self.sp
+    }
+
+    fn render_registers(&self, renderer: &mut dyn Renderer) {
+        let mut table = Table::new((0..16).map(|i| format!("r{i:02X}")).collect());
+        let mut cells: Vec<Cell> = self.register.iter().enumerate()
+            .map(|(i, &register)| Cell::new(format!("{register:02X}"), Marker::None, Some(self.heat.register[i])))
+            .collect();
+        if self.extensions.stack {
+            table.columns.push(String::from("SP"));
+            cells.push(Cell::new(format!("{:02X}", self.sp), Marker::None, None));
+        }
+        if self.extensions.flags {
+            table.columns.push(String::from("CVZN"));
+            cells.push(Cell::new(self.status.to_string(), Marker::None, None));
+        }
+        table.rows.push((None, cells));
+
+        renderer.line("");
+        renderer.table(&table);
+    }
+
+    fn render_memory(&self, renderer: &mut dyn Renderer) {
+        renderer.line("");
+        renderer.table(&self.memory_table());
+    }
+
+    // The memory, 16 bytes per line with a '*' marking the program counter
+    fn memory_table(&self) -> Table {
+        let mut table = Table::new((0..16).map(|i| format!("m{i:02X}")).collect());
+        for (row, bytes) in self.memory.chunks(16).enumerate() {
+            let first_address = row * 16;
+            let cells = bytes.iter().enumerate().map(|(offset, &byte)| {
+                let i = first_address + offset;
+                let marker = if i == self.pc as usize {
+                    Marker::ProgramCounter
+                } else if self.modified_code[i] {
+                    Marker::Modified
+                } else if self.in_stack(i as Address) {
+                    Marker::Stack
+                } else {
+                    Marker::None
+                };
+                Cell::new(format!("{byte:02X}"), marker, Some(self.heat.memory[i]))
+            });
+            table.rows.push((Some(format!("m{first_address:02X}")), cells.collect()));
+        }
+        table
+    }
+
     fn print(&self) {
         Terminal::clear();
-        println!("\nProgram's Used CPU Cycles: {0:#02X}::{0}", self.cycles);
-        println!("Program Counter: m{:#02X}", self.pc);
-        self.print_registers();
-        self.print_memory();
+        let mut renderer = Terminal::renderer();
+        self.render(renderer.as_mut());
+        print!("{}", renderer.finish());
+    }
+
+    fn render(&self, renderer: &mut dyn Renderer) {
+        renderer.line("");
+        renderer.line(&format!("Program's Used CPU Cycles: {0:#02X}::{0}", self.cycles));
+        renderer.line(&format!("Program Counter: m{:#02X}", self.pc));
+        if self.extensions.stack {
+            renderer.line(&format!("Stack Pointer: m{:#02X}", self.sp));
+        }
+        self.render_registers(renderer);
+        self.render_memory(renderer);
+        self.render_disassembly(renderer);
+        self.render_trace_events(renderer, true);
+    }
+
+    fn render_disassembly(&self, renderer: &mut dyn Renderer) {
+        renderer.line("");
+        renderer.table(&self.disassembly_table(2, 2));
+    }
+
+    // Decode the instructions around the program counter from the current memory,
+    // so instructions the program overwrote are shown as they will now execute
+    fn disassembly_table(&self, before: u8, after: u8) -> Table {
+        let mut table = Table::new(Vec::new());
+        for offset in -(before as i16)..=after as i16 {
+            let address = self.pc.wrapping_add_signed((offset * 2) as i8);
+            let modified = self.modified_code[address as usize]
+                || self.modified_code[address.wrapping_add(1) as usize];
+            let marker = match (offset, modified) {
+                (0, _) => Marker::ProgramCounter,
+                (_, true) => Marker::Modified,
+                (_, false) => Marker::None,
+            };
+            let instruction = Instruction::fetch(&self.memory, address, self.extensions);
+            let text = format!("m0x{address:02X}  0x{:04X}  {instruction}",
+                u16::from_be_bytes([self.memory[address as usize], self.memory[address.wrapping_add(1) as usize]])
+            );
+            table.rows.push((None, vec![Cell::new(text, marker, None)]));
+        }
+        table
     }
 
-    fn print_iteration(&self) {
+    // The self-modifying writes and uninitialized reads, of the last cycle or of the whole run
+    fn render_trace_events(&self, renderer: &mut dyn Renderer, last_cycle_only: bool) {
+        let entries: Vec<&TraceEntry> = self.trace.iter()
+            .filter(|entry| !matches!(entry.event, TraceEvent::Executed(_)))
+            .filter(|entry| !last_cycle_only || entry.cycle == self.cycles)
+            .collect();
+        if entries.is_empty() {
+            return;
+        }
+
+        renderer.line("");
+        for entry in entries {
+            renderer.line(&entry.to_string());
+        }
+    }
+
+    fn print_iteration(&self, mut screen: Option<&mut screen::Screen>) {
+        let prompt = match self.iterate_by {
+            IterationFormat::User => "Press Enter to continue...",
+            IterationFormat::Auto => "",
+            IterationFormat::NoCycles | IterationFormat::FullScreen => return,
+        };
+        match screen.as_deref_mut() {
+            Some(screen) => {
+                let mut renderer = render::Ansi::new();
+                self.render(&mut renderer);
+                renderer.line(prompt);
+                screen.draw(renderer.take_lines());
+            },
+            None => {
+                self.print();
+                print!("{prompt}");
+            },
+        }
+
         match self.iterate_by {
             IterationFormat::User => {
-                self.print();
-                print!("Press Enter to continue...");
                 let mut input = String::new();
                 std::io::stdout().flush().unwrap();
                 std::io::stdin().read_line(&mut input).unwrap();
+                if let Some(screen) = screen {
+                    screen.forget_prompt();
+                }
             },
-            IterationFormat::Auto => {
-                self.print();
-                thread::sleep(SLEEP_DURATION);
-            },
-            IterationFormat::NoCycles => {}
+            IterationFormat::Auto => thread::sleep(speed_interval(self.speed)),
+            IterationFormat::NoCycles | IterationFormat::FullScreen => {},
         }
     }
 
+    // Returns whether memory should be filled with garbage before the program is loaded
+    fn update_sanitizer(&mut self) -> bool {
+        Terminal::clear();
+        let (sanitizer, garbage) =
+        prompt(format!("\n{}{}{}{}{}\n> ",
+                "How should reads of registers and memory the program never set be handled?",
+                "\n\tEnter 'o' to allow them (everything starts as 0x00)",
+                "\n\tEnter 'w' to warn about them",
+                "\n\tEnter 'f' to fault on them",
+                "\n\tEnter 'g' to warn about them and fill memory with garbage at power-on",
+            ).as_str(),
+        &mut |input, modify| -> bool {
+            match input {
+                'o' | 'O' => *modify = (Sanitizer::Off, false),
+                'w' | 'W' => *modify = (Sanitizer::Warn, false),
+                'f' | 'F' => *modify = (Sanitizer::Fault, false),
+                'g' | 'G' => *modify = (Sanitizer::Warn, true),
+                _ => return false,
+            }
+            true
+        });
+
+        self.sanitizer = sanitizer;
+        garbage
+    }
+
+    fn update_taint(&mut self) {
+        Terminal::clear();
+        let track = prompt("\nTrack where values come from, to ask why a location holds its value afterwards? (y/n)\n> ",
+            &mut |input, modify: &mut bool| -> bool {
+                *modify = matches!(input, 'y' | 'Y');
+                matches!(input, 'y' | 'n' | 'Y' | 'N')
+            }
+        );
+        self.taint = track.then(taint::Taint::new);
+    }
+
     fn update_iteration_format(&mut self) {
         Terminal::clear();
         self.iterate_by =
-        prompt(format!("\n{}{}{}{}\n> ",
+        prompt(format!("\n{}{}{}{}{}\n> ",
                 "How would you like to iterate through the program?",
                 "\n\tEnter 'm' for manual (you cycle the CPU)",
                 "\n\tEnter 'a' for automatic (shows every cycle)",
                 "\n\tEnter 'n' for no cycle (shows the final state of the CPU)",
+                "\n\tEnter 'f' for full screen (panes and keys to step, run, pause and edit)",
             ).as_str(),
         &mut |input, modify| -> bool {
             match input {
@@ -414,6 +1068,10 @@ impl Cpu {
                 'n' | 'N' => {
                     *modify = IterationFormat::NoCycles;
                     true
+                },
+                'f' | 'F' => {
+                    *modify = IterationFormat::FullScreen;
+                    true
                 }
                 _ => false
             }}
@@ -421,39 +1079,226 @@ impl Cpu {
     }
     
     fn import(&mut self, program: Program) {
+        let cfg = cfg::Cfg::build(&program);
         match program {
-            Program {name, code, start_address} if name != String::new() => {
+            Program {name, code, start_address, extensions, segments, ..} if name != String::new() => {
                 // Set the program name
                 self.program_name = name;
+                self.extensions = extensions;
 
                 // Load the program into memory 
                 for (i, &byte) in code.iter().enumerate() {
                     self.memory[start_address as usize + i] = byte;
+                    self.initialized_memory[start_address as usize + i] = true;
+                }
+                // And the segments loaded with it
+                for segment in segments {
+                    for (i, &byte) in segment.bytes.iter().enumerate() {
+                        self.memory[segment.address as usize + i] = byte;
+                        self.initialized_memory[segment.address as usize + i] = true;
+                    }
                 }
 
                 // Set the address for the program counter to start at
                 self.pc = start_address;
+
+                // Mark the instructions the program can reach as code
+                for block in cfg.reachable() {
+                    for &(address, _, _) in &block.instructions {
+                        self.mark_executable(address);
+                    }
+                }
             },
             _ => unreachable!()
         }
     }
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Location {
+    Register(usize),
+    Memory(Address),
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Location::Register(r) => write!(f, "r{r:X}"),
+            Location::Memory(address) => write!(f, "m0x{address:02X}"),
+        }
+    }
+}
+
+// The status register, set by add_tc, add_fl, or, and, xor and rotate
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+struct Flags {
+    carry: bool,    // The unsigned result didn't fit, or the last bit rotated out
+    overflow: bool, // The two's complement result didn't fit
+    zero: bool,
+    negative: bool, // Bit 7 of the result is set
+}
+
+impl std::fmt::Display for Flags {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for flag in [self.carry, self.overflow, self.zero, self.negative] {
+            write!(f, "{}", flag as u8)?;
+        }
+        Ok(())
+    }
+}
+
+// The conditions the 0xF[C]XY jump can test
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Condition {
+    Carry,
+    Overflow,
+    Zero,
+    Negative,
+    NoCarry,
+    NoOverflow,
+    NotZero,
+    NotNegative,
+}
+
+impl Condition {
+    const ALL: [Condition; 8] = [
+        Condition::Carry,
+        Condition::Overflow,
+        Condition::Zero,
+        Condition::Negative,
+        Condition::NoCarry,
+        Condition::NoOverflow,
+        Condition::NotZero,
+        Condition::NotNegative,
+    ];
+
+    fn from_nibble(nibble: u8) -> Option<Condition> {
+        Condition::ALL.get(nibble as usize).copied()
+    }
+
+    fn holds(&self, flags: Flags) -> bool {
+        match self {
+            Condition::Carry => flags.carry,
+            Condition::Overflow => flags.overflow,
+            Condition::Zero => flags.zero,
+            Condition::Negative => flags.negative,
+            Condition::NoCarry => !flags.carry,
+            Condition::NoOverflow => !flags.overflow,
+            Condition::NotZero => !flags.zero,
+            Condition::NotNegative => !flags.negative,
+        }
+    }
+
+    fn negate(&self) -> Condition {
+        Condition::ALL[(*self as usize + 4) % 8]
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let text = match self {
+            Condition::Carry => "carry",
+            Condition::Overflow => "overflow",
+            Condition::Zero => "zero",
+            Condition::Negative => "negative",
+            Condition::NoCarry => "no carry",
+            Condition::NoOverflow => "no overflow",
+            Condition::NotZero => "not zero",
+            Condition::NotNegative => "not negative",
+        };
+        write!(f, "{text}")
+    }
+}
+
+// How reads of registers and memory that were never written are handled
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+enum Sanitizer {
+    #[default]
+    Off,
+    Warn,
+    Fault,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct TraceEntry {
+    cycle: u128,
+    address: Address, // Address of the instruction that caused the entry
+    event: TraceEvent,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum TraceEvent {
+    Executed(Instruction),
+    // A store changed the bytes of an instruction that has been or will be executed
+    SelfModified {
+        address: Address,
+        old: u8,
+        new: u8,
+        instruction_address: Address,
+        instruction: Instruction,
+    },
+    UninitializedRead(Location),
+}
+
+impl std::fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:>6}  m0x{:02X}  ", self.cycle, self.address)?;
+        match &self.event {
+            TraceEvent::Executed(instruction) => write!(f, "{instruction}"),
+            TraceEvent::SelfModified { address, old, new, instruction_address, instruction } => write!(f,
+                "! m0x{address:02X} changed 0x{old:02X} -> 0x{new:02X}, m0x{instruction_address:02X} now reads {instruction}"
+            ),
+            TraceEvent::UninitializedRead(location) => write!(f, "? {location} read before it was initialized"),
+        }
+    }
+}
+
+// The outcome of executing a single instruction
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Step {
+    Continue,
+    Halt,
+    Fault(Fault),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Fault {
+    InvalidOpCode { address: Address, opcode: u8 },
+    UninitializedRead { address: Address, location: Location },
+    StackOverflow { address: Address },
+    StackUnderflow { address: Address },
+}
+
+impl std::fmt::Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Fault::InvalidOpCode { address, opcode } => {
+                write!(f, "Invalid OpCode 0x{opcode:X} at m0x{address:02X}")
+            }
+            Fault::UninitializedRead { address, location } => {
+                write!(f, "{location} read before it was initialized at m0x{address:02X}")
+            }
+            Fault::StackOverflow { address } => {
+                write!(f, "Stack overflow at m0x{address:02X}")
+            }
+            Fault::StackUnderflow { address } => {
+                write!(f, "Pop from an empty stack at m0x{address:02X}")
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Default)]
 enum IterationFormat {
     User,
+    #[default]
     Auto,
     NoCycles,
-}
-
-impl std::default::Default for IterationFormat {
-    fn default() -> Self {
-        IterationFormat::Auto
-    }
+    FullScreen,
 }
 
 #[allow(dead_code)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 enum Foreground {
     Black,
     Red,
@@ -488,10 +1333,8 @@ struct Terminal;
 #[allow(dead_code)]
 impl Terminal { 
     fn clear() {
-        // print!("\x1Bc");
-        print!("\x1B[H");
-        println!("{}", format!("{}\n", " ".repeat(80)).repeat(30));
-        print!("\x1B[H");
+        // Erase the screen and move the cursor to the top left
+        print!("\x1B[2J\x1B[H");
     }
     
     fn erase_line_cr() {
@@ -520,16 +1363,34 @@ impl Terminal {
         print!("\x1B[0m");
     }
 
+    // Colour for the terminal, plain text when the output is piped somewhere
+    fn renderer() -> Box<dyn Renderer> {
+        match std::io::stdout().is_terminal() {
+            true => Box::new(render::Ansi::new()),
+            false => Box::new(render::Plain::new()),
+        }
+    }
+
     fn continue_prompt() {
         prompt("Press Enter to continue...", &mut |_: &String, _: &mut ()| true);
     }
 }
 
+// Optional additions to the classic instruction set
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+struct Extensions {
+    stack: bool, // Stack pointer, call/return (0xD) and push/pop (0xE)
+    flags: bool, // Status register and conditional jumps on it (0xF)
+}
+
 #[derive(Clone, PartialEq)]
 struct Program {
     name: String,
     code: Vec<u8>,
     start_address: Address,
+    extensions: Extensions,
+    relocations: Option<Vec<usize>>, // Offsets of the code bytes holding an address inside the program
+    segments: Vec<image::Segment>, // Code and data loaded into memory with the program
 }
 
 impl Program {
@@ -538,8 +1399,42 @@ impl Program {
             name,
             code,
             start_address,
+            extensions: Extensions::default(),
+            relocations: None,
+            segments: Vec::new(),
         }
     }
+
+    fn with_extensions(mut self, extensions: Extensions) -> Program {
+        self.extensions = extensions;
+        self
+    }
+
+    fn with_relocations(mut self, relocations: Vec<usize>) -> Program {
+        self.relocations = Some(relocations);
+        self
+    }
+
+    // The program moved to another start address, with every address inside it patched to match
+    fn relocate(&self, start_address: Address) -> Result<Program, String> {
+        if start_address == self.start_address {
+            return Ok(self.clone());
+        }
+        let Some(relocations) = &self.relocations else {
+            return Err(format!("Program {} has no relocation table and only runs at m0x{:02X}.", self.name, self.start_address));
+        };
+        if start_address as usize + self.code.len() > 256 {
+            return Err(format!("Program {} is {} bytes long and doesn't fit at m0x{start_address:02X}.", self.name, self.code.len()));
+        }
+
+        let mut code = self.code.clone();
+        for &offset in relocations {
+            code[offset] = code[offset].wrapping_sub(self.start_address).wrapping_add(start_address);
+        }
+        let relocated = Program { code, start_address, ..self.clone() };
+        image::check_overlaps(&relocated)?;
+        Ok(relocated)
+    }
 }
 
 fn program() -> Program {
@@ -548,14 +1443,119 @@ fn program() -> Program {
     Terminal::clear();
     let text = "\nChoose a program to run:\n";
     let text = format!("{}\t{}", text, library.get_names().join("\n\t"));
-    let text = format!("{}\n> ", text);
+    let text = format!("{}\nor enter the path of a Tiny (.tiny) or assembly (.asm) source file, or a memory image (.image)\n> ", text);
 
+    let mut compiled = None;
     let mut valid = |input: &String, modify: &mut String| -> bool {
         *modify = input.clone();
-        library.get_names().contains(&input)
+        let result = if input.ends_with(".tiny") {
+            compiler::compile_file(input).map(|(program, _)| program)
+        } else if input.ends_with(".asm") {
+            assembler::assemble_file(input).map(|(program, _)| program)
+        } else if input.ends_with(".image") {
+            image::load(input)
+        } else {
+            return library.get_names().contains(input);
+        };
+        match result {
+            Ok(program) => compiled = Some(program),
+            Err(error) => println!("{error}"),
+        }
+        compiled.is_some()
     };
     let program_name = prompt(text.as_str(), &mut valid);
-    library.retrieve(program_name)
+    let program = match compiled {
+        Some(program) => program,
+        None => library.retrieve(program_name),
+    };
+
+    if program.relocations.is_none() {
+        return program;
+    }
+    let relocated = prompt(format!("\nLoad program {} at which address? Enter it in hex, or press Enter for m0x{:02X}\n> ",
+            program.name,
+            program.start_address
+        ).as_str(),
+        &mut |input: &String, modify: &mut Option<Program>| -> bool {
+            let address = match input.trim_start_matches("m0x").trim_start_matches("0x") {
+                "" => program.start_address,
+                address => match u8::from_str_radix(address, 16) {
+                    Ok(address) => address,
+                    Err(_) => return false,
+                },
+            };
+            match program.relocate(address) {
+                Ok(relocated) => *modify = Some(relocated),
+                Err(error) => {
+                    println!("{error}");
+                    return false;
+                },
+            }
+            true
+        }
+    );
+    relocated.unwrap_or(program)
+}
+
+// A program given on the command line: a name from the library, a source file or a byte image,
+// optionally followed by `@XY` to relocate it to m0xXY
+fn load_program(source: &str) -> Result<Program, String> {
+    if let Some((source, address)) = source.rsplit_once('@') {
+        if let Ok(address) = u8::from_str_radix(address, 16) {
+            return load_program(source)?.relocate(address);
+        }
+    }
+
+    if let Some(program) = ProgramLibrary::init().get(source) {
+        return Ok(program);
+    }
+    if source.ends_with(".tiny") {
+        return compiler::compile_file(source).map(|(program, _)| program);
+    }
+    if source.ends_with(".asm") {
+        return assembler::assemble_file(source).map(|(program, _)| program);
+    }
+    if source.ends_with(".image") {
+        return image::load(source);
+    }
+
+    // A byte image is hex bytes separated by whitespace, `#` starts a comment
+    let text = std::fs::read_to_string(source)
+        .map_err(|error| format!("No program named '{source}' in the library and {source}: {error}"))?;
+    let mut start_address = 0;
+    let mut code = Vec::new();
+    for word in text.lines().flat_map(|line| line.split('#').next().unwrap_or_default().split_whitespace()) {
+        let parsed = match word.strip_prefix('@') {
+            Some(address) if code.is_empty() => u8::from_str_radix(address, 16).map(|address| start_address = address),
+            _ => u8::from_str_radix(word, 16).map(|byte| code.push(byte)),
+        };
+        parsed.map_err(|_| format!("{source}: '{word}' is not a hex byte"))?;
+    }
+    if code.is_empty() || code.len() % 2 != 0 || code.len() > 256 {
+        return Err(format!("{source}: a program needs an even number of bytes, at most 256"));
+    }
+    if start_address as usize + code.len() > 256 {
+        return Err(format!("{source}: {} bytes don't fit at m0x{start_address:02X}", code.len()));
+    }
+
+    let name = std::path::Path::new(source)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| source.to_string());
+    Ok(Program::new(name, code, start_address))
+}
+
+fn describe_speed(speed: usize) -> String {
+    match SPEEDS[speed] {
+        Some(1) => String::from("1 instruction/s"),
+        Some(per_second) => format!("{per_second} instructions/s"),
+        None => String::from("full speed"),
+    }
+}
+
+// The time between two instructions, none at full speed
+fn speed_interval(speed: usize) -> Duration {
+    SPEEDS[speed].map(|per_second| Duration::from_secs_f64(1.0 / per_second as f64)).unwrap_or_default()
 }
 
 fn prompt<T, U>(text: &str, valid: &mut dyn FnMut(&U, &mut T) -> bool) -> T
@@ -588,13 +1588,14 @@ impl ProgramLibrary {
     }
 
     fn retrieve(&self, name: String) -> Program {
-        for program in &self.programs {
-            if program.name == name {
-                return program.clone();
-            }
+        match self.get(&name) {
+            Some(program) => program,
+            None => unreachable!("Program not found even though input matched an existing program."),
         }
+    }
 
-        unreachable!("Program not found even though input matched an existing program.");
+    fn get(&self, name: &str) -> Option<Program> {
+        self.programs.iter().find(|program| program.name == name).cloned()
     }
 
     fn init() -> ProgramLibrary {
@@ -619,7 +1620,7 @@ impl ProgramLibrary {
                         /* 'A        | m0x48, m0x49 | */ 0xC0, 0x00, // | 0xC000 | // Halt
                     ],
                     0x30, // Load program at m0x30
-                ),
+                ).with_relocations(vec![0x11, 0x13, 0x15, 0x17]), // The address bytes of m0x40 to m0x46
         
                 Program::new(
                     String::from("B"),
@@ -633,7 +1634,7 @@ impl ProgramLibrary {
                         /* m0x0C, m0x0D */ 0xC0, 0x00, // | 0xC000 | // Halt
                     ],
                     0x00, // Load program at m0x00
-                ),
+                ).with_relocations(vec![0x09, 0x0B]), // The address bytes of the jumps
 
                 Program::new(
                     String::from("C"),
@@ -647,7 +1648,44 @@ impl ProgramLibrary {
                         /* m0x0C, m0x0D */ 0xC0, 0x00, 
                     ],
                     0x00, // Load program at m0x00
+                ).with_relocations(vec![0x07]), // The address byte of the store over m0x00
+
+                Program::new(
+                    String::from("D"),
+                    vec![
+                        /* m0x00, m0x01 */ 0x21, 0x01, // | 0x2101 | // Load 0x01 into r1
+                        /* m0x02, m0x03 */ 0x22, 0x07, // | 0x2207 | // Load 0x07 into r2
+                        /* m0x04, m0x05 */ 0xD0, 0x0C, // | 0xD00C | // Call the subroutine at m0x0C
+                        /* m0x06, m0x07 */ 0xD0, 0x0C, // | 0xD00C | // Call the subroutine at m0x0C
+                        /* m0x08, m0x09 */ 0x31, 0x40, // | 0x3140 | // Store from r1 into m0x40
+                        /* m0x0A, m0x0B */ 0xC0, 0x00, // | 0xC000 | // Halt
+                        /* m0x0C, m0x0D */ 0xE2, 0x00, // | 0xE200 | // Push r2 onto the stack // Doubles r1, keeps r2
+                        /* m0x0E, m0x0F */ 0x40, 0x12, // | 0x4012 | // Move from r1 to r2
+                        /* m0x10, m0x11 */ 0x51, 0x12, // | 0x5112 | // r1 + r2 into r1
+                        /* m0x12, m0x13 */ 0xE2, 0x01, // | 0xE201 | // Pop from the stack into r2
+                        /* m0x14, m0x15 */ 0xD1, 0x00, // | 0xD100 | // Return from the subroutine
+                    ],
+                    0x00, // Load program at m0x00
+                )
+                .with_extensions(Extensions { stack: true, ..Extensions::default() })
+                .with_relocations(vec![0x05, 0x07]), // The address bytes of the calls
+
+                Program::new(
+                    String::from("E"),
+                    vec![
+                        /* m0x00, m0x01 */ 0x21, 0x64, // | 0x2164 | // Load 0x64 into r1 // 0x64 == 100
+                        /* m0x02, m0x03 */ 0x22, 0x64, // | 0x2264 | // Load 0x64 into r2
+                        /* m0x04, m0x05 */ 0x53, 0x12, // | 0x5312 | // r1 + r2 into r3 // 100 + 100 overflows to -56
+                        /* m0x06, m0x07 */ 0xF1, 0x0C, // | 0xF10C | // Jump to m0x0C if overflow
+                        /* m0x08, m0x09 */ 0x24, 0x00, // | 0x2400 | // Load 0x00 into r4
+                        /* m0x0A, m0x0B */ 0xC0, 0x00, // | 0xC000 | // Halt
+                        /* m0x0C, m0x0D */ 0x24, 0xFF, // | 0x24FF | // Load 0xFF into r4
+                        /* m0x0E, m0x0F */ 0xC0, 0x00, // | 0xC000 | // Halt
+                    ],
+                    0x00, // Load program at m0x00
                 )
+                .with_extensions(Extensions { flags: true, ..Extensions::default() })
+                .with_relocations(vec![0x07]), // The address byte of the jump
             ]
         }
     }
@@ -0,0 +1,453 @@
+// Peephole optimizer for Vole programs
+//
+// Each pass looks at the basic blocks of the program one at a time and removes
+//   - no-ops (`0x0XYZ`),
+//   - jumps to the instruction right after them,
+//   - loads of a value the register is already known to hold,
+//   - writes to a register or memory cell that are overwritten before anything reads them,
+// then lays the remaining instructions out again and points the jumps at their new addresses.
+// Passes repeat until nothing changes. Programs that read or write their own code are left
+// alone, moving their instructions would change what they do.
+
+use std::fmt::Write;
+
+use crate::cfg::Cfg;
+use crate::instruction::Instruction;
+use crate::{Address, Cpu, Program, Step};
+
+const CYCLE_BUDGET: u128 = 10_000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Reason {
+    NoOp,
+    JumpToNext,
+    RedundantLoad,
+    DeadStore,
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Reason::NoOp => write!(f, "no-op"),
+            Reason::JumpToNext => write!(f, "jump to the next instruction"),
+            Reason::RedundantLoad => write!(f, "the register already holds the value"),
+            Reason::DeadStore => write!(f, "overwritten before it is read"),
+        }
+    }
+}
+
+// An instruction that was removed, with its address in the program it was removed from
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Removal {
+    pub address: Address,
+    pub instruction: Instruction,
+    pub reason: Reason,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Optimized {
+    pub program: Program,
+    pub removed: Vec<Removal>,
+}
+
+// What a register is known to hold
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Known {
+    Value(u8),
+    Memory(Address),
+}
+
+pub fn optimize(program: &Program) -> Result<Optimized, String> {
+    if !program.code.len().is_multiple_of(2) {
+        return Err(format!("Program {} ends in half an instruction.", program.name));
+    }
+
+    let mut program = program.clone();
+    let mut removed = Vec::new();
+    loop {
+        // Removals are listed pass by pass, each at its address in the program of that pass
+        let mut removals = find_removals(&program)?;
+        if removals.is_empty() {
+            break;
+        }
+        removals.sort_by_key(|removal| removal.address.wrapping_sub(program.start_address));
+        program = relayout(&program, &removals);
+        removed.extend(removals);
+    }
+
+    Ok(Optimized { program, removed })
+}
+
+fn find_removals(program: &Program) -> Result<Vec<Removal>, String> {
+    let cfg = Cfg::build(program);
+    let code_range = program.start_address as usize..program.start_address as usize + program.code.len();
+    let in_code = |address: Address| code_range.contains(&(address as usize));
+    let is_instruction = |address: Address| address.wrapping_sub(program.start_address).is_multiple_of(2);
+
+    for block in &cfg.blocks {
+        for &(address, _, instruction) in &block.instructions {
+            if instruction.memory_read().into_iter().chain(instruction.memory_written()).any(in_code) {
+                return Err(format!(
+                    "Program {} reads or writes its own code at m0x{address:02X} and can't be optimized.",
+                    program.name
+                ));
+            }
+            if let Instruction::Jump { address: target, .. }
+            | Instruction::JumpIf { address: target, .. }
+            | Instruction::Call { address: target } = instruction {
+                if in_code(target) && !is_instruction(target) {
+                    return Err(format!(
+                        "Program {} jumps into the middle of an instruction at m0x{address:02X} and can't be optimized.",
+                        program.name
+                    ));
+                }
+            }
+        }
+    }
+
+    let mut removals = Vec::new();
+    for block in &cfg.blocks {
+        let instructions = &block.instructions;
+        let mut known: [Option<Known>; 16] = [None; 16];
+
+        for (position, &(address, _, instruction)) in instructions.iter().enumerate() {
+            let next = address.wrapping_add(2);
+            let later = &instructions[position + 1..];
+            let reason = match instruction {
+                Instruction::NoOp => Some(Reason::NoOp),
+                Instruction::Jump { address: target, .. }
+                | Instruction::JumpIf { address: target, .. } if target == next => Some(Reason::JumpToNext),
+                Instruction::Load { r, value } if known[r] == Some(Known::Value(value)) => Some(Reason::RedundantLoad),
+                Instruction::LoadFrom { r, address } if known[r] == Some(Known::Memory(address)) => Some(Reason::RedundantLoad),
+                Instruction::Move { r, s } if r == s || (known[s].is_some() && known[s] == known[r]) => {
+                    Some(Reason::RedundantLoad)
+                },
+                _ if is_dead(instruction, later, program.extensions.flags) => Some(Reason::DeadStore),
+                _ => None,
+            };
+            if let Some(reason) = reason {
+                removals.push(Removal { address, instruction, reason });
+                continue;
+            }
+
+            // Update what the registers are known to hold
+            match instruction {
+                Instruction::Load { r, value } => known[r] = Some(Known::Value(value)),
+                Instruction::LoadFrom { r, address } => known[r] = Some(Known::Memory(address)),
+                Instruction::Move { r, s } => known[s] = known[r],
+                Instruction::Store { r, address } => {
+                    for fact in known.iter_mut().filter(|fact| **fact == Some(Known::Memory(address))) {
+                        *fact = None;
+                    }
+                    known[r] = known[r].or(Some(Known::Memory(address)));
+                },
+                // The stack and subroutines write memory the block can't see
+                Instruction::Call { .. } | Instruction::Return => known = [None; 16],
+                Instruction::Push { .. } | Instruction::Pop { .. } => {
+                    for fact in known.iter_mut().filter(|fact| matches!(fact, Some(Known::Memory(_)))) {
+                        *fact = None;
+                    }
+                    if let Some(r) = instruction.register_written() {
+                        known[r] = None;
+                    }
+                },
+                _ => {
+                    if let Some(r) = instruction.register_written() {
+                        known[r] = None;
+                    }
+                },
+            }
+        }
+    }
+
+    Ok(removals)
+}
+
+// Whether the value the instruction writes is overwritten in the rest of its block before it is read
+fn is_dead(instruction: Instruction, later: &[(Address, [u8; 2], Instruction)], flags: bool) -> bool {
+    // With the flags extension, arithmetic also writes the status flags
+    let sets_flags = flags && matches!(instruction,
+        Instruction::AddTc { .. }
+        | Instruction::AddFl { .. }
+        | Instruction::Or { .. }
+        | Instruction::And { .. }
+        | Instruction::Xor { .. }
+        | Instruction::Rotate { .. }
+    );
+    if sets_flags || matches!(instruction, Instruction::Pop { .. }) {
+        return false;
+    }
+
+    if let Some(r) = instruction.register_written() {
+        for &(_, _, other) in later {
+            if other.registers_read().contains(&r) {
+                return false;
+            }
+            if other.register_written() == Some(r) {
+                return true;
+            }
+        }
+        return false;
+    }
+
+    if let Some(address) = instruction.memory_written() {
+        for &(_, _, other) in later {
+            let touches_stack = matches!(other,
+                Instruction::Call { .. } | Instruction::Return | Instruction::Push { .. } | Instruction::Pop { .. }
+            );
+            if touches_stack || other.memory_read() == Some(address) {
+                return false;
+            }
+            if other.memory_written() == Some(address) {
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+// Drop the removed instructions and point every jump at where its target moved to
+fn relayout(program: &Program, removals: &[Removal]) -> Program {
+    let count = program.code.len() / 2;
+    let address_of = |index: usize| program.start_address.wrapping_add((index * 2) as u8);
+    let kept: Vec<bool> = (0..count)
+        .map(|index| !removals.iter().any(|removal| removal.address == address_of(index)))
+        .collect();
+
+    // The new index of every old instruction, a removed one moves to the next kept one
+    let mut new_index = Vec::with_capacity(count + 1);
+    let mut position = 0;
+    for &keep in &kept {
+        new_index.push(position);
+        position += keep as usize;
+    }
+    new_index.push(position);
+
+    let moved = |target: Address| {
+        let offset = target.wrapping_sub(program.start_address) as usize;
+        match offset.is_multiple_of(2) && offset / 2 <= count {
+            true => program.start_address.wrapping_add((new_index[offset / 2] * 2) as u8),
+            false => target,
+        }
+    };
+
+    let mut code = Vec::new();
+    for index in (0..count).filter(|&index| kept[index]) {
+        let instruction = Instruction::decode(program.code[index * 2], program.code[index * 2 + 1], program.extensions);
+        let instruction = match instruction {
+            Instruction::Jump { r, address } => Instruction::Jump { r, address: moved(address) },
+            Instruction::JumpIf { condition, address } => Instruction::JumpIf { condition, address: moved(address) },
+            Instruction::Call { address } => Instruction::Call { address: moved(address) },
+            // Keep the exact bytes of everything else, including ignored bits and invalid OpCodes
+            _ => {
+                code.extend_from_slice(&program.code[index * 2..index * 2 + 2]);
+                continue;
+            },
+        };
+        code.extend_from_slice(&instruction.encode());
+    }
+
+    // The relocated bytes move with their instructions, those of removed instructions are gone
+    let relocations = program.relocations.as_ref().map(|relocations| {
+        relocations.iter()
+            .filter(|&&offset| kept.get(offset / 2) == Some(&true))
+            .map(|&offset| new_index[offset / 2] * 2 + offset % 2)
+            .collect()
+    });
+    Program { code, relocations, ..program.clone() }
+}
+
+// Run a program from an empty machine until it halts
+fn run(program: &Program) -> Result<Cpu, String> {
+    let mut cpu = Cpu::new();
+    cpu.import(program.clone());
+    while cpu.cycles < CYCLE_BUDGET {
+        match cpu.step() {
+            Step::Continue => {},
+            Step::Halt => return Ok(cpu),
+            Step::Fault(fault) => return Err(fault.to_string()),
+        }
+    }
+    Err(format!("it didn't halt within {CYCLE_BUDGET} cycles"))
+}
+
+pub fn listing(program: &Program) -> String {
+    let mut listing = String::new();
+    for (index, bytes) in program.code.chunks_exact(2).enumerate() {
+        let address = program.start_address.wrapping_add((index * 2) as u8);
+        let instruction = Instruction::decode(bytes[0], bytes[1], program.extensions);
+        writeln!(listing, "m0x{address:02X}  0x{:02X}{:02X}  {instruction}", bytes[0], bytes[1]).unwrap();
+    }
+    listing
+}
+
+// The optimized program as a byte image that `load_program` reads back
+fn image(program: &Program) -> String {
+    let mut image = format!("# Program {}, optimized\n@{:02X}\n", program.name, program.start_address);
+    for line in program.code.chunks(8) {
+        let bytes: Vec<String> = line.iter().map(|byte| format!("{byte:02X}")).collect();
+        writeln!(image, "{}", bytes.join(" ")).unwrap();
+    }
+    image
+}
+
+// `vole-machine optimize <program> [output.hex]`
+pub fn optimize_command(args: &[String]) -> bool {
+    let Some(source) = args.first() else {
+        println!("Usage: vole-machine optimize <program> [output.hex]");
+        return false;
+    };
+    let original = match crate::load_program(source) {
+        Ok(program) => program,
+        Err(error) => {
+            println!("{error}");
+            return false;
+        },
+    };
+    let Optimized { program, removed } = match optimize(&original) {
+        Ok(optimized) => optimized,
+        Err(error) => {
+            println!("{error}");
+            return false;
+        },
+    };
+
+    println!("Program {}: removed {} instruction(s)", original.name, removed.len());
+    for removal in &removed {
+        println!("  m0x{:02X}  {:<36}  {}", removal.address, removal.instruction.to_string(), removal.reason);
+    }
+    println!();
+    print!("{}", listing(&program));
+    println!();
+
+    let (before, after) = (original.code.len(), program.code.len());
+    println!("Bytes:  {before} -> {after} ({} saved)", before - after);
+
+    // Both versions have to end in the same registers and the same memory outside the code
+    let verified = match (run(&original), run(&program)) {
+        (Ok(expected), Ok(found)) => {
+            let code_range = original.start_address as usize..original.start_address as usize + before;
+            let same_memory = (0..256)
+                .filter(|address| !code_range.contains(address))
+                .all(|address| expected.memory[address] == found.memory[address]);
+            if expected.register == found.register && same_memory {
+                println!("Cycles: {} -> {} ({} saved), both versions halt in the same state",
+                    expected.cycles, found.cycles, expected.cycles as i128 - found.cycles as i128
+                );
+                true
+            } else {
+                println!("The optimized program halts in a different state than the original!");
+                false
+            }
+        },
+        (Err(error), _) => {
+            println!("Cycles: not measured, the original program can't be run to completion: {error}");
+            true
+        },
+        (Ok(_), Err(error)) => {
+            println!("The optimized program can't be run to completion: {error}");
+            false
+        },
+    };
+
+    match args.get(1) {
+        Some(path) if verified => match std::fs::write(path, image(&program)) {
+            Ok(()) => true,
+            Err(error) => {
+                println!("{path}: {error}");
+                false
+            },
+        },
+        _ => verified,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "
+                .org 0x10
+                load r1, 5
+                nop
+                store r1, [0x81]
+                load r1, 5
+                load r2, 1
+                load r2, 2
+                jump next
+        next:   add r3, r1, r2
+                store r3, [0x80]
+                jump r3, done
+                halt
+        done:   halt
+    ";
+
+    fn assemble(source: &str) -> Program {
+        crate::assembler::assemble("test", source).unwrap().0
+    }
+
+    #[test]
+    fn removes_and_relocates() {
+        let program = assemble(SOURCE);
+        let optimized = optimize(&program).unwrap();
+        let removed: Vec<(Address, Reason)> = optimized.removed.iter().map(|removal| (removal.address, removal.reason)).collect();
+        assert_eq!(removed, vec![
+            (0x12, Reason::NoOp),
+            (0x16, Reason::RedundantLoad),
+            (0x18, Reason::DeadStore),
+            (0x1C, Reason::JumpToNext),
+        ]);
+        assert_eq!(listing(&optimized.program), "\
+            m0x10  0x2105  Load 0x05 into r1\n\
+            m0x12  0x3181  Store from r1 into m0x81\n\
+            m0x14  0x2202  Load 0x02 into r2\n\
+            m0x16  0x5312  r1 + r2 into r3\n\
+            m0x18  0x3380  Store from r3 into m0x80\n\
+            m0x1A  0xB31E  Jump to m0x1E if r3 == r0\n\
+            m0x1C  0xC000  Halt\n\
+            m0x1E  0xC000  Halt\n"
+        );
+        // The relocation of the removed jump is gone, the other one moved with its jump
+        assert_eq!(program.relocations, Some(vec![0x0D, 0x13]));
+        assert_eq!(optimized.program.relocations, Some(vec![0x0B]));
+
+        let relocated = optimized.program.relocate(0x40).unwrap();
+        assert_eq!(relocated.code[0x0B], 0x4E);
+        assert_eq!(run(&relocated).unwrap().memory[0x80], run(&program).unwrap().memory[0x80]);
+    }
+
+    #[test]
+    fn nothing_to_remove() {
+        let program = assemble("load r1, 1\nstore r1, [0x80]\nhalt");
+        let optimized = optimize(&program).unwrap();
+        assert!(optimized.removed.is_empty());
+        assert_eq!(optimized.program.code, program.code);
+    }
+
+    #[test]
+    fn image_reads_back() {
+        let program = optimize(&assemble(SOURCE)).unwrap().program;
+        let path = std::env::temp_dir().join(format!("vole-optimizer-{}.hex", std::process::id()));
+        std::fs::write(&path, image(&program)).unwrap();
+        let loaded = crate::load_program(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!((loaded.code, loaded.start_address), (program.code, program.start_address));
+    }
+
+    #[test]
+    fn refuses_programs_it_would_break() {
+        let half = Program::new(String::from("half"), vec![0xC0, 0x00, 0x21], 0x00);
+        assert_eq!(optimize(&half).err(), Some(String::from("Program half ends in half an instruction.")));
+
+        let library_a = crate::ProgramLibrary::init().get("A").unwrap();
+        assert!(optimize(&library_a).err().unwrap().contains("reads or writes its own code"));
+
+        let middle = assemble("jump 0x03\nhalt");
+        assert_eq!(optimize(&middle).err(), Some(String::from(
+            "Program test jumps into the middle of an instruction at m0x00 and can't be optimized."
+        )));
+    }
+}
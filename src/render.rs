@@ -0,0 +1,347 @@
+// Renderers: the machine's state as coloured terminal text, plain text or a static HTML page
+//
+// The state display is made of lines of text and tables of cells. A cell can carry a marker,
+// `*` for the program counter, `!` for code the program rewrote and `^` for the stack, and a
+// heat level. Each backend decides how they look: the ANSI renderer colours cells by heat like
+// the terminal always did, the plain renderer writes the heat as a digit after the value, and the
+// HTML renderer gives cells classes for a style sheet.
+
+use crate::{Cpu, Foreground, HeatLevel, Step, Terminal};
+
+// How long a snapshot runs a program that doesn't halt
+const DEFAULT_CYCLES: u128 = 10_000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Marker {
+    #[default]
+    None,
+    ProgramCounter,
+    Modified,
+    Stack,
+    // Only the full-screen interface has breakpoints and a cursor
+    Breakpoint,
+    Selected,
+}
+
+impl Marker {
+    fn symbol(self) -> char {
+        match self {
+            Marker::None => ' ',
+            Marker::ProgramCounter => '*',
+            Marker::Modified => '!',
+            Marker::Stack => '^',
+            Marker::Breakpoint => 'o',
+            Marker::Selected => '>',
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Cell {
+    pub text: String,
+    pub marker: Marker,
+    // None for values that don't heat up, like the stack pointer
+    pub heat: Option<HeatLevel>,
+}
+
+impl Cell {
+    pub fn new(text: impl Into<String>, marker: Marker, heat: Option<HeatLevel>) -> Cell {
+        Cell { text: text.into(), marker, heat }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Table {
+    // A table without columns has no header line
+    pub columns: Vec<String>,
+    // Rows of the memory grid are labelled with their first address
+    pub rows: Vec<(Option<String>, Vec<Cell>)>,
+}
+
+impl Table {
+    pub fn new(columns: Vec<String>) -> Table {
+        Table { columns, rows: Vec::new() }
+    }
+}
+
+pub trait Renderer {
+    // A line of text, or an empty line between parts
+    fn line(&mut self, text: &str);
+    fn table(&mut self, table: &Table);
+    // Everything rendered so far, ready to be written out
+    fn finish(&mut self) -> String;
+}
+
+// The widths of the label column and of every column, wide enough for the header and every cell
+fn layout(table: &Table, cell_width: impl Fn(&Cell) -> usize) -> (usize, Vec<usize>) {
+    let label_width = table.rows.iter().filter_map(|(label, _)| label.as_ref()).map(|label| label.len() + 1).max().unwrap_or(0);
+    let mut widths: Vec<usize> = table.columns.iter().map(|column| column.len() + 1).collect();
+    for (_, cells) in &table.rows {
+        for (i, cell) in cells.iter().enumerate() {
+            if i < widths.len() {
+                widths[i] = widths[i].max(cell_width(cell));
+            }
+        }
+    }
+    (label_width, widths)
+}
+
+fn header(table: &Table, label_width: usize, widths: &[usize]) -> String {
+    let mut header = " ".repeat(label_width);
+    for (column, &width) in table.columns.iter().zip(widths) {
+        header += &format!("{column:>width$}");
+    }
+    header
+}
+
+// A run of text in one colour, None for the terminal's own
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Span {
+    pub text: String,
+    pub colour: Option<Foreground>,
+}
+
+impl Span {
+    pub fn new(text: impl Into<String>, colour: Option<Foreground>) -> Span {
+        Span { text: text.into(), colour }
+    }
+}
+
+// Today's look: values coloured by heat, the program counter in magenta
+#[derive(Default)]
+pub struct Ansi {
+    lines: Vec<Vec<Span>>,
+}
+
+impl Ansi {
+    pub fn new() -> Ansi {
+        Ansi::default()
+    }
+
+    // The lines rendered so far, for the screen to draw
+    pub fn take_lines(&mut self) -> Vec<Vec<Span>> {
+        std::mem::take(&mut self.lines)
+    }
+}
+
+impl Renderer for Ansi {
+    fn line(&mut self, text: &str) {
+        self.lines.push(vec![Span::new(text, None)]);
+    }
+
+    fn table(&mut self, table: &Table) {
+        let (label_width, widths) = layout(table, |cell| cell.text.len() + 2);
+        if !table.columns.is_empty() {
+            self.line(&header(table, label_width, &widths));
+        }
+        for (label, cells) in &table.rows {
+            let mut line = vec![Span::new(label.as_ref().map(|label| format!(" {label}")).unwrap_or_default(), None)];
+            for (i, cell) in cells.iter().enumerate() {
+                let padding = widths.get(i).map(|width| width - cell.text.len() - 2).unwrap_or(0);
+                let colour = match (cell.marker, cell.heat) {
+                    (Marker::ProgramCounter, _) => Some(Foreground::Magenta),
+                    (_, Some(heat)) => Some(Foreground::heat_from(heat)),
+                    (_, None) => None,
+                };
+                line.push(Span::new(format!("{} {}", " ".repeat(padding), cell.marker.symbol()), None));
+                line.push(Span::new(cell.text.clone(), colour));
+            }
+            self.lines.push(line);
+        }
+    }
+
+    fn finish(&mut self) -> String {
+        let mut output = String::new();
+        for line in self.take_lines() {
+            for span in line {
+                match span.colour {
+                    Some(colour) => output += &format!("{}{}{}", Terminal::get_fg_color(colour), span.text, Terminal::get_reset_all()),
+                    None => output += &span.text,
+                }
+            }
+            output.push('\n');
+        }
+        output
+    }
+}
+
+// No escape codes, for logs and pipes: the heat follows every value as a digit, like `03:5`
+#[derive(Default)]
+pub struct Plain {
+    output: String,
+}
+
+impl Plain {
+    pub fn new() -> Plain {
+        Plain::default()
+    }
+}
+
+impl Renderer for Plain {
+    fn line(&mut self, text: &str) {
+        self.output += text;
+        self.output.push('\n');
+    }
+
+    fn table(&mut self, table: &Table) {
+        let text = |cell: &Cell| match cell.heat {
+            Some(heat) => format!("{}:{heat}", cell.text),
+            None => cell.text.clone(),
+        };
+        let (label_width, widths) = layout(table, |cell| text(cell).len() + 2);
+        if !table.columns.is_empty() {
+            self.line(&header(table, label_width, &widths));
+        }
+        for (label, cells) in &table.rows {
+            let mut line = label.as_ref().map(|label| format!(" {label}")).unwrap_or_default();
+            for (i, cell) in cells.iter().enumerate() {
+                let text = text(cell);
+                let padding = widths.get(i).map(|width| width - text.len() - 2).unwrap_or(0);
+                line += &format!("{} {}{text}", " ".repeat(padding), cell.marker.symbol());
+            }
+            self.line(line.trim_end());
+        }
+    }
+
+    fn finish(&mut self) -> String {
+        std::mem::take(&mut self.output)
+    }
+}
+
+// A page that shows the state without a server, in the colours of the web UI
+#[derive(Default)]
+pub struct Html {
+    body: String,
+}
+
+impl Html {
+    pub fn new() -> Html {
+        Html::default()
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+impl Renderer for Html {
+    fn line(&mut self, text: &str) {
+        if !text.is_empty() {
+            self.body += &format!("<p>{}</p>\n", escape(text));
+        }
+    }
+
+    fn table(&mut self, table: &Table) {
+        let labelled = table.rows.iter().any(|(label, _)| label.is_some());
+        self.body += "<table>\n";
+        if !table.columns.is_empty() {
+            self.body += "<tr>";
+            if labelled {
+                self.body += "<th></th>";
+            }
+            for column in &table.columns {
+                self.body += &format!("<th>{}</th>", escape(column));
+            }
+            self.body += "</tr>\n";
+        }
+        for (label, cells) in &table.rows {
+            self.body += "<tr>";
+            if labelled {
+                self.body += &format!("<th>{}</th>", escape(label.as_deref().unwrap_or_default()));
+            }
+            for cell in cells {
+                let class = match (cell.marker, cell.heat) {
+                    (Marker::ProgramCounter, _) => String::from("pc"),
+                    (_, Some(heat)) => format!("heat{heat}"),
+                    (_, None) => String::new(),
+                };
+                let marker = cell.marker.symbol().to_string();
+                self.body += &format!("<td class=\"{class}\">{}{}</td>", escape(marker.trim()), escape(&cell.text));
+            }
+            self.body += "</tr>\n";
+        }
+        self.body += "</table>\n";
+    }
+
+    fn finish(&mut self) -> String {
+        format!("{HEAD}{}</body>\n</html>\n", std::mem::take(&mut self.body))
+    }
+}
+
+const HEAD: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Vole Machine</title>
+<style>
+    body { background: #111; color: #eee; font-family: monospace; font-size: 16px; margin: 2em; }
+    p { margin: 0.2em 0; white-space: pre; }
+    table { border-collapse: collapse; margin: 1em 0; }
+    th { color: #888; font-weight: normal; padding: 0.15em 0.45em; }
+    td { padding: 0.15em 0.45em; text-align: left; white-space: pre; }
+    .heat0 { color: #eee; } .heat1 { color: #5c8cff; } .heat2 { color: #3cd; }
+    .heat3 { color: #4c4; } .heat4 { color: #ee4; } .heat5 { color: #f44; }
+    .pc { color: #e4e; outline: 2px solid #e4e; }
+</style>
+</head>
+<body>
+"#;
+
+// `vole-machine snapshot <program> [ansi | plain | html] [cycles]`
+pub fn snapshot_command(args: &[String]) -> bool {
+    let [source, rest @ ..] = args else {
+        println!("Usage: vole-machine snapshot <program> [ansi | plain | html] [cycles]");
+        return false;
+    };
+    let mut renderer: Box<dyn Renderer> = match rest.first().map(String::as_str) {
+        None | Some("ansi") => Box::new(Ansi::new()),
+        Some("plain") => Box::new(Plain::new()),
+        Some("html") => Box::new(Html::new()),
+        Some(format) => {
+            println!("'{format}' is not a format, use ansi, plain or html.");
+            return false;
+        },
+    };
+    let cycles = match rest.get(1).map(|cycles| cycles.parse::<u128>()) {
+        None => DEFAULT_CYCLES,
+        Some(Ok(cycles)) => cycles,
+        Some(Err(_)) => {
+            println!("'{}' is not a number of cycles.", rest[1]);
+            return false;
+        },
+    };
+    let program = match crate::load_program(source) {
+        Ok(program) => program,
+        Err(error) => {
+            println!("{error}");
+            return false;
+        },
+    };
+
+    let mut cpu = Cpu::new();
+    cpu.import(program);
+    if let Ok(Some(spec)) = crate::spec::Spec::find(&cpu.program_name) {
+        spec.prepare(&mut cpu);
+    }
+    let mut stopped = None;
+    while cpu.cycles < cycles {
+        match cpu.step() {
+            Step::Continue => {},
+            Step::Halt => {
+                stopped = Some(format!("Program {} halted.", cpu.program_name));
+                break;
+            },
+            Step::Fault(fault) => {
+                stopped = Some(fault.to_string());
+                break;
+            },
+        }
+    }
+
+    cpu.render(renderer.as_mut());
+    cpu.render_trace_events(renderer.as_mut(), false);
+    renderer.line("");
+    renderer.line(&stopped.unwrap_or_else(|| format!("Program {} is still running after {cycles} cycles.", cpu.program_name)));
+    print!("{}", renderer.finish());
+    true
+}
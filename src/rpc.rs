@@ -0,0 +1,396 @@
+// JSON-RPC control server: drive the machine from another program over a local socket
+//
+// Every connection gets its own machine and sends JSON-RPC 2.0 requests, one per line, and gets
+// one response per line. Notifications (messages without an id) are sent the moment they happen:
+// `progress` while a long run goes on, and `halted`, `fault` or `breakpoint` when the program
+// stops, before the response of the request that ran it.
+//
+//     {"jsonrpc": "2.0", "id": 1, "method": "load", "params": {"program": "A"}}
+//     {"jsonrpc": "2.0", "id": 2, "method": "run", "params": {"cycles": 100}}
+//     {"jsonrpc": "2.0", "method": "halted", "params": {"pc": 72, "cycles": 28}}
+
+use std::collections::BTreeSet;
+use std::io::{BufRead, BufReader, Write};
+
+use crate::events::Breakpoints;
+use crate::json::Json;
+use crate::{Address, Cpu, Step};
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
+// Cycles between two `progress` notifications of a run
+const PROGRESS_INTERVAL: u64 = 100_000;
+
+// Error codes from the JSON-RPC specification, and one for requests the machine refuses
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const MACHINE_ERROR: i64 = -32000;
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+fn invalid_params(message: &str) -> RpcError {
+    RpcError { code: INVALID_PARAMS, message: message.to_string() }
+}
+
+pub struct Session<W: Write> {
+    cpu: Cpu,
+    breakpoints: Breakpoints,
+    writer: W,
+    // The first write to the client that failed, the session ends with it
+    error: Option<std::io::Error>,
+}
+
+impl<W: Write> Session<W> {
+    pub fn new(writer: W) -> Session<W> {
+        Session { cpu: Cpu::new(), breakpoints: Breakpoints::new(), writer, error: None }
+    }
+
+    fn send(&mut self, message: &Json) {
+        if self.error.is_none() {
+            if let Err(error) = writeln!(self.writer, "{message}").and_then(|_| self.writer.flush()) {
+                self.error = Some(error);
+            }
+        }
+    }
+
+    fn notify(&mut self, method: &str, params: Json) {
+        self.send(&Json::object([
+            ("jsonrpc", Json::from("2.0")),
+            ("method", Json::from(method)),
+            ("params", params),
+        ]));
+    }
+
+    // Answer one line, after the notifications the request caused
+    pub fn handle_line(&mut self, line: &str) -> std::io::Result<()> {
+        let (id, result) = match Json::parse(line) {
+            Ok(request) => {
+                let id = request.get("id").cloned();
+                let method = request.get("method").and_then(Json::as_str);
+                let result = match method {
+                    Some(method) => self.call(method, request.get("params").unwrap_or(&Json::Null)),
+                    None => Err(RpcError { code: INVALID_REQUEST, message: String::from("the request has no method") }),
+                };
+                (id, result)
+            },
+            Err(error) => (Some(Json::Null), Err(RpcError { code: PARSE_ERROR, message: error })),
+        };
+
+        // Requests without an id are notifications themselves and get no response
+        if let Some(id) = id {
+            let mut response = Json::object([("jsonrpc", Json::from("2.0")), ("id", id)]);
+            match result {
+                Ok(result) => response.insert("result", result),
+                Err(RpcError { code, message }) => {
+                    response.insert("error", Json::object([("code", Json::from(code)), ("message", Json::from(message))]));
+                },
+            }
+            self.send(&response);
+        }
+        self.error.take().map_or(Ok(()), Err)
+    }
+
+    fn call(&mut self, method: &str, params: &Json) -> Result<Json, RpcError> {
+        let number = |name: &str| params.get(name).and_then(Json::as_u64);
+        let byte = |name: &str| {
+            number(name).filter(|&value| value <= 0xFF).map(|value| value as u8)
+                .ok_or_else(|| invalid_params(&format!("'{name}' should be a number from 0 to 255")))
+        };
+
+        match method {
+            "load" => {
+                let source = params.get("program").and_then(Json::as_str).ok_or_else(|| invalid_params("'program' is missing"))?;
+                let program = crate::load_program(source).map_err(|message| RpcError { code: MACHINE_ERROR, message })?;
+                let spec = crate::spec::Spec::find(&program.name).ok().flatten();
+                self.cpu = Cpu::new();
+                self.breakpoints = Breakpoints::new();
+                self.cpu.import(program);
+                if let Some(spec) = &spec {
+                    spec.prepare(&mut self.cpu);
+                }
+                Ok(self.state())
+            },
+            "state" => Ok(self.state()),
+            "step" => {
+                self.run(1, false);
+                Ok(self.state())
+            },
+            "run" => {
+                let cycles = number("cycles").ok_or_else(|| invalid_params("'cycles' should be a number"))?;
+                let reason = self.run(cycles, true);
+                let mut state = self.state();
+                state.insert("stopped", Json::from(reason));
+                Ok(state)
+            },
+            "readRegisters" => Ok(Json::object([
+                ("registers", Json::from(self.cpu.register.iter().map(|&value| Json::from(value)).collect::<Vec<_>>())),
+                ("pc", Json::from(self.cpu.pc)),
+                ("sp", Json::from(self.cpu.sp)),
+                ("flags", Json::from(self.cpu.status.to_string())),
+            ])),
+            "writeRegister" => {
+                let value = byte("value")?;
+                match params.get("register").and_then(Json::as_str).map(str::trim) {
+                    Some("pc") => self.cpu.pc = value,
+                    Some(register) => match crate::taint::parse_location(register) {
+                        Some(crate::Location::Register(r)) => {
+                            self.cpu.register[r] = value;
+                            self.cpu.initialized_register[r] = true;
+                        },
+                        _ => return Err(invalid_params("'register' should be r0 to rF or pc")),
+                    },
+                    None => return Err(invalid_params("'register' should be r0 to rF or pc")),
+                }
+                Ok(Json::Null)
+            },
+            "readMemory" => {
+                let address = byte("address")? as usize;
+                let length = number("length").unwrap_or(1) as usize;
+                let end = address.checked_add(length).filter(|&end| end <= 256)
+                    .ok_or_else(|| invalid_params("the range runs past the end of memory"))?;
+                let bytes = self.cpu.memory[address..end].iter().map(|&value| Json::from(value)).collect();
+                Ok(Json::object([("bytes", Json::Array(bytes))]))
+            },
+            "writeMemory" => {
+                let address = byte("address")? as usize;
+                let bytes: Option<Vec<u8>> = params.get("bytes").and_then(Json::as_array).map(|bytes| {
+                    bytes.iter().map(|value| value.as_u64().filter(|&value| value <= 0xFF).map(|value| value as u8)).collect()
+                }).unwrap_or_default();
+                let bytes = bytes.ok_or_else(|| invalid_params("'bytes' should be an array of numbers from 0 to 255"))?;
+                let end = address.checked_add(bytes.len()).filter(|&end| end <= 256)
+                    .ok_or_else(|| invalid_params("the bytes run past the end of memory"))?;
+                self.cpu.memory[address..end].copy_from_slice(&bytes);
+                self.cpu.initialized_memory[address..end].fill(true);
+                Ok(Json::Null)
+            },
+            "heatMap" => Ok(Json::object([
+                ("registers", Json::from(self.cpu.heat.register.iter().map(|&heat| Json::from(heat)).collect::<Vec<_>>())),
+                ("memory", Json::from(self.cpu.heat.memory.iter().map(|&heat| Json::from(heat)).collect::<Vec<_>>())),
+                ("max", Json::from(crate::MAX_HEAT)),
+            ])),
+            "setBreakpoints" => {
+                let addresses: Option<BTreeSet<Address>> = params.get("addresses").and_then(Json::as_array).map(|addresses| {
+                    addresses.iter().map(|value| value.as_u64().filter(|&value| value <= 0xFF).map(|value| value as u8)).collect()
+                }).unwrap_or_default();
+                self.breakpoints.addresses = addresses.ok_or_else(|| invalid_params("'addresses' should be an array of addresses"))?;
+                Ok(Json::Null)
+            },
+            _ => Err(RpcError { code: METHOD_NOT_FOUND, message: format!("there's no method '{method}'") }),
+        }
+    }
+
+    fn state(&self) -> Json {
+        Json::object([
+            ("program", Json::from(self.cpu.program_name.clone())),
+            ("pc", Json::from(self.cpu.pc)),
+            ("cycles", Json::from(self.cpu.cycles)),
+        ])
+    }
+
+    // Run up to the number of cycles, and say why it stopped
+    fn run(&mut self, cycles: u64, stop_at_breakpoints: bool) -> &'static str {
+        for cycle in 1..=cycles {
+            match self.cpu.step_with(&mut self.breakpoints) {
+                Step::Continue => {},
+                Step::Halt => {
+                    self.notify("halted", self.state());
+                    return "halt";
+                },
+                Step::Fault(fault) => {
+                    let mut params = self.state();
+                    params.insert("message", Json::from(fault.to_string()));
+                    self.notify("fault", params);
+                    return "fault";
+                },
+            }
+            if stop_at_breakpoints && self.breakpoints.hit().is_some() {
+                self.notify("breakpoint", self.state());
+                return "breakpoint";
+            }
+            if cycle.is_multiple_of(PROGRESS_INTERVAL) {
+                self.notify("progress", self.state());
+                // Nobody is listening for the rest of the run
+                if self.error.is_some() {
+                    break;
+                }
+            }
+        }
+        "cycles"
+    }
+}
+
+fn serve(reader: impl BufRead, writer: impl Write) -> std::io::Result<()> {
+    let mut session = Session::new(writer);
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        session.handle_line(&line)?;
+    }
+    Ok(())
+}
+
+// `vole-machine rpc [<host:port> | unix:<path>]`
+pub fn rpc_command(args: &[String]) -> bool {
+    let address = args.first().map(String::as_str).unwrap_or(DEFAULT_ADDRESS);
+
+    if let Some(path) = address.strip_prefix("unix:") {
+        #[cfg(unix)]
+        {
+            let listener = match std::os::unix::net::UnixListener::bind(path) {
+                Ok(listener) => listener,
+                Err(error) => {
+                    println!("Can't listen on {path}: {error}");
+                    return false;
+                },
+            };
+            println!("Listening for JSON-RPC on {path}");
+            for stream in listener.incoming().flatten() {
+                std::thread::spawn(move || {
+                    if let Ok(reader) = stream.try_clone() {
+                        let _ = serve(BufReader::new(reader), stream);
+                    }
+                });
+            }
+            return true;
+        }
+        #[cfg(not(unix))]
+        {
+            println!("Unix sockets aren't available here, listen on a TCP port instead.");
+            return false;
+        }
+    }
+
+    let listener = match std::net::TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(error) => {
+            println!("Can't listen on {address}: {error}");
+            return false;
+        },
+    };
+    println!("Listening for JSON-RPC on {address}");
+    for stream in listener.incoming().flatten() {
+        std::thread::spawn(move || {
+            if let Ok(reader) = stream.try_clone() {
+                let _ = serve(BufReader::new(reader), stream);
+            }
+        });
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The messages a session sends for the request lines
+    fn exchange(requests: &[&str]) -> Vec<Json> {
+        let mut output = Vec::new();
+        serve(requests.join("\n").as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap().lines().map(|line| Json::parse(line).unwrap()).collect()
+    }
+
+    fn result(message: &Json) -> &Json {
+        message.get("result").unwrap()
+    }
+
+    fn error_code(message: &Json) -> Option<Json> {
+        message.get("error")?.get("code").cloned()
+    }
+
+    #[test]
+    fn notifications_come_before_the_response() {
+        let messages = exchange(&[
+            r#"{"jsonrpc": "2.0", "id": 1, "method": "load", "params": {"program": "A"}}"#,
+            r#"{"jsonrpc": "2.0", "id": 2, "method": "run", "params": {"cycles": 100}}"#,
+        ]);
+        assert_eq!(messages[0].to_string(), r#"{"jsonrpc":"2.0","id":1,"result":{"program":"A","pc":48,"cycles":0}}"#);
+        assert_eq!(messages[1].to_string(), r#"{"jsonrpc":"2.0","method":"halted","params":{"program":"A","pc":72,"cycles":28}}"#);
+        assert_eq!(messages[2].get("id"), Some(&Json::from(2u64)));
+        assert_eq!(result(&messages[2]).get("stopped"), Some(&Json::from("halt")));
+        assert_eq!(messages.len(), 3);
+    }
+
+    #[test]
+    fn breakpoints_and_progress() {
+        let messages = exchange(&[
+            r#"{"jsonrpc": "2.0", "id": 1, "method": "load", "params": {"program": "A"}}"#,
+            r#"{"jsonrpc": "2.0", "id": 2, "method": "setBreakpoints", "params": {"addresses": [56]}}"#,
+            r#"{"jsonrpc": "2.0", "id": 3, "method": "run", "params": {"cycles": 100}}"#,
+            r#"{"jsonrpc": "2.0", "id": 4, "method": "step"}"#,
+        ]);
+        assert_eq!(messages[2].get("method"), Some(&Json::from("breakpoint")));
+        assert_eq!(result(&messages[3]).get("pc"), Some(&Json::from(56u8)));
+        assert_eq!(result(&messages[3]).get("stopped"), Some(&Json::from("breakpoint")));
+        assert_eq!(result(&messages[4]).get("pc"), Some(&Json::from(58u8)));
+
+        // Empty memory is no-ops all the way round
+        let messages = exchange(&[r#"{"jsonrpc": "2.0", "id": 1, "method": "run", "params": {"cycles": 200000}}"#]);
+        let methods: Vec<Option<&str>> = messages.iter().map(|message| message.get("method").and_then(Json::as_str)).collect();
+        assert_eq!(methods, [Some("progress"), Some("progress"), None]);
+        assert_eq!(result(&messages[2]).get("stopped"), Some(&Json::from("cycles")));
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let messages = exchange(&[
+            r#"{"jsonrpc": "2.0", "id": 1, "method": "writeRegister", "params": {"register": "rA", "value": 255}}"#,
+            r#"{"jsonrpc": "2.0", "id": 2, "method": "writeRegister", "params": {"register": "pc", "value": 16}}"#,
+            r#"{"jsonrpc": "2.0", "id": 3, "method": "writeMemory", "params": {"address": 254, "bytes": [1, 2]}}"#,
+            r#"{"jsonrpc": "2.0", "id": 4, "method": "readMemory", "params": {"address": 253, "length": 3}}"#,
+            r#"{"jsonrpc": "2.0", "id": 5, "method": "readRegisters"}"#,
+        ]);
+        assert_eq!(result(&messages[3]).to_string(), r#"{"bytes":[0,1,2]}"#);
+        let registers = result(&messages[4]);
+        assert_eq!(registers.get("registers").and_then(Json::as_array).map(|registers| &registers[10]), Some(&Json::from(255u8)));
+        assert_eq!(registers.get("pc"), Some(&Json::from(16u8)));
+    }
+
+    #[test]
+    fn malformed_requests() {
+        let messages = exchange(&[
+            r#"{"jsonrpc": "2.0", "id": 1, "method": "load""#,
+            r#"{"jsonrpc": "2.0", "id": 2}"#,
+            r#"{"jsonrpc": "2.0", "id": 3, "method": "explode"}"#,
+            r#"{"jsonrpc": "2.0", "id": 4, "method": "load", "params": {}}"#,
+            r#"{"jsonrpc": "2.0", "id": 5, "method": "load", "params": {"program": "nothing"}}"#,
+            r#"{"jsonrpc": "2.0", "id": 6, "method": "run", "params": {"cycles": -1}}"#,
+            r#"{"jsonrpc": "2.0", "id": 7, "method": "readMemory", "params": {"address": 255, "length": 2}}"#,
+            r#"{"jsonrpc": "2.0", "id": 8, "method": "readMemory", "params": {"address": 0, "length": 18446744073709551615}}"#,
+            r#"{"jsonrpc": "2.0", "id": 9, "method": "writeMemory", "params": {"address": 255, "bytes": [1, 2]}}"#,
+            r#"{"jsonrpc": "2.0", "id": 10, "method": "writeMemory", "params": {"address": 0, "bytes": [256]}}"#,
+            r#"{"jsonrpc": "2.0", "id": 11, "method": "writeRegister", "params": {"register": "m0x10", "value": 1}}"#,
+            r#"{"jsonrpc": "2.0", "id": 12, "method": "setBreakpoints", "params": {"addresses": "all"}}"#,
+            r#"{"jsonrpc": "2.0", "method": "explode"}"#,
+        ]);
+        assert_eq!(messages[0].get("id"), Some(&Json::Null));
+        let codes: Vec<Json> = messages.iter().filter_map(error_code).collect();
+        let expected = [PARSE_ERROR, INVALID_REQUEST, METHOD_NOT_FOUND, INVALID_PARAMS, MACHINE_ERROR]
+            .into_iter()
+            .chain([INVALID_PARAMS; 7])
+            .map(Json::from);
+        assert_eq!(messages.len(), 12);
+        assert!(codes.into_iter().eq(expected));
+    }
+
+    #[test]
+    fn a_failed_write_ends_the_session() {
+        struct Closed;
+        impl Write for Closed {
+            fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut session = Session::new(Closed);
+        let result = session.handle_line(r#"{"jsonrpc": "2.0", "id": 1, "method": "state"}"#);
+        assert_eq!(result.map_err(|error| error.kind()), Err(std::io::ErrorKind::BrokenPipe));
+    }
+}
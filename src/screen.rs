@@ -0,0 +1,261 @@
+// Flicker-free drawing of the running program on the terminal's alternate screen
+//
+// While a program runs in the automatic or manual mode, its frames are drawn on the alternate
+// screen buffer, so the menus before the run stay where they were and the final state is printed
+// below them afterwards. Every frame only writes the characters that changed since the previous
+// one. The terminal is restored when the run ends, and on Ctrl-C before the process exits.
+//
+// Keys can be read as they are typed, for pausing and changing the speed while running. Ctrl-C is
+// then a key like the others, and doesn't stop the process.
+
+use std::io::{IsTerminal, Read, Write};
+use std::process::{Command, Stdio};
+
+use crate::render::Span;
+use crate::{Foreground, Terminal};
+
+const ENTER: &str = "\x1B[?1049h\x1B[2J\x1B[H";
+const LEAVE: &str = "\x1B[0m\x1B[?25h\x1B[?1049l";
+
+// The size to draw in when the terminal doesn't say
+const DEFAULT_SIZE: (usize, usize) = (30, 80);
+
+// A character on the screen and its colour
+type Glyph = (char, Option<Foreground>);
+
+pub struct Screen {
+    // What the terminal shows, row by row
+    previous: Vec<Vec<Glyph>>,
+    size: (usize, usize),
+}
+
+impl Screen {
+    pub fn enter() -> Screen {
+        sys::restore_on_interrupt(true);
+        print!("{ENTER}");
+        std::io::stdout().flush().unwrap();
+        Screen { previous: Vec::new(), size: size() }
+    }
+
+    // Draw a frame, leaving the cursor at the end of its last line for a prompt
+    pub fn draw(&mut self, lines: Vec<Vec<Span>>) {
+        let mut output = String::new();
+        let (rows, columns) = size();
+        if (rows, columns) != self.size {
+            // The terminal may have rewrapped what it showed, so start over
+            output += "\x1B[2J";
+            self.previous.clear();
+            self.size = (rows, columns);
+        }
+
+        // A line is kept free below the frame, for the newline of a prompt's Enter
+        let frame: Vec<Vec<Glyph>> = lines.iter()
+            .take(rows.saturating_sub(1).max(1))
+            .map(|spans| {
+                spans.iter().flat_map(|span| span.text.chars().map(|c| (c, span.colour))).take(columns).collect()
+            })
+            .collect();
+
+        let blank: Glyph = (' ', None);
+        let mut colour = None;
+        let mut cursor = None;
+        for row in 0..frame.len().max(self.previous.len()) {
+            let new = frame.get(row).map(Vec::as_slice).unwrap_or_default();
+            let old = self.previous.get(row).map(Vec::as_slice).unwrap_or_default();
+            for column in 0..new.len().max(old.len()) {
+                let glyph = new.get(column).copied().unwrap_or(blank);
+                if old.get(column).copied().unwrap_or(blank) == glyph {
+                    continue;
+                }
+                if cursor != Some((row, column)) {
+                    output += &format!("\x1B[{};{}H", row + 1, column + 1);
+                }
+                if glyph.1 != colour {
+                    match glyph.1 {
+                        Some(foreground) => output += &Terminal::get_fg_color(foreground),
+                        None => output += "\x1B[39m",
+                    }
+                    colour = glyph.1;
+                }
+                output.push(glyph.0);
+                cursor = Some((row, column + 1));
+            }
+        }
+        if colour.is_some() {
+            output += "\x1B[39m";
+        }
+
+        if cursor.is_some() {
+            let last_row = frame.len().saturating_sub(1);
+            let last_column = frame.last().map(Vec::len).unwrap_or_default();
+            output += &format!("\x1B[{};{}H", last_row + 1, last_column + 1);
+        }
+        self.previous = frame;
+        print!("{output}");
+        std::io::stdout().flush().unwrap();
+    }
+
+    // Forget the last line and the one below it, where a prompt's answer was typed and echoed
+    pub fn forget_prompt(&mut self) {
+        let last_row = self.previous.len().saturating_sub(1);
+        print!("\x1B[{0};1H\x1B[2K\x1B[{1};1H\x1B[2K", last_row + 1, last_row + 2);
+        if let Some(line) = self.previous.last_mut() {
+            line.clear();
+        }
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        print!("{LEAVE}");
+        std::io::stdout().flush().unwrap();
+        sys::restore_on_interrupt(false);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Key {
+    Char(char),
+    Up,
+    Down,
+    Left,
+    Right,
+    Enter,
+    Backspace,
+    Escape,
+    Interrupt,
+}
+
+// Keys are read as they are typed, without echo, and Ctrl-C is a key instead of a signal. `stty`
+// puts the terminal in non-canonical mode, and back the way it was when this is dropped.
+pub struct RawMode {
+    saved: String,
+}
+
+fn stty(args: &[&str]) -> Result<String, String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()
+        .map_err(|error| format!("Can't run stty: {error}"))?;
+    match output.status.success() {
+        true => Ok(String::from_utf8_lossy(&output.stdout).trim().to_string()),
+        false => Err(format!("stty failed: {}", String::from_utf8_lossy(&output.stderr).trim())),
+    }
+}
+
+impl RawMode {
+    pub fn enter() -> Result<RawMode, String> {
+        if !std::io::stdin().is_terminal() || !std::io::stdout().is_terminal() {
+            return Err(String::from("Reading keys needs a terminal."));
+        }
+        let saved = stty(&["-g"])?;
+        // Reads return at once, with whatever was typed
+        stty(&["-icanon", "-echo", "-isig", "min", "0", "time", "0"])?;
+        Ok(RawMode { saved })
+    }
+
+    // The keys typed since the last call, without waiting
+    pub fn read_keys(&self) -> Vec<Key> {
+        let mut buffer = [0; 64];
+        let length = std::io::stdin().lock().read(&mut buffer).unwrap_or_default();
+        let mut keys = Vec::new();
+        let mut bytes = buffer[..length].iter().copied().peekable();
+        while let Some(byte) = bytes.next() {
+            keys.push(match byte {
+                0x1B if bytes.peek() == Some(&b'[') => {
+                    bytes.next();
+                    match bytes.next() {
+                        Some(b'A') => Key::Up,
+                        Some(b'B') => Key::Down,
+                        Some(b'C') => Key::Right,
+                        Some(b'D') => Key::Left,
+                        _ => continue,
+                    }
+                },
+                0x1B => Key::Escape,
+                0x03 => Key::Interrupt,
+                b'\r' | b'\n' => Key::Enter,
+                0x7F | 0x08 => Key::Backspace,
+                byte => Key::Char(byte as char),
+            });
+        }
+        keys
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+// The rows and columns of the terminal
+pub fn size() -> (usize, usize) {
+    sys::size().unwrap_or(DEFAULT_SIZE)
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::ffi::{c_int, c_ulong, c_ushort, c_void};
+
+    const SIGINT: c_int = 2;
+    const SIG_DFL: usize = 0;
+    const STDOUT: c_int = 1;
+    #[cfg(target_os = "linux")]
+    const TIOCGWINSZ: c_ulong = 0x5413;
+    #[cfg(not(target_os = "linux"))]
+    const TIOCGWINSZ: c_ulong = 0x40087468;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct WindowSize {
+        rows: c_ushort,
+        columns: c_ushort,
+        x_pixels: c_ushort,
+        y_pixels: c_ushort,
+    }
+
+    extern "C" {
+        fn signal(signal: c_int, handler: usize) -> usize;
+        fn write(fd: c_int, buffer: *const c_void, count: usize) -> isize;
+        fn _exit(status: c_int) -> !;
+        fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    }
+
+    // Only calls that are safe in a signal handler: leave the alternate screen and exit like Ctrl-C would
+    extern "C" fn interrupted(_signal: c_int) {
+        // SAFETY: write and _exit are async-signal-safe, and LEAVE is a static string
+        unsafe {
+            write(STDOUT, super::LEAVE.as_ptr().cast(), super::LEAVE.len());
+            _exit(128 + SIGINT);
+        }
+    }
+
+    pub fn restore_on_interrupt(enabled: bool) {
+        let handler = match enabled {
+            true => interrupted as extern "C" fn(c_int) as usize,
+            false => SIG_DFL,
+        };
+        // SAFETY: the handler is either the default or a function that only makes signal-safe calls
+        unsafe {
+            signal(SIGINT, handler);
+        }
+    }
+
+    pub fn size() -> Option<(usize, usize)> {
+        let mut size = WindowSize::default();
+        // SAFETY: TIOCGWINSZ fills in a winsize struct, which WindowSize lays out
+        let result = unsafe { ioctl(STDOUT, TIOCGWINSZ, &mut size as *mut WindowSize) };
+        (result == 0 && size.rows > 0 && size.columns > 0).then_some((size.rows as usize, size.columns as usize))
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    pub fn restore_on_interrupt(_enabled: bool) {}
+
+    pub fn size() -> Option<(usize, usize)> {
+        None
+    }
+}
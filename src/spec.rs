@@ -0,0 +1,514 @@
+// Assignment specs: the initial state a program starts from and the state it is expected to halt in
+//
+// A spec is a plain text file of `key = value` lines, with `#` starting a comment:
+//
+//     program = A          # Name of a program in the ProgramLibrary
+//     cycles = 64          # Cycle budget, the program must halt within it
+//     forbid = 6 9         # OpCodes the program is not allowed to execute
+//     sanitize = warn      # Report reads of registers and memory never set (off, warn or fault)
+//
+//     [initial]            # Values written into the CPU after the program is loaded
+//     m0x00 = 11 22 33     # Several values fill consecutive addresses
+//
+//     [expected]           # Values the CPU must hold once the program halts
+//     pc = 48
+//     r2 = 03
+//     m0x10 = 11 22 33
+//
+// Instead of naming a library program, a spec can carry the program itself with
+// `name`, `code` and `start` keys, plus `extensions = stack flags` for the extensions it uses.
+// All numbers are hexadecimal, the `0x` prefix is optional, except the cycle budget: it is
+// decimal like the cycle counts in the report, unless it has the `0x` prefix.
+
+use crate::events::{Event, Observer};
+use crate::{Address, Cpu, Extensions, Fault, Location, Program, ProgramLibrary, Sanitizer, Step};
+
+// Where the interactive emulator looks for the spec of the program it is about to run
+const SPEC_DIRECTORY: &str = "specs";
+// Used when a spec doesn't set a cycle budget so a looping program still gets checked
+const DEFAULT_CYCLE_BUDGET: u128 = 10_000;
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct State {
+    pub pc: Option<Address>,
+    pub registers: Vec<(usize, u8)>,
+    pub memory: Vec<(Address, u8)>,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Spec {
+    pub program: Program,
+    pub cycle_budget: Option<u128>,
+    pub forbidden: Vec<u8>,
+    pub sanitizer: Sanitizer,
+    pub initial: State,
+    pub expected: State,
+}
+
+impl Spec {
+    pub fn load(path: &str) -> Result<Spec, String> {
+        let text = std::fs::read_to_string(path).map_err(|error| format!("{path}: {error}"))?;
+        let fallback_name = std::path::Path::new(path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        Spec::parse(&text, &fallback_name).map_err(|error| format!("{path}: {error}"))
+    }
+
+    // Look for `specs/<program name>.spec`, a missing file just means the program has no spec
+    pub fn find(program_name: &str) -> Result<Option<Spec>, String> {
+        let path = format!("{SPEC_DIRECTORY}/{program_name}.spec");
+        if !std::path::Path::new(&path).exists() {
+            return Ok(None);
+        }
+
+        Spec::load(&path).map(Some)
+    }
+
+    pub fn parse(text: &str, fallback_name: &str) -> Result<Spec, String> {
+        let mut library_name: Option<String> = None;
+        let mut name: Option<String> = None;
+        let mut code: Option<Vec<u8>> = None;
+        let mut start_address: Address = 0;
+        let mut extensions = Extensions::default();
+        let mut cycle_budget = None;
+        let mut forbidden = Vec::new();
+        let mut sanitizer = Sanitizer::Off;
+        let mut initial = State::default();
+        let mut expected = State::default();
+        let mut section = Section::Header;
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                section = match &line[1..line.len() - 1] {
+                    "initial" => Section::Initial,
+                    "expected" => Section::Expected,
+                    other => return Err(format!("line {line_number}: unknown section [{other}]")),
+                };
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .ok_or_else(|| format!("line {line_number}: expected `key = value`"))?;
+            let error = |message: String| format!("line {line_number}: {message}");
+
+            match section {
+                Section::Header => match key {
+                    "program" => library_name = Some(value.to_string()),
+                    "name" => name = Some(value.to_string()),
+                    "code" => code = Some(parse_bytes(value).map_err(error)?),
+                    "start" => start_address = parse_byte(value).map_err(error)?,
+                    "extensions" => {
+                        for extension in value.split_whitespace() {
+                            match extension {
+                                "stack" => extensions.stack = true,
+                                "flags" => extensions.flags = true,
+                                _ => return Err(error(format!("unknown extension '{extension}'"))),
+                            }
+                        }
+                    },
+                    "cycles" => {
+                        let budget = match value.strip_prefix("0x") {
+                            Some(hex) => u128::from_str_radix(hex, 16),
+                            None => value.parse(),
+                        };
+                        let budget = budget.map_err(|_| error(format!("invalid cycle budget '{value}'")))?;
+                        cycle_budget = Some(budget);
+                    },
+                    "forbid" => {
+                        for opcode in value.split_whitespace() {
+                            match parse_byte(opcode) {
+                                Ok(opcode) if opcode <= 0xF => forbidden.push(opcode),
+                                _ => return Err(error(format!("invalid OpCode '{opcode}'"))),
+                            }
+                        }
+                    },
+                    "sanitize" => sanitizer = match value {
+                        "off" => Sanitizer::Off,
+                        "warn" => Sanitizer::Warn,
+                        "fault" => Sanitizer::Fault,
+                        _ => return Err(error(format!("sanitize must be off, warn or fault, not '{value}'"))),
+                    },
+                    _ => return Err(error(format!("unknown key '{key}'"))),
+                },
+                Section::Initial => initial.set(key, value).map_err(error)?,
+                Section::Expected => expected.set(key, value).map_err(error)?,
+            }
+        }
+
+        let program = match (library_name, code) {
+            (Some(_), Some(_)) => return Err(String::from("a spec can't have both `program` and `code`")),
+            (None, Some(code)) => {
+                if start_address as usize + code.len() > 256 {
+                    return Err(String::from("the program doesn't fit in memory"));
+                }
+                let name = name.unwrap_or_else(|| fallback_name.to_string());
+                Program::new(name, code, start_address).with_extensions(extensions)
+            },
+            (library_name, None) => {
+                if extensions != Extensions::default() {
+                    return Err(String::from("`extensions` can only be used together with `code`"));
+                }
+                let library_name = library_name.unwrap_or_else(|| fallback_name.to_string());
+                ProgramLibrary::init()
+                    .get(&library_name)
+                    .ok_or_else(|| format!("no program named '{library_name}' in the library"))?
+            },
+        };
+
+        Ok(Spec {
+            program,
+            cycle_budget,
+            forbidden,
+            sanitizer,
+            initial,
+            expected,
+        })
+    }
+
+    // Write the initial state of the spec into a CPU that already has the program imported
+    pub fn prepare(&self, cpu: &mut Cpu) {
+        if let Some(pc) = self.initial.pc {
+            cpu.pc = pc;
+        }
+        for &(index, value) in &self.initial.registers {
+            cpu.register[index] = value;
+            cpu.set_initialized(Location::Register(index));
+        }
+        for &(address, value) in &self.initial.memory {
+            cpu.memory[address as usize] = value;
+            cpu.set_initialized(Location::Memory(address));
+        }
+    }
+
+    // What to look out for while the program runs
+    pub fn watch(&self) -> Watch {
+        Watch { forbidden: self.forbidden.clone(), ..Watch::default() }
+    }
+
+    // Run the program from its initial state and compare where it ends up with the expected state
+    pub fn check(&self) -> Report {
+        let mut cpu = Cpu::new();
+        cpu.sanitizer = self.sanitizer;
+        cpu.import(self.program.clone());
+        self.prepare(&mut cpu);
+        cpu.spec_watch = Some(self.watch());
+
+        let budget = self.budget();
+        let mut end = Step::Continue;
+        while cpu.cycles < budget {
+            end = cpu.step();
+            if end != Step::Continue {
+                break;
+            }
+        }
+        self.report(&mut cpu, &end)
+    }
+
+    // Compare a run that ended with `end` with the spec, from what its watch saw and where it ended up
+    pub fn report(&self, cpu: &mut Cpu, end: &Step) -> Report {
+        let watch = cpu.spec_watch.take().unwrap_or_default();
+        let mut mismatches = watch.forbidden_executed;
+        // A program may halt on the last cycle of its budget, but not run on after it
+        let budget = self.budget();
+        let over_budget = cpu.cycles > budget || (*end == Step::Continue && cpu.cycles == budget);
+        match *end {
+            _ if over_budget => mismatches.push(Mismatch::CycleBudget { budget }),
+            Step::Continue => mismatches.push(Mismatch::Stopped { address: cpu.pc }),
+            Step::Halt => {},
+            Step::Fault(fault) => mismatches.push(Mismatch::Fault(fault)),
+        }
+        mismatches.extend(watch.uninitialized_reads);
+
+        if let Some(expected) = self.expected.pc {
+            if cpu.pc != expected {
+                mismatches.push(Mismatch::ProgramCounter { expected, found: cpu.pc });
+            }
+        }
+        for &(index, expected) in &self.expected.registers {
+            if cpu.register[index] != expected {
+                mismatches.push(Mismatch::Register { index, expected, found: cpu.register[index] });
+            }
+        }
+        for &(address, expected) in &self.expected.memory {
+            let found = cpu.memory[address as usize];
+            if found != expected {
+                mismatches.push(Mismatch::Memory { address, expected, found });
+            }
+        }
+
+        Report {
+            program_name: self.program.name.clone(),
+            cycles: cpu.cycles,
+            mismatches,
+        }
+    }
+
+    fn budget(&self) -> u128 {
+        self.cycle_budget.unwrap_or(DEFAULT_CYCLE_BUDGET)
+    }
+}
+
+// The forbidden OpCodes executed and every uninitialized read of a run, where the trace only keeps
+// the last ones
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Watch {
+    forbidden: Vec<u8>,
+    forbidden_executed: Vec<Mismatch>,
+    uninitialized_reads: Vec<Mismatch>,
+}
+
+impl Observer for Watch {
+    fn notify(&mut self, cycle: u128, event: &Event) {
+        match *event {
+            Event::InstructionFetched { address, instruction } => {
+                let opcode = instruction.opcode();
+                let seen = self.forbidden_executed.iter()
+                    .any(|mismatch| matches!(*mismatch, Mismatch::ForbiddenOpCode { opcode: seen, .. } if seen == opcode));
+                if self.forbidden.contains(&opcode) && !seen {
+                    self.forbidden_executed.push(Mismatch::ForbiddenOpCode { opcode, address, cycle });
+                }
+            },
+            Event::UninitializedRead { pc, location } => {
+                self.uninitialized_reads.push(Mismatch::UninitializedRead { location, address: pc, cycle });
+            },
+            _ => {},
+        }
+    }
+}
+
+enum Section {
+    Header,
+    Initial,
+    Expected,
+}
+
+impl State {
+    // Parse one `pc = XY`, `rR = XY` or `m0xXY = XY ...` line into the state
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let values = parse_bytes(value)?;
+        if key == "pc" {
+            match values[..] {
+                [pc] => self.pc = Some(pc),
+                _ => return Err(String::from("pc takes a single address")),
+            }
+        } else if let Some(index) = key.strip_prefix('r') {
+            let index = parse_byte(index)? as usize;
+            if index + values.len() > 16 {
+                return Err(format!("register '{key}' is out of range"));
+            }
+            for (offset, &byte) in values.iter().enumerate() {
+                self.registers.push((index + offset, byte));
+            }
+        } else if let Some(address) = key.strip_prefix('m') {
+            let address = parse_byte(address)? as usize;
+            if address + values.len() > 256 {
+                return Err(format!("memory '{key}' runs past m0xFF"));
+            }
+            for (offset, &byte) in values.iter().enumerate() {
+                self.memory.push(((address + offset) as Address, byte));
+            }
+        } else {
+            return Err(format!("unknown location '{key}', expected pc, rR or m0xXY"));
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_byte(text: &str) -> Result<u8, String> {
+    let digits = text.strip_prefix("0x").unwrap_or(text);
+    u8::from_str_radix(digits, 16).map_err(|_| format!("invalid hexadecimal byte '{text}'"))
+}
+
+fn parse_bytes(text: &str) -> Result<Vec<u8>, String> {
+    let bytes = text.split_whitespace().map(parse_byte).collect::<Result<Vec<u8>, String>>()?;
+    if bytes.is_empty() {
+        return Err(String::from("missing value"));
+    }
+
+    Ok(bytes)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mismatch {
+    ProgramCounter { expected: Address, found: Address },
+    Register { index: usize, expected: u8, found: u8 },
+    Memory { address: Address, expected: u8, found: u8 },
+    CycleBudget { budget: u128 },
+    ForbiddenOpCode { opcode: u8, address: Address, cycle: u128 },
+    UninitializedRead { location: Location, address: Address, cycle: u128 },
+    Fault(Fault),
+    // The run was stopped from the keyboard before the program halted
+    Stopped { address: Address },
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Mismatch::ProgramCounter { expected, found } => write!(f,
+                "The program counter should halt at m0x{expected:02X} but halted at m0x{found:02X}"
+            ),
+            Mismatch::Register { index, expected, found } => write!(f,
+                "r{index:X} should hold 0x{expected:02X} but holds 0x{found:02X}"
+            ),
+            Mismatch::Memory { address, expected, found } => write!(f,
+                "m0x{address:02X} should hold 0x{expected:02X} but holds 0x{found:02X}"
+            ),
+            Mismatch::CycleBudget { budget } => write!(f,
+                "The program didn't halt within its budget of {budget} cycles"
+            ),
+            Mismatch::ForbiddenOpCode { opcode, address, cycle } => write!(f,
+                "Forbidden OpCode 0x{opcode:X} executed at m0x{address:02X} on cycle {cycle}"
+            ),
+            Mismatch::UninitializedRead { location, address, cycle } => write!(f,
+                "{location} was read before it was initialized by m0x{address:02X} on cycle {cycle}"
+            ),
+            Mismatch::Fault(fault) => write!(f, "The program faulted: {fault}"),
+            Mismatch::Stopped { address } => write!(f, "The program was stopped at m0x{address:02X} before it halted"),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Report {
+    pub program_name: String,
+    pub cycles: u128,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+
+    pub fn print(&self) {
+        if self.passed() {
+            println!("\nProgram {} matches its spec ({} cycles).", self.program_name, self.cycles);
+            return;
+        }
+
+        println!("\nProgram {} doesn't match its spec ({} cycles):", self.program_name, self.cycles);
+        for mismatch in &self.mismatches {
+            println!("  - {mismatch}");
+        }
+    }
+}
+
+// `vole-machine check <spec>...`
+pub fn check_command(paths: &[String]) -> bool {
+    if paths.is_empty() {
+        println!("Usage: vole-machine check <spec>...");
+        return false;
+    }
+
+    let mut all_passed = true;
+    for path in paths {
+        match Spec::load(path) {
+            Ok(spec) => {
+                let report = spec.check();
+                report.print();
+                all_passed &= report.passed();
+            },
+            Err(error) => {
+                println!("\n{error}");
+                all_passed = false;
+            },
+        }
+    }
+
+    all_passed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(text: &str) -> Spec {
+        Spec::parse(text, "test").unwrap()
+    }
+
+    #[test]
+    fn library_specs_match() {
+        for text in [include_str!("../specs/A.spec"), include_str!("../specs/B.spec")] {
+            let report = spec(text).check();
+            assert!(report.passed(), "{:?}", report.mismatches);
+        }
+    }
+
+    #[test]
+    fn inline_program() {
+        let spec = spec("name = five\ncode = 21 05 C0 00\nstart = 0x10\n[expected]\npc = 12\nr1 = 05");
+        assert_eq!(spec.program.name, "five");
+        assert_eq!(spec.program.start_address, 0x10);
+        assert_eq!(spec.expected, State { pc: Some(0x12), registers: vec![(1, 5)], memory: Vec::new() });
+        assert!(spec.check().passed());
+    }
+
+    #[test]
+    fn mismatches() {
+        let report = spec("code = 21 05 C0 00\n[expected]\nr1 = 06\nm0x10 = 01").check();
+        assert_eq!(report.mismatches, vec![
+            Mismatch::Register { index: 1, expected: 6, found: 5 },
+            Mismatch::Memory { address: 0x10, expected: 1, found: 0 },
+        ]);
+    }
+
+    #[test]
+    fn forbidden_opcode_and_budget() {
+        // Loads r1 and jumps back to itself forever
+        let report = spec("code = 21 05 B0 00\nforbid = 2\ncycles = 10").check();
+        assert_eq!(report.mismatches, vec![
+            Mismatch::ForbiddenOpCode { opcode: 2, address: 0, cycle: 1 },
+            Mismatch::CycleBudget { budget: 10 },
+        ]);
+    }
+
+    #[test]
+    fn cycle_budget_is_decimal_unless_hex() {
+        assert_eq!(spec("code = C0 00\ncycles = 10").cycle_budget, Some(10));
+        assert_eq!(spec("code = C0 00\ncycles = 0x10").cycle_budget, Some(16));
+    }
+
+    #[test]
+    fn initial_state_is_written() {
+        let spec = spec("code = C0 00\n[initial]\nr2 = 01 02\nm0xFE = AA BB");
+        let mut cpu = Cpu::new();
+        cpu.import(spec.program.clone());
+        spec.prepare(&mut cpu);
+        assert_eq!(cpu.register[2..4], [1, 2]);
+        assert_eq!(cpu.memory[0xFE..], [0xAA, 0xBB]);
+    }
+
+    #[test]
+    fn malformed_specs() {
+        for (text, error) in [
+            ("code = C0 00\n[final]", "line 2: unknown section [final]"),
+            ("code C0 00", "line 1: expected `key = value`"),
+            ("code = C0 0G", "line 1: invalid hexadecimal byte '0G'"),
+            ("code = C0 00\ncycles = ten", "line 2: invalid cycle budget 'ten'"),
+            ("code = C0 00\nforbid = 10", "line 2: invalid OpCode '10'"),
+            ("code = C0 00\nsanitize = loud", "line 2: sanitize must be off, warn or fault, not 'loud'"),
+            ("code = C0 00\n[initial]\nrF = 01 02", "line 3: register 'rF' is out of range"),
+            ("code = C0 00\n[initial]\nm0xFF = 01 02", "line 3: memory 'm0xFF' runs past m0xFF"),
+            ("code = C0 00\n[initial]\npc = 01 02", "line 3: pc takes a single address"),
+            ("code = C0 00\n[initial]\nx = 01", "line 3: unknown location 'x', expected pc, rR or m0xXY"),
+            ("code = C0 00\n[initial]\nr1 =", "line 3: missing value"),
+            ("program = A\ncode = C0 00", "a spec can't have both `program` and `code`"),
+            ("code = C0 00\nstart = FF", "the program doesn't fit in memory"),
+            ("program = A\nextensions = stack", "`extensions` can only be used together with `code`"),
+            ("program = nothing", "no program named 'nothing' in the library"),
+        ] {
+            assert_eq!(Spec::parse(text, "test").err().as_deref(), Some(error), "{text}");
+        }
+    }
+}
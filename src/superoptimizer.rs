@@ -0,0 +1,311 @@
+// Superoptimizer: the shortest instruction sequences that compute the same as a program
+//
+// The program is the reference for a function from its input registers to its output registers.
+// Candidate sequences are built from the register instructions (0x2 and 0x4 to 0xA) over the
+// input and output registers, shortest first, and run on the CPU: first on a few sample inputs,
+// then on every combination of input values. Registers that aren't inputs start at 0x00, both in
+// the reference program and in the candidates.
+
+use crate::instruction::Instruction;
+use crate::{Cpu, Program, Step};
+
+const CYCLE_BUDGET: u128 = 10_000;
+const MAX_INPUTS: usize = 2;
+const DEFAULT_LENGTH: usize = 3;
+// The search grows with the alphabet to the power of the length, this is already hours of it
+const MAX_LENGTH: usize = 4;
+// Shown per length, the rest are only counted
+const MAX_SHOWN: usize = 20;
+const SAMPLES: [u8; 8] = [0x00, 0x01, 0x02, 0x7F, 0x80, 0xFF, 0x5A, 0xC3];
+
+pub struct Search {
+    inputs: Vec<usize>,
+    outputs: Vec<usize>,
+    // Every combination of input values, in the order of `combination`
+    expected: Vec<Vec<u8>>,
+    samples: Vec<usize>,
+}
+
+impl Search {
+    pub fn new(reference: &Program, inputs: Vec<usize>, outputs: Vec<usize>) -> Result<Search, String> {
+        if inputs.len() > MAX_INPUTS {
+            return Err(format!("At most {MAX_INPUTS} input registers can be checked on every input."));
+        }
+
+        let combinations = 1 << (8 * inputs.len());
+        let mut expected = Vec::with_capacity(combinations);
+        for index in 0..combinations {
+            let mut cpu = Cpu::new();
+            cpu.import(reference.clone());
+            for (&r, value) in inputs.iter().zip(combination(index, inputs.len())) {
+                cpu.register[r] = value;
+            }
+            run(&mut cpu).map_err(|error| format!("Program {} {error}", reference.name))?;
+            expected.push(outputs.iter().map(|&r| cpu.register[r]).collect());
+        }
+
+        // Pair up the sample values so every input gets each of them
+        let samples = (0..SAMPLES.len())
+            .map(|position| {
+                (0..inputs.len()).fold(0, |index, input| {
+                    let value = SAMPLES[(position + input * 3) % SAMPLES.len()] as usize;
+                    index | value << (8 * input)
+                })
+            })
+            .collect();
+
+        Ok(Search { inputs, outputs, expected, samples })
+    }
+
+    // The registers candidates may use
+    fn registers(&self) -> Vec<usize> {
+        let mut registers: Vec<usize> = self.inputs.iter().chain(&self.outputs).copied().collect();
+        registers.sort();
+        registers.dedup();
+        registers
+    }
+
+    fn alphabet(&self) -> Vec<Instruction> {
+        let registers = self.registers();
+        let mut alphabet = Vec::new();
+        for &r in &registers {
+            alphabet.extend((0..=255).map(|value| Instruction::Load { r, value }));
+            alphabet.extend((1..8).map(|bits| Instruction::Rotate { r, bits }));
+            for &s in &registers {
+                if r != s {
+                    alphabet.push(Instruction::Move { r, s });
+                }
+                for &t in &registers {
+                    alphabet.push(Instruction::AddTc { r, s, t });
+                    alphabet.push(Instruction::AddFl { r, s, t });
+                    alphabet.push(Instruction::Or { r, s, t });
+                    alphabet.push(Instruction::And { r, s, t });
+                    alphabet.push(Instruction::Xor { r, s, t });
+                }
+            }
+        }
+        alphabet
+    }
+
+    // Whether the candidate gives the same outputs as the reference for the input combination
+    fn matches(&self, cpu: &mut Cpu, code: &[u8], index: usize) -> bool {
+        // The candidate and its halt have to fit in memory
+        if code.len() + 2 > cpu.memory.len() {
+            return false;
+        }
+        cpu.reset();
+        cpu.memory[..code.len()].copy_from_slice(code);
+        cpu.memory[code.len()..code.len() + 2].copy_from_slice(&Instruction::Halt.encode());
+        for (&r, value) in self.inputs.iter().zip(combination(index, self.inputs.len())) {
+            cpu.register[r] = value;
+        }
+        run(cpu).is_ok() && self.outputs.iter().zip(&self.expected[index]).all(|(&r, &value)| cpu.register[r] == value)
+    }
+
+    // All equivalent sequences of the given length, and how many sequences were tried
+    pub fn search(&self, length: usize) -> (Vec<Vec<Instruction>>, u64) {
+        let alphabet = self.alphabet();
+        let mut cpu = Cpu::new();
+        let mut found = Vec::new();
+        let mut tried = 0;
+        let mut sequence = Vec::with_capacity(length);
+        self.extend(&alphabet, length, &mut sequence, &mut cpu, &mut found, &mut tried);
+        (found, tried)
+    }
+
+    fn extend(
+        &self,
+        alphabet: &[Instruction],
+        length: usize,
+        sequence: &mut Vec<Instruction>,
+        cpu: &mut Cpu,
+        found: &mut Vec<Vec<Instruction>>,
+        tried: &mut u64,
+    ) {
+        if sequence.len() < length {
+            for &instruction in alphabet {
+                sequence.push(instruction);
+                self.extend(alphabet, length, sequence, cpu, found, tried);
+                sequence.pop();
+            }
+            return;
+        }
+        if !self.useful(sequence) {
+            return;
+        }
+
+        *tried += 1;
+        let code: Vec<u8> = sequence.iter().flat_map(|instruction| instruction.encode()).collect();
+        if self.samples.iter().all(|&index| self.matches(cpu, &code, index))
+            && (0..self.expected.len()).all(|index| self.matches(cpu, &code, index))
+        {
+            found.push(sequence.clone());
+        }
+    }
+
+    // A shortest sequence ends by writing an output and doesn't write a register nothing reads
+    fn useful(&self, sequence: &[Instruction]) -> bool {
+        let Some(last) = sequence.last() else {
+            return true;
+        };
+        if !last.register_written().is_some_and(|r| self.outputs.contains(&r)) {
+            return false;
+        }
+        sequence.iter().enumerate().all(|(position, instruction)| {
+            let Some(r) = instruction.register_written() else {
+                return true;
+            };
+            for later in &sequence[position + 1..] {
+                if later.registers_read().contains(&r) {
+                    return true;
+                }
+                if later.register_written() == Some(r) {
+                    return false;
+                }
+            }
+            self.outputs.contains(&r)
+        })
+    }
+}
+
+// The input values of the combination with the index, one byte per input
+fn combination(index: usize, inputs: usize) -> impl Iterator<Item = u8> {
+    (0..inputs).map(move |input| (index >> (8 * input)) as u8)
+}
+
+fn run(cpu: &mut Cpu) -> Result<(), String> {
+    while cpu.cycles < CYCLE_BUDGET {
+        match cpu.step() {
+            Step::Continue => {},
+            Step::Halt => return Ok(()),
+            Step::Fault(fault) => return Err(format!("faults: {fault}")),
+        }
+    }
+    Err(format!("doesn't halt within {CYCLE_BUDGET} cycles"))
+}
+
+fn parse_registers(text: &str) -> Result<Vec<usize>, String> {
+    text.split(',')
+        .map(|register| {
+            register.strip_prefix('r')
+                .and_then(|index| usize::from_str_radix(index, 16).ok())
+                .filter(|&index| index < 16)
+                .ok_or_else(|| format!("'{register}' is not a register (r0 to rF)"))
+        })
+        .collect()
+}
+
+// `vole-machine superoptimize <program> <inputs> <outputs> [length]`
+pub fn superoptimize_command(args: &[String]) -> bool {
+    let [source, inputs, outputs, rest @ ..] = args else {
+        println!("Usage: vole-machine superoptimize <program> <inputs> <outputs> [length]");
+        println!("e.g.   vole-machine superoptimize double.hex r1 r2 3");
+        return false;
+    };
+
+    let parsed = (|| {
+        let reference = crate::load_program(source)?;
+        let inputs = parse_registers(inputs)?;
+        let outputs = parse_registers(outputs)?;
+        let length = match rest.first() {
+            Some(length) => length.parse().map_err(|_| format!("'{length}' is not a length"))?,
+            None => DEFAULT_LENGTH,
+        };
+        if length > MAX_LENGTH {
+            return Err(format!("The length can be at most {MAX_LENGTH}, the search takes too long beyond that."));
+        }
+        Ok::<_, String>((reference, inputs, outputs, length))
+    })();
+    let (reference, inputs, outputs, length) = match parsed {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            println!("{error}");
+            return false;
+        },
+    };
+
+    let search = match Search::new(&reference, inputs, outputs) {
+        Ok(search) => search,
+        Err(error) => {
+            println!("{error}");
+            return false;
+        },
+    };
+    println!("Searching for sequences equivalent to program {} on all {} input(s)",
+        reference.name,
+        search.expected.len()
+    );
+
+    for length in 0..=length {
+        let (found, tried) = search.search(length);
+        if found.is_empty() {
+            println!("Length {length}: none of {tried} sequence(s) is equivalent");
+            continue;
+        }
+
+        println!("Length {length}: {} of {tried} sequence(s) are equivalent", found.len());
+        for sequence in found.iter().take(MAX_SHOWN) {
+            println!();
+            for instruction in sequence {
+                let [high, low] = instruction.encode();
+                println!("  0x{high:02X}{low:02X}  {instruction}");
+            }
+        }
+        if found.len() > MAX_SHOWN {
+            println!("\n  ... and {} more", found.len() - MAX_SHOWN);
+        }
+        return true;
+    }
+
+    println!("No equivalent sequence of up to {length} instruction(s).");
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(source: &str) -> Program {
+        crate::assembler::assemble("reference", source).unwrap().0
+    }
+
+    #[test]
+    fn finds_the_shortest_sequence() {
+        let search = Search::new(&program("move r1, r2\nadd r2, r2, r1\nhalt"), vec![1], vec![2]).unwrap();
+        assert_eq!(search.expected.len(), 256);
+        assert!(search.search(0).0.is_empty());
+        let (found, tried) = search.search(1);
+        assert_eq!(found, vec![vec![Instruction::AddTc { r: 2, s: 1, t: 1 }]]);
+        assert!(tried > 1);
+    }
+
+    #[test]
+    fn constants_need_no_inputs() {
+        let search = Search::new(&program("load r3, 7\nhalt"), Vec::new(), vec![3]).unwrap();
+        assert_eq!(search.search(1).0, vec![vec![Instruction::Load { r: 3, value: 7 }]]);
+    }
+
+    #[test]
+    fn candidates_that_dont_fit_dont_match() {
+        let search = Search::new(&program("halt"), Vec::new(), Vec::new()).unwrap();
+        assert!(search.matches(&mut Cpu::new(), &[0x00; 254], 0));
+        assert!(!search.matches(&mut Cpu::new(), &[0x00; 255], 0));
+    }
+
+    #[test]
+    fn malformed_arguments() {
+        assert_eq!(parse_registers("r1,rF"), Ok(vec![1, 15]));
+        assert_eq!(parse_registers("r1,rG"), Err(String::from("'rG' is not a register (r0 to rF)")));
+        assert_eq!(
+            Search::new(&program("halt"), vec![1, 2, 3], vec![4]).err(),
+            Some(format!("At most {MAX_INPUTS} input registers can be checked on every input."))
+        );
+        assert_eq!(
+            Search::new(&program("loop: jump loop"), Vec::new(), vec![1]).err(),
+            Some(format!("Program reference doesn't halt within {CYCLE_BUDGET} cycles"))
+        );
+        let args = |length: &str| ["A", "r1", "r2", length].map(String::from);
+        assert!(!superoptimize_command(&args("5")));
+        assert!(!superoptimize_command(&args("three")));
+    }
+}
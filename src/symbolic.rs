@@ -0,0 +1,632 @@
+// Symbolic execution: inputs that make a program reach an address or halt with given outputs
+//
+// The chosen registers and memory cells start as symbols instead of values. Every instruction
+// computes an expression over the symbols, and a jump that compares expressions (rR == r0, or
+// the status flags of an expression) explores both sides, each with the condition it took added
+// to its path. A path's conditions are solved by trying values for the symbols: every combination
+// for up to three symbols, random values beyond that, within a budget for the whole exploration.
+// Addresses are always part of the instruction, so only values are symbolic.
+
+use std::rc::Rc;
+
+use crate::instruction::Instruction;
+use crate::{Address, Condition, Flags, Location, Program};
+
+const MAX_STEPS: usize = 1_000; // Per path, to cut off loops that depend on a symbol
+const MAX_PATHS: usize = 1_000;
+const MAX_SHOWN: usize = 10;
+const EXHAUSTIVE_SYMBOLS: usize = 3;
+const RANDOM_TRIES: usize = 1 << 20;
+// Values tried by the solver over the whole exploration, two exhaustive searches of three symbols
+const SOLVER_BUDGET: usize = 1 << 25;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Operation {
+    AddTc,
+    AddFl,
+    Or,
+    And,
+    Xor,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Value {
+    Const(u8),
+    Symbol(usize),
+    Binary(Operation, Rc<Value>, Rc<Value>),
+    Rotate(Rc<Value>, u8),
+}
+
+impl Value {
+    fn eval(&self, model: &[u8]) -> u8 {
+        match self {
+            Value::Const(value) => *value,
+            Value::Symbol(index) => model[*index],
+            Value::Binary(operation, left, right) => {
+                let (left, right) = (left.eval(model), right.eval(model));
+                match operation {
+                    Operation::AddTc => left.wrapping_add(right),
+                    Operation::AddFl => (left as f32 + right as f32) as u8,
+                    Operation::Or => left | right,
+                    Operation::And => left & right,
+                    Operation::Xor => left ^ right,
+                }
+            },
+            Value::Rotate(value, bits) => value.eval(model).rotate_right(*bits as u32),
+        }
+    }
+
+    // The status flags an instruction computing this value sets, as the CPU sets them
+    fn flags(&self, model: &[u8]) -> Flags {
+        let result = self.eval(model);
+        let (carry, overflow) = match self {
+            Value::Binary(Operation::AddTc, left, right) => {
+                let (left, right) = (left.eval(model), right.eval(model));
+                (left.checked_add(right).is_none(), (left as i8).checked_add(right as i8).is_none())
+            },
+            Value::Binary(Operation::AddFl, left, right) => {
+                let out_of_range = left.eval(model) as f32 + right.eval(model) as f32 > u8::MAX as f32;
+                (out_of_range, out_of_range)
+            },
+            Value::Rotate(_, bits) => (*bits != 0 && result & 0x80 != 0, false),
+            _ => (false, false),
+        };
+        Flags { carry, overflow, zero: result == 0, negative: result & 0x80 != 0 }
+    }
+
+    fn collect_symbols(&self, symbols: &mut Vec<usize>) {
+        match self {
+            Value::Const(_) => {},
+            Value::Symbol(index) => {
+                if !symbols.contains(index) {
+                    symbols.push(*index);
+                }
+            },
+            Value::Binary(_, left, right) => {
+                left.collect_symbols(symbols);
+                right.collect_symbols(symbols);
+            },
+            Value::Rotate(value, _) => value.collect_symbols(symbols),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum FlagState {
+    Known(Flags),
+    // Set by the instruction that computed the value
+    Symbolic(Value),
+}
+
+// Combine the constants of `(x + c1) + c2` and `(x ^ c1) ^ c2`, so a loop adding to a symbol
+// doesn't grow its expression
+fn simplify(value: Value) -> Value {
+    let Value::Binary(operation @ (Operation::AddTc | Operation::Xor), left, right) = &value else {
+        return value;
+    };
+    let (inner, constant) = match (left.as_ref(), right.as_ref()) {
+        (inner, Value::Const(constant)) | (Value::Const(constant), inner) => (inner, *constant),
+        _ => return value,
+    };
+    let combine = |a: u8, b: u8| match operation {
+        Operation::AddTc => a.wrapping_add(b),
+        _ => a ^ b,
+    };
+    match inner {
+        Value::Binary(inner_operation, x, c) if inner_operation == operation => match c.as_ref() {
+            Value::Const(c) => Value::Binary(*operation, x.clone(), Rc::new(Value::Const(combine(*c, constant)))),
+            _ => value,
+        },
+        _ if constant == 0 => inner.clone(),
+        _ => value,
+    }
+}
+
+// A condition a path took at a jump
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Constraint {
+    Compare { left: Value, right: Value, equal: bool },
+    Flag { condition: Condition, value: Value },
+}
+
+impl Constraint {
+    fn holds(&self, model: &[u8]) -> bool {
+        match self {
+            Constraint::Compare { left, right, equal } => (left.eval(model) == right.eval(model)) == *equal,
+            Constraint::Flag { condition, value } => condition.holds(value.flags(model)),
+        }
+    }
+
+    fn collect_symbols(&self, symbols: &mut Vec<usize>) {
+        match self {
+            Constraint::Compare { left, right, .. } => {
+                left.collect_symbols(symbols);
+                right.collect_symbols(symbols);
+            },
+            Constraint::Flag { value, .. } => value.collect_symbols(symbols),
+        }
+    }
+}
+
+// Values for the symbols that satisfy every constraint, trying the hint first. Each other value
+// tried is taken from the budget, once it's spent only the hint is tried.
+fn solve(constraints: &[Constraint], hint: &[u8], budget: &mut usize) -> Option<Vec<u8>> {
+    // The newest constraint rules out the most values, so it's checked first
+    let satisfied = |model: &[u8]| constraints.iter().rev().all(|constraint| constraint.holds(model));
+    if satisfied(hint) {
+        return Some(hint.to_vec());
+    }
+
+    let mut symbols = Vec::new();
+    for constraint in constraints {
+        constraint.collect_symbols(&mut symbols);
+    }
+    let mut model = hint.to_vec();
+    let mut spend = || match *budget {
+        0 => false,
+        _ => {
+            *budget -= 1;
+            true
+        },
+    };
+
+    // Values are tried from just after the hint, a loop counting a symbol up or down needs the next one
+    if symbols.len() <= EXHAUSTIVE_SYMBOLS {
+        for combination in 1..=1usize << (8 * symbols.len()) {
+            if !spend() {
+                return None;
+            }
+            for (position, &symbol) in symbols.iter().enumerate() {
+                model[symbol] = hint[symbol].wrapping_add((combination >> (8 * position)) as u8);
+            }
+            if satisfied(&model) {
+                return Some(model);
+            }
+        }
+        return None;
+    }
+
+    let mut seed: u64 = 0x9E37_79B9_7F4A_7C15;
+    for _ in 0..RANDOM_TRIES {
+        if !spend() {
+            return None;
+        }
+        for &symbol in &symbols {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            model[symbol] = seed as u8;
+        }
+        if satisfied(&model) {
+            return Some(model);
+        }
+    }
+    None
+}
+
+#[derive(Clone)]
+struct State {
+    register: Vec<Value>,
+    memory: Vec<Value>,
+    pc: Address,
+    sp: Address,
+    status: FlagState,
+    path: Vec<Constraint>,
+    // Symbol values that satisfy the path so far
+    model: Vec<u8>,
+    steps: usize,
+}
+
+impl State {
+    fn concrete(&self, address: Address) -> Option<u8> {
+        match self.memory[address as usize] {
+            Value::Const(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    // Fold a computation on constants into its result, the flags are taken from the unfolded value
+    fn compute(&mut self, r: usize, value: Value, flags: bool) {
+        let mut symbols = Vec::new();
+        value.collect_symbols(&mut symbols);
+        let status = match symbols.is_empty() {
+            true => FlagState::Known(value.flags(&[])),
+            false => FlagState::Symbolic(value.clone()),
+        };
+        if flags {
+            self.status = status;
+        }
+        self.register[r] = match symbols.is_empty() {
+            true => Value::Const(value.eval(&[])),
+            false => simplify(value),
+        };
+    }
+
+    // The state after taking one side of a jump, if some input takes it
+    fn branch(&self, constraint: Constraint, pc: Address, budget: &mut usize) -> Option<State> {
+        let mut path = self.path.clone();
+        path.push(constraint);
+        let model = solve(&path, &self.model, budget)?;
+        Some(State { path, model, pc, ..self.clone() })
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Goal {
+    Reach(Address),
+    Halt(Vec<(Location, u8)>),
+}
+
+// A path that meets the goal, with the inputs that take it
+pub struct Found {
+    pub steps: usize,
+    pub inputs: Vec<(Location, u8)>,
+}
+
+#[derive(Default)]
+pub struct Exploration {
+    pub found: Vec<Found>,
+    pub paths: usize,
+    pub halted: usize,
+    pub faulted: usize,
+    pub cut_off: usize,
+    // Paths that execute or return to symbolic bytes
+    pub abandoned: usize,
+    // Whether the solver ran out of its budget, jumps after that only take the side the inputs so far take
+    pub budget_spent: bool,
+}
+
+enum Outcome {
+    Continue(Vec<State>),
+    Halted,
+    Faulted,
+    Abandoned,
+}
+
+fn step(mut state: State, program: &Program, budget: &mut usize) -> Outcome {
+    // Like on the CPU, an instruction at m0xFF has its second byte at m0x00
+    let (Some(high), Some(low)) = (state.concrete(state.pc), state.concrete(state.pc.wrapping_add(1))) else {
+        return Outcome::Abandoned;
+    };
+    let flags = program.extensions.flags;
+    let next = state.pc.wrapping_add(2);
+    let binary = |operation, state: &State, s: usize, t: usize| {
+        Value::Binary(operation, Rc::new(state.register[s].clone()), Rc::new(state.register[t].clone()))
+    };
+
+    match Instruction::decode(high, low, program.extensions) {
+        Instruction::NoOp => {},
+        Instruction::LoadFrom { r, address } => state.register[r] = state.memory[address as usize].clone(),
+        Instruction::Load { r, value } => state.register[r] = Value::Const(value),
+        Instruction::Store { r, address } => state.memory[address as usize] = state.register[r].clone(),
+        Instruction::Move { r, s } => state.register[s] = state.register[r].clone(),
+        Instruction::AddTc { r, s, t } => state.compute(r, binary(Operation::AddTc, &state, s, t), flags),
+        Instruction::AddFl { r, s, t } => state.compute(r, binary(Operation::AddFl, &state, s, t), flags),
+        Instruction::Or { r, s, t } => state.compute(r, binary(Operation::Or, &state, s, t), flags),
+        Instruction::And { r, s, t } => state.compute(r, binary(Operation::And, &state, s, t), flags),
+        Instruction::Xor { r, s, t } => state.compute(r, binary(Operation::Xor, &state, s, t), flags),
+        Instruction::Rotate { r, bits } => {
+            let value = Value::Rotate(Rc::new(state.register[r].clone()), bits);
+            state.compute(r, value, flags);
+        },
+        Instruction::Jump { r, address } => {
+            let (left, right) = (state.register[r].clone(), state.register[0].clone());
+            if let (Value::Const(left), Value::Const(right)) = (&left, &right) {
+                state.pc = if left == right { address } else { next };
+                return Outcome::Continue(vec![state]);
+            }
+            let taken = state.branch(Constraint::Compare { left: left.clone(), right: right.clone(), equal: true }, address, budget);
+            let not_taken = state.branch(Constraint::Compare { left, right, equal: false }, next, budget);
+            return Outcome::Continue(not_taken.into_iter().chain(taken).collect());
+        },
+        Instruction::JumpIf { condition, address } => {
+            let value = match &state.status {
+                FlagState::Known(status) => {
+                    state.pc = if condition.holds(*status) { address } else { next };
+                    return Outcome::Continue(vec![state]);
+                },
+                FlagState::Symbolic(value) => value.clone(),
+            };
+            let taken = state.branch(Constraint::Flag { condition, value: value.clone() }, address, budget);
+            let not_taken = state.branch(Constraint::Flag { condition: condition.negate(), value }, next, budget);
+            return Outcome::Continue(not_taken.into_iter().chain(taken).collect());
+        },
+        Instruction::Halt => return Outcome::Halted,
+        Instruction::Call { address } => {
+            let sp = state.sp.wrapping_sub(1);
+            if sp == 0 {
+                return Outcome::Faulted;
+            }
+            state.sp = sp;
+            state.memory[sp as usize] = Value::Const(next);
+            state.pc = address;
+            return Outcome::Continue(vec![state]);
+        },
+        Instruction::Return => {
+            if state.sp == 0 {
+                return Outcome::Faulted;
+            }
+            let Some(address) = state.concrete(state.sp) else {
+                return Outcome::Abandoned;
+            };
+            state.sp = state.sp.wrapping_add(1);
+            state.pc = address;
+            return Outcome::Continue(vec![state]);
+        },
+        Instruction::Push { r } => {
+            let sp = state.sp.wrapping_sub(1);
+            if sp == 0 {
+                return Outcome::Faulted;
+            }
+            state.sp = sp;
+            state.memory[sp as usize] = state.register[r].clone();
+        },
+        Instruction::Pop { r } => {
+            if state.sp == 0 {
+                return Outcome::Faulted;
+            }
+            state.register[r] = state.memory[state.sp as usize].clone();
+            state.sp = state.sp.wrapping_add(1);
+        },
+        Instruction::Invalid { .. } => return Outcome::Faulted,
+    }
+
+    state.pc = next;
+    Outcome::Continue(vec![state])
+}
+
+pub fn explore(program: &Program, symbols: &[Location], goal: &Goal) -> Exploration {
+    let mut memory = vec![Value::Const(0); 256];
+    for (offset, &byte) in program.code.iter().enumerate() {
+        memory[program.start_address.wrapping_add(offset as u8) as usize] = Value::Const(byte);
+    }
+    let mut register = vec![Value::Const(0); 16];
+    for (index, &location) in symbols.iter().enumerate() {
+        match location {
+            Location::Register(r) => register[r] = Value::Symbol(index),
+            Location::Memory(address) => memory[address as usize] = Value::Symbol(index),
+        }
+    }
+
+    let value_of = |state: &State, location: Location| match location {
+        Location::Register(r) => state.register[r].clone(),
+        Location::Memory(address) => state.memory[address as usize].clone(),
+    };
+    let inputs = |model: &[u8]| symbols.iter().copied().zip(model.iter().copied()).collect();
+
+    let mut exploration = Exploration::default();
+    let mut budget = SOLVER_BUDGET;
+    let mut pending = vec![State {
+        register,
+        memory,
+        pc: program.start_address,
+        sp: 0,
+        status: FlagState::Known(Flags::default()),
+        path: Vec::new(),
+        model: vec![0; symbols.len()],
+        steps: 0,
+    }];
+
+    while let Some(mut state) = pending.pop() {
+        if exploration.paths >= MAX_PATHS {
+            break;
+        }
+
+        if *goal == Goal::Reach(state.pc) {
+            exploration.paths += 1;
+            exploration.found.push(Found { steps: state.steps, inputs: inputs(&state.model) });
+            continue;
+        }
+        if state.steps >= MAX_STEPS {
+            exploration.paths += 1;
+            exploration.cut_off += 1;
+            continue;
+        }
+
+        state.steps += 1;
+        let halted = state.clone();
+        match step(state, program, &mut budget) {
+            Outcome::Continue(states) => pending.extend(states),
+            Outcome::Halted => {
+                exploration.paths += 1;
+                exploration.halted += 1;
+                if let Goal::Halt(outputs) = goal {
+                    let mut path = halted.path.clone();
+                    path.extend(outputs.iter().map(|&(location, value)| Constraint::Compare {
+                        left: value_of(&halted, location),
+                        right: Value::Const(value),
+                        equal: true,
+                    }));
+                    if let Some(model) = solve(&path, &halted.model, &mut budget) {
+                        exploration.found.push(Found { steps: halted.steps, inputs: inputs(&model) });
+                    }
+                }
+            },
+            Outcome::Faulted => {
+                exploration.paths += 1;
+                exploration.faulted += 1;
+            },
+            Outcome::Abandoned => {
+                exploration.paths += 1;
+                exploration.abandoned += 1;
+            },
+        }
+    }
+
+    exploration.budget_spent = budget == 0;
+    exploration
+}
+
+fn parse_location(text: &str) -> Result<Location, String> {
+    let register = text.strip_prefix('r').and_then(|r| usize::from_str_radix(r, 16).ok()).filter(|&r| r < 16);
+    let address = text.strip_prefix("m0x").and_then(|address| u8::from_str_radix(address, 16).ok());
+    match (register, address) {
+        (Some(r), _) => Ok(Location::Register(r)),
+        (_, Some(address)) => Ok(Location::Memory(address)),
+        _ => Err(format!("'{text}' is neither a register (r0 to rF) nor a memory cell (m0x00 to m0xFF)")),
+    }
+}
+
+fn parse_goal(args: &[String]) -> Result<Goal, String> {
+    match args {
+        [kind, address] if kind == "reach" => {
+            let address = address.trim_start_matches("m0x").trim_start_matches("0x");
+            u8::from_str_radix(address, 16)
+                .map(Goal::Reach)
+                .map_err(|_| format!("'{address}' is not an address"))
+        },
+        [kind, outputs @ ..] if kind == "halt" => outputs.iter()
+            .map(|output| {
+                let (location, value) = output.split_once('=')
+                    .ok_or_else(|| format!("'{output}' should look like r2=05 or m0x10=FF"))?;
+                let value = value.trim_start_matches("0x");
+                let value = u8::from_str_radix(value, 16).map_err(|_| format!("'{value}' is not a byte"))?;
+                Ok((parse_location(location)?, value))
+            })
+            .collect::<Result<_, String>>()
+            .map(Goal::Halt),
+        _ => Err(String::from("The goal is either `reach <address>` or `halt [<location>=<value>]...`")),
+    }
+}
+
+// `vole-machine symbolic <program> <symbols> reach <address>`
+// `vole-machine symbolic <program> <symbols> halt [<location>=<value>]...`
+pub fn symbolic_command(args: &[String]) -> bool {
+    let [source, symbols, goal @ ..] = args else {
+        println!("Usage: vole-machine symbolic <program> <symbols> reach <address>");
+        println!("       vole-machine symbolic <program> <symbols> halt [<location>=<value>]...");
+        println!("e.g.   vole-machine symbolic B r2 halt r1=04");
+        return false;
+    };
+
+    let parsed = (|| {
+        let program = crate::load_program(source)?;
+        let symbols = symbols.split(',').map(parse_location).collect::<Result<Vec<_>, _>>()?;
+        Ok::<_, String>((program, symbols, parse_goal(goal)?))
+    })();
+    let (program, symbols, goal) = match parsed {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            println!("{error}");
+            return false;
+        },
+    };
+
+    let exploration = explore(&program, &symbols, &goal);
+    println!("Explored {} path(s) of program {}: {} halted, {} faulted, {} cut off after {MAX_STEPS} steps, {} ran into symbolic code",
+        exploration.paths,
+        program.name,
+        exploration.halted,
+        exploration.faulted,
+        exploration.cut_off,
+        exploration.abandoned
+    );
+    if exploration.paths >= MAX_PATHS {
+        println!("Stopped after {MAX_PATHS} paths, others may exist.");
+    }
+    if exploration.budget_spent {
+        println!("The solver stopped after trying {SOLVER_BUDGET} values, later jumps were only explored on the side already taken.");
+    }
+
+    let goal_text = match &goal {
+        Goal::Reach(address) => format!("reaches m0x{address:02X}"),
+        Goal::Halt(_) => String::from("halts as asked"),
+    };
+    if exploration.found.is_empty() {
+        println!("\nNo path found that {goal_text}.");
+        return false;
+    }
+
+    println!("\n{} path(s) found that {goal_text}:", exploration.found.len());
+    for found in exploration.found.iter().take(MAX_SHOWN) {
+        let inputs: Vec<String> = found.inputs.iter().map(|(location, value)| format!("{location} = 0x{value:02X}")).collect();
+        println!("  after {:>4} steps with {}", found.steps, inputs.join(", "));
+    }
+    if exploration.found.len() > MAX_SHOWN {
+        println!("  ... and {} more", exploration.found.len() - MAX_SHOWN);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cpu, ProgramLibrary, Step};
+
+    fn program(source: &str) -> Program {
+        crate::assembler::assemble("test", source).unwrap().0
+    }
+
+    #[test]
+    fn finds_inputs_that_halt_as_asked() {
+        let symbols = [Location::Memory(0x00), Location::Memory(0x01), Location::Memory(0x02)];
+        let outputs = vec![(Location::Memory(0x10), 0x01), (Location::Memory(0x11), 0x02), (Location::Memory(0x12), 0x03)];
+        let exploration = explore(&ProgramLibrary::init().get("A").unwrap(), &symbols, &Goal::Halt(outputs));
+        assert_eq!((exploration.paths, exploration.halted), (1, 1));
+        assert_eq!(exploration.found[0].inputs, vec![(symbols[0], 0x01), (symbols[1], 0x02), (symbols[2], 0x03)]);
+        assert!(!exploration.budget_spent);
+    }
+
+    #[test]
+    fn explores_both_sides_of_a_jump() {
+        let program = program("load r0, 0x2A\njump r1, win\nhalt\nwin: halt");
+        let exploration = explore(&program, &[Location::Register(1)], &Goal::Reach(0x06));
+        assert_eq!((exploration.paths, exploration.halted), (2, 1));
+        assert_eq!(exploration.found.len(), 1);
+        assert_eq!(exploration.found[0].inputs, vec![(Location::Register(1), 0x2A)]);
+        assert_eq!(exploration.found[0].steps, 2);
+    }
+
+    #[test]
+    fn wraps_around_like_the_cpu() {
+        // Jumps to m0xFF, where the instruction is C0 followed by the B0 at m0x00
+        let mut code = vec![0x00; 256];
+        code[..2].copy_from_slice(&[0xB0, 0xFF]);
+        code[0xFF] = 0xC0;
+        let program = Program::new(String::from("wrap"), code, 0x00);
+
+        let exploration = explore(&program, &[Location::Register(1)], &Goal::Halt(Vec::new()));
+        assert_eq!((exploration.halted, exploration.found.len()), (1, 1));
+        let mut cpu = Cpu::new();
+        cpu.import(program);
+        assert_eq!((cpu.step(), cpu.step()), (Step::Continue, Step::Halt));
+    }
+
+    #[test]
+    fn solver_stays_within_its_budget() {
+        let equal = |value| Constraint::Compare { left: Value::Symbol(0), right: Value::Const(value), equal: true };
+        let mut budget = 1_000;
+        assert_eq!(solve(&[equal(0x10)], &[0], &mut budget), Some(vec![0x10]));
+        assert_eq!(budget, 1_000 - 0x10);
+
+        // Never satisfied: every value is tried once
+        let mut budget = 1_000;
+        assert_eq!(solve(&[equal(1), equal(2)], &[0], &mut budget), None);
+        assert_eq!(budget, 1_000 - 256);
+
+        // Out of budget, only the hint is tried
+        let mut budget = 0;
+        assert_eq!(solve(&[equal(0x10)], &[0], &mut budget), None);
+        assert_eq!(solve(&[equal(0x10)], &[0x10], &mut budget), Some(vec![0x10]));
+    }
+
+    #[test]
+    fn malformed_arguments() {
+        assert_eq!(parse_location("rA"), Ok(Location::Register(10)));
+        assert_eq!(parse_location("m0xFF"), Ok(Location::Memory(0xFF)));
+        assert_eq!(parse_location("r16"), Err(String::from(
+            "'r16' is neither a register (r0 to rF) nor a memory cell (m0x00 to m0xFF)"
+        )));
+
+        let goal = |args: &[&str]| parse_goal(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>());
+        assert_eq!(goal(&["reach", "m0x08"]), Ok(Goal::Reach(0x08)));
+        assert_eq!(goal(&["halt", "r2=05", "m0x10=0xFF"]), Ok(Goal::Halt(vec![
+            (Location::Register(2), 0x05),
+            (Location::Memory(0x10), 0xFF),
+        ])));
+        assert_eq!(goal(&["reach", "100"]), Err(String::from("'100' is not an address")));
+        assert_eq!(goal(&["halt", "r2"]), Err(String::from("'r2' should look like r2=05 or m0x10=FF")));
+        assert_eq!(goal(&["halt", "r2=100"]), Err(String::from("'100' is not a byte")));
+        assert!(goal(&["stop"]).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+// Taint tracking: where the value of every register and memory cell comes from
+//
+// Every location starts out as its own input. An instruction that writes a location gives it
+// what the locations it reads come from, plus its own cycle, so at any point a location knows
+// the inputs its value was computed from and the instructions that computed it, its backward
+// slice. Only the flow of values is tracked, not the jumps that decided which instructions ran.
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::instruction::Instruction;
+use crate::{Cpu, Location, Step, TraceEntry, TraceEvent};
+
+const CYCLE_BUDGET: u128 = 10_000;
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Provenance {
+    pub inputs: BTreeSet<Location>,
+    pub cycles: BTreeSet<u128>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Taint {
+    register: Vec<Provenance>,
+    memory: Vec<Provenance>,
+    // Every executed instruction, the trace only keeps the last ones
+    executed: Vec<TraceEntry>,
+    // The registers and memory when the first instruction ran
+    initial: Option<([u8; 16], [u8; 256])>,
+}
+
+impl Taint {
+    pub fn new() -> Taint {
+        let input = |location| Provenance { inputs: BTreeSet::from([location]), cycles: BTreeSet::new() };
+        Taint {
+            register: (0..16).map(|r| input(Location::Register(r))).collect(),
+            memory: (0..=255).map(|address| input(Location::Memory(address))).collect(),
+            executed: Vec::new(),
+            initial: None,
+        }
+    }
+
+    pub fn provenance(&self, location: Location) -> &Provenance {
+        match location {
+            Location::Register(r) => &self.register[r],
+            Location::Memory(address) => &self.memory[address as usize],
+        }
+    }
+
+    // Called before the CPU executes the instruction at its program counter
+    pub fn record(&mut self, cpu: &Cpu, instruction: Instruction) {
+        self.initial.get_or_insert((cpu.register, cpu.memory));
+        self.executed.push(TraceEntry { cycle: cpu.cycles, address: cpu.pc, event: TraceEvent::Executed(instruction) });
+
+        let (sources, destination) = match instruction {
+            Instruction::Call { .. } => (Vec::new(), Some(Location::Memory(cpu.sp.wrapping_sub(1)))),
+            Instruction::Push { r } => (vec![Location::Register(r)], Some(Location::Memory(cpu.sp.wrapping_sub(1)))),
+            Instruction::Pop { r } => (vec![Location::Memory(cpu.sp)], Some(Location::Register(r))),
+            _ => {
+                let sources = instruction.registers_read().into_iter()
+                    .map(Location::Register)
+                    .chain(instruction.memory_read().map(Location::Memory))
+                    .collect();
+                let destination = instruction.register_written().map(Location::Register)
+                    .or(instruction.memory_written().map(Location::Memory));
+                (sources, destination)
+            },
+        };
+        let Some(destination) = destination else {
+            return;
+        };
+
+        let mut provenance = Provenance { inputs: BTreeSet::new(), cycles: BTreeSet::from([cpu.cycles]) };
+        for source in sources {
+            let source = self.provenance(source);
+            provenance.inputs.extend(&source.inputs);
+            provenance.cycles.extend(&source.cycles);
+        }
+        match destination {
+            Location::Register(r) => self.register[r] = provenance,
+            Location::Memory(address) => self.memory[address as usize] = provenance,
+        }
+    }
+
+    // The inputs a location's value was computed from and the instructions that computed it
+    pub fn why(&self, cpu: &Cpu, location: Location) -> String {
+        let value = match location {
+            Location::Register(r) => cpu.register[r],
+            Location::Memory(address) => cpu.memory[address as usize],
+        };
+        let Provenance { inputs, cycles } = self.provenance(location);
+        let initial_value = |location: Location| match (self.initial, location) {
+            (Some((register, _)), Location::Register(r)) => register[r],
+            (Some((_, memory)), Location::Memory(address)) => memory[address as usize],
+            (None, _) => value,
+        };
+
+        let mut why = String::new();
+        if cycles.is_empty() {
+            writeln!(why, "{location} = 0x{value:02X} was never written, it still holds its value from the start.").unwrap();
+            return why;
+        }
+
+        let inputs: Vec<String> = inputs.iter()
+            .map(|&input| format!("{input} = 0x{:02X}", initial_value(input)))
+            .collect();
+        match inputs.is_empty() {
+            true => writeln!(why, "{location} = 0x{value:02X} was computed from constants only,").unwrap(),
+            false => writeln!(why, "{location} = 0x{value:02X} was computed from {} at the start,", inputs.join(", ")).unwrap(),
+        }
+        writeln!(why, "by these {} instruction(s):\n", cycles.len()).unwrap();
+        writeln!(why, " Cycle  Address  Instruction").unwrap();
+        for entry in self.executed.iter().filter(|entry| cycles.contains(&entry.cycle)) {
+            writeln!(why, "{entry}").unwrap();
+        }
+        why
+    }
+}
+
+pub fn parse_location(text: &str) -> Option<Location> {
+    let text = text.trim();
+    if let Some(r) = text.strip_prefix('r') {
+        return usize::from_str_radix(r, 16).ok().filter(|&r| r < 16).map(Location::Register);
+    }
+    text.strip_prefix("m0x")
+        .and_then(|address| u8::from_str_radix(address, 16).ok())
+        .map(Location::Memory)
+}
+
+// `vole-machine why <program> <location>...`
+pub fn why_command(args: &[String]) -> bool {
+    let [source, locations @ ..] = args else {
+        println!("Usage: vole-machine why <program> <location>...");
+        return false;
+    };
+    let locations: Option<Vec<Location>> = locations.iter().map(|location| parse_location(location)).collect();
+    let Some(locations) = locations.filter(|locations| !locations.is_empty()) else {
+        println!("Locations are registers (r0 to rF) or memory cells (m0x00 to m0xFF).");
+        return false;
+    };
+    let program = match crate::load_program(source) {
+        Ok(program) => program,
+        Err(error) => {
+            println!("{error}");
+            return false;
+        },
+    };
+    let spec = match crate::spec::Spec::find(&program.name) {
+        Ok(spec) => spec,
+        Err(error) => {
+            println!("Ignoring the spec for program {}: {error}", program.name);
+            None
+        },
+    };
+
+    let mut cpu = Cpu::new();
+    cpu.taint = Some(Taint::new());
+    cpu.import(program);
+    if let Some(spec) = &spec {
+        spec.prepare(&mut cpu);
+    }
+    while cpu.cycles < CYCLE_BUDGET {
+        match cpu.step() {
+            Step::Continue => {},
+            Step::Halt => break,
+            Step::Fault(fault) => {
+                println!("{fault}\n");
+                break;
+            },
+        }
+    }
+    if cpu.cycles >= CYCLE_BUDGET {
+        println!("Program {} didn't halt within {CYCLE_BUDGET} cycles.\n", cpu.program_name);
+    }
+
+    let Some(taint) = &cpu.taint else {
+        return false;
+    };
+    for location in locations {
+        println!("{}", taint.why(&cpu, location));
+    }
+    true
+}
@@ -0,0 +1,367 @@
+// Full-screen interface: the machine in panes, driven by single keys
+//
+// The registers and the memory grid are at the top, with the disassembly around the program
+// counter beside the memory when the terminal is wide enough and below it otherwise. The trace of
+// the last instructions and a console of what happened fill the rest. Panes grow and shrink with
+// the terminal. Keys are read as they are typed, without waiting for Enter.
+//
+//     s        step one instruction          arrows   move the memory cursor (or h, j, k, l)
+//     r        run                           b        toggle a breakpoint at the cursor
+//     p        pause                         e        edit the memory cell at the cursor
+//     + / -    run faster or slower          q        quit (Ctrl-C pauses, or quits when paused)
+
+use std::io::Write;
+use std::time::Instant;
+
+use crate::events::Breakpoints;
+use crate::render::{Ansi, Marker, Renderer, Span, Table};
+use crate::screen::{self, Key, RawMode, Screen};
+use crate::{Address, Cpu, Foreground, Step, DEFAULT_SPEED, FRAME_DURATION, SPEEDS};
+
+// Instructions between two frames at full speed
+const FULL_SPEED_STEPS: u32 = 10_000;
+
+const CONSOLE_LENGTH: usize = 100;
+// Memory grid with its header and row labels, and the blank line above it
+const MEMORY_HEIGHT: usize = 18;
+const MEMORY_WIDTH: usize = 68;
+const HELP: &str = "s step  r run  p pause  +/- speed  arrows move  b breakpoint  e edit  q quit";
+
+struct Tui<'a> {
+    cpu: &'a mut Cpu,
+    breakpoints: Breakpoints,
+    running: bool,
+    speed: usize,
+    // The memory cell that breakpoints and edits go to
+    cursor: Address,
+    // The hex digits typed so far while editing the cell at the cursor
+    editing: Option<String>,
+    console: Vec<String>,
+    // The halt or fault the program stopped at
+    stopped: Option<Step>,
+}
+
+// A line of text in the terminal's own colour
+fn plain(text: impl Into<String>) -> Vec<Span> {
+    vec![Span::new(text, None)]
+}
+
+fn width(line: &[Span]) -> usize {
+    line.iter().map(|span| span.text.chars().count()).sum()
+}
+
+// Exactly `height` lines: the first ones, padded with empty lines
+fn fit(mut lines: Vec<Vec<Span>>, height: usize) -> Vec<Vec<Span>> {
+    lines.truncate(height);
+    lines.resize(height, Vec::new());
+    lines
+}
+
+// Two panes next to each other, the left one padded to its width
+fn beside(left: Vec<Vec<Span>>, left_width: usize, right: Vec<Vec<Span>>) -> Vec<Vec<Span>> {
+    let height = left.len().max(right.len());
+    let mut right = fit(right, height).into_iter();
+    fit(left, height)
+        .into_iter()
+        .map(|mut line| {
+            line.push(Span::new(" ".repeat(left_width.saturating_sub(width(&line))), None));
+            line.extend(right.next().unwrap_or_default());
+            line
+        })
+        .collect()
+}
+
+fn title(text: &str) -> Vec<Span> {
+    vec![Span::new(text, Some(Foreground::Cyan))]
+}
+
+impl<'a> Tui<'a> {
+    fn new(cpu: &'a mut Cpu) -> Tui<'a> {
+        let console = vec![format!("Program {} loaded at m0x{:02X}.", cpu.program_name, cpu.pc)];
+        Tui {
+            cpu,
+            breakpoints: Breakpoints::new(),
+            running: false,
+            speed: DEFAULT_SPEED,
+            cursor: 0,
+            editing: None,
+            console,
+            stopped: None,
+        }
+    }
+
+    fn say(&mut self, message: String) {
+        if self.console.len() == CONSOLE_LENGTH {
+            self.console.remove(0);
+        }
+        self.console.push(message);
+    }
+
+    // Execute one instruction, and say whether the program can go on
+    fn step(&mut self) -> bool {
+        if self.stopped.is_some() {
+            self.running = false;
+            return false;
+        }
+        match self.cpu.step_with(&mut self.breakpoints) {
+            Step::Continue => {},
+            Step::Halt => {
+                self.say(format!("Program {} halted after {} cycles.", self.cpu.program_name, self.cpu.cycles));
+                self.stopped = Some(Step::Halt);
+            },
+            Step::Fault(fault) => {
+                self.say(fault.to_string());
+                self.stopped = Some(Step::Fault(fault));
+            },
+        }
+        if self.stopped.is_some() {
+            self.running = false;
+            return false;
+        }
+        if let Some(address) = self.breakpoints.hit().filter(|_| self.running) {
+            self.say(format!("Paused at the breakpoint at m0x{address:02X}."));
+            self.running = false;
+            return false;
+        }
+        true
+    }
+
+    // Handle a key, and say whether the interface stays open
+    fn handle(&mut self, key: Key) -> bool {
+        if let Some(digits) = &mut self.editing {
+            match key {
+                Key::Char(c) if c.is_ascii_hexdigit() => digits.push(c),
+                Key::Backspace => {
+                    digits.pop();
+                },
+                Key::Escape | Key::Interrupt => self.editing = None,
+                _ => {},
+            }
+            if let Some(digits) = self.editing.take_if(|digits| digits.len() == 2) {
+                let value = u8::from_str_radix(&digits, 16).unwrap();
+                let old = self.cpu.memory[self.cursor as usize];
+                self.cpu.memory[self.cursor as usize] = value;
+                self.cpu.initialized_memory[self.cursor as usize] = true;
+                self.say(format!("m0x{:02X} changed 0x{old:02X} -> 0x{value:02X}.", self.cursor));
+            }
+            return true;
+        }
+
+        match key {
+            Key::Char('q') | Key::Char('Q') => return false,
+            Key::Interrupt if !self.running => return false,
+            Key::Interrupt | Key::Char('p') => {
+                if self.running {
+                    self.say(format!("Paused at m0x{:02X}.", self.cpu.pc));
+                }
+                self.running = false;
+            },
+            Key::Char('s') | Key::Enter => {
+                self.running = false;
+                self.step();
+            },
+            Key::Char('r') => match self.stopped {
+                Some(_) => self.say(String::from("The program has stopped, quit and load it again to rerun it.")),
+                None => self.running = true,
+            },
+            Key::Char('+') | Key::Char('=') => self.speed = (self.speed + 1).min(SPEEDS.len() - 1),
+            Key::Char('-') => self.speed = self.speed.saturating_sub(1),
+            Key::Up | Key::Char('k') => self.cursor = self.cursor.wrapping_sub(16),
+            Key::Down | Key::Char('j') => self.cursor = self.cursor.wrapping_add(16),
+            Key::Left | Key::Char('h') => self.cursor = self.cursor.wrapping_sub(1),
+            Key::Right | Key::Char('l') => self.cursor = self.cursor.wrapping_add(1),
+            Key::Char('b') => {
+                let address = self.cursor;
+                match self.breakpoints.addresses.remove(&address) {
+                    true => self.say(format!("Removed the breakpoint at m0x{address:02X}.")),
+                    false => {
+                        self.breakpoints.addresses.insert(address);
+                        self.say(format!("Added a breakpoint at m0x{address:02X}."));
+                    },
+                }
+            },
+            Key::Char('e') => self.editing = Some(String::new()),
+            _ => {},
+        }
+        true
+    }
+
+    fn status(&self) -> String {
+        let state = match (&self.stopped, self.running) {
+            (Some(Step::Fault(_)), _) => String::from("faulted"),
+            (Some(_), _) => String::from("halted"),
+            (None, true) => String::from("running"),
+            (None, false) => String::from("paused"),
+        };
+        format!("Program {}  cycle {}  PC m0x{:02X}  {state} at {}",
+            self.cpu.program_name, self.cpu.cycles, self.cpu.pc, crate::describe_speed(self.speed)
+        )
+    }
+
+    fn memory_pane(&self) -> Vec<Vec<Span>> {
+        let mut table = self.cpu.memory_table();
+        for (address, cell) in table.rows.iter_mut().flat_map(|(_, cells)| cells).enumerate() {
+            if cell.marker == Marker::ProgramCounter {
+                continue;
+            }
+            if address == self.cursor as usize {
+                cell.marker = Marker::Selected;
+            } else if self.breakpoints.addresses.contains(&(address as Address)) {
+                cell.marker = Marker::Breakpoint;
+            }
+        }
+        let mut renderer = Ansi::new();
+        renderer.line("");
+        renderer.table(&table);
+        renderer.take_lines()
+    }
+
+    fn disassembly_pane(&self, height: usize) -> Vec<Vec<Span>> {
+        let lines = height.saturating_sub(2).clamp(1, 127) as u8;
+        let mut table: Table = self.cpu.disassembly_table((lines - 1) / 2, lines / 2);
+        let first = self.cpu.pc.wrapping_sub((lines - 1) / 2 * 2);
+        for (i, (_, cells)) in table.rows.iter_mut().enumerate() {
+            let address = first.wrapping_add(i as u8 * 2);
+            if cells[0].marker == Marker::None && self.breakpoints.addresses.contains(&address) {
+                cells[0].marker = Marker::Breakpoint;
+            }
+        }
+        let mut renderer = Ansi::new();
+        renderer.line("");
+        renderer.table(&table);
+        let mut pane = renderer.take_lines();
+        pane[0] = title(" Disassembly");
+        pane
+    }
+
+    fn trace_pane(&self, height: usize) -> Vec<Vec<Span>> {
+        let entries: Vec<_> = self.cpu.trace.iter().collect();
+        let shown = entries.len().saturating_sub(height.saturating_sub(1));
+        let mut pane = vec![title(" Trace")];
+        pane.extend(entries[shown..].iter().map(|entry| plain(entry.to_string())));
+        pane
+    }
+
+    fn console_pane(&self, height: usize) -> Vec<Vec<Span>> {
+        let mut lines: Vec<String> = self.console.clone();
+        if let Some(digits) = &self.editing {
+            lines.push(format!("m0x{:02X} = {digits}_  (two hex digits, Esc to cancel)", self.cursor));
+        }
+        let shown = lines.len().saturating_sub(height.saturating_sub(1));
+        let mut pane = vec![title(" Console")];
+        pane.extend(lines[shown..].iter().map(|line| plain(format!(" {line}"))));
+        pane
+    }
+
+    // One frame for a terminal of the given size
+    fn frame(&self, (rows, columns): (usize, usize)) -> Vec<Vec<Span>> {
+        // The screen keeps the last row free
+        let rows = rows.saturating_sub(1);
+        let mut frame = vec![plain(format!(" {}", self.status()))];
+
+        let mut registers = Ansi::new();
+        self.cpu.render_registers(&mut registers);
+        frame.extend(registers.take_lines());
+
+        let memory = self.memory_pane();
+        let beside_memory = columns >= MEMORY_WIDTH + 32;
+        if beside_memory {
+            let disassembly = self.disassembly_pane(MEMORY_HEIGHT);
+            frame.extend(beside(memory, MEMORY_WIDTH + 2, disassembly));
+        } else {
+            frame.extend(memory);
+        }
+
+        // What's left, without the help line, goes to the disassembly when it didn't fit beside
+        // the memory, then the trace and the console
+        let mut left = rows.saturating_sub(frame.len() + 2);
+        if !beside_memory && left > 6 {
+            let height = (left / 3).clamp(4, 12);
+            frame.extend(self.disassembly_pane(height));
+            left = left.saturating_sub(height);
+        }
+        frame.push(Vec::new());
+        if columns >= 100 {
+            let trace = self.trace_pane(left);
+            let console = self.console_pane(left);
+            frame.extend(fit(beside(trace, columns / 2, console), left));
+        } else {
+            let trace_height = left / 2;
+            frame.extend(fit(self.trace_pane(trace_height), trace_height));
+            frame.extend(fit(self.console_pane(left - trace_height), left - trace_height));
+        }
+
+        frame.push(plain(format!(" {HELP}")));
+        frame
+    }
+}
+
+// Run the machine in the full-screen interface until the user quits, and return the halt or fault it
+// stopped at, or Continue when it was still going
+pub fn run(cpu: &mut Cpu) -> Result<Step, String> {
+    let raw_mode = RawMode::enter()?;
+    let mut screen = Screen::enter();
+    print!("\x1B[?25l"); // The cursor would only be in the way
+    let mut tui = Tui::new(cpu);
+
+    let mut last_frame = Instant::now();
+    let mut due = 0.0;
+    loop {
+        screen.draw(tui.frame(screen::size()));
+        for key in raw_mode.read_keys() {
+            if !tui.handle(key) {
+                return Ok(tui.stopped.unwrap_or(Step::Continue));
+            }
+        }
+
+        let now = Instant::now();
+        if tui.running {
+            let steps = match SPEEDS[tui.speed] {
+                Some(per_second) => {
+                    due += (now - last_frame).as_secs_f64() * per_second as f64;
+                    let steps = (due as u32).min(FULL_SPEED_STEPS);
+                    due -= due.floor();
+                    steps
+                },
+                None => FULL_SPEED_STEPS,
+            };
+            for _ in 0..steps {
+                if !tui.step() {
+                    break;
+                }
+            }
+        } else {
+            due = 0.0;
+        }
+        last_frame = now;
+        std::io::stdout().flush().unwrap();
+        std::thread::sleep(FRAME_DURATION);
+    }
+}
+
+// `vole-machine tui <program>`
+pub fn tui_command(args: &[String]) -> bool {
+    let [source] = args else {
+        println!("Usage: vole-machine tui <program>");
+        return false;
+    };
+    let program = match crate::load_program(source) {
+        Ok(program) => program,
+        Err(error) => {
+            println!("{error}");
+            return false;
+        },
+    };
+    let mut cpu = Cpu::new();
+    cpu.import(program);
+    if let Ok(Some(spec)) = crate::spec::Spec::find(&cpu.program_name) {
+        spec.prepare(&mut cpu);
+    }
+    match run(&mut cpu) {
+        Ok(_) => true,
+        Err(error) => {
+            println!("{error}");
+            false
+        },
+    }
+}
@@ -0,0 +1,165 @@
+// Static checks of a program, run before it is executed
+
+use crate::cfg::{Cfg, EdgeKind, Target};
+use crate::instruction::Instruction;
+use crate::{Address, Program};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Warning {
+    // A jump into the middle of an instruction
+    MisalignedJump { address: Address, target: Address },
+    JumpOutsideProgram { address: Address, target: Address },
+    // Execution runs off the end of the program without reaching a halt
+    FallsThroughEnd { address: Address, target: Address },
+    StoresOverCode { address: Address, target: Address },
+    InvalidOpCode { address: Address, opcode: u8 },
+    Unreachable { start: Address, end: Address },
+}
+
+impl Warning {
+    fn address(&self) -> Address {
+        match *self {
+            Warning::MisalignedJump { address, .. }
+            | Warning::JumpOutsideProgram { address, .. }
+            | Warning::FallsThroughEnd { address, .. }
+            | Warning::StoresOverCode { address, .. }
+            | Warning::InvalidOpCode { address, .. } => address,
+            Warning::Unreachable { start, .. } => start,
+        }
+    }
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Warning::MisalignedJump { address, target } => write!(f,
+                "m0x{address:02X}: jumps to m0x{target:02X}, which is in the middle of an instruction"
+            ),
+            Warning::JumpOutsideProgram { address, target } => write!(f,
+                "m0x{address:02X}: jumps to m0x{target:02X}, which is outside the program"
+            ),
+            Warning::FallsThroughEnd { address, target } => write!(f,
+                "m0x{address:02X}: continues to m0x{target:02X} past the end of the program without a halt"
+            ),
+            Warning::StoresOverCode { address, target } => write!(f,
+                "m0x{address:02X}: stores into m0x{target:02X}, which holds an instruction of the program"
+            ),
+            Warning::InvalidOpCode { address, opcode } => write!(f,
+                "m0x{address:02X}: OpCode 0x{opcode:X} is not an instruction and can be reached"
+            ),
+            Warning::Unreachable { start, end } => write!(f,
+                "m0x{start:02X}..m0x{end:02X}: can never be reached"
+            ),
+        }
+    }
+}
+
+pub fn verify(program: &Program) -> Vec<Warning> {
+    let cfg = Cfg::build(program);
+    let reachable = cfg.reachable();
+    let end_of_program = program.start_address.wrapping_add((program.code.len() / 2 * 2) as u8);
+    let code_range = program.start_address as usize..program.start_address as usize + program.code.len();
+    let is_reachable = |start: Address| reachable.iter().any(|block| block.start == start);
+
+    let mut warnings = Vec::new();
+    for edge in cfg.edges.iter().filter(|edge| is_reachable(edge.from)) {
+        let Target::Outside(target) = edge.to else {
+            continue;
+        };
+        // Code loaded with the program, like a shared routine, is a fine target
+        let in_segment = program.segments.iter()
+            .any(|segment| (segment.address as usize..segment.address as usize + segment.bytes.len()).contains(&(target as usize)));
+        if in_segment {
+            continue;
+        }
+        let Some(block) = cfg.blocks.iter().find(|block| block.start == edge.from) else {
+            continue;
+        };
+        let address = block.instructions.last().map(|&(address, _, _)| address).unwrap_or(block.start);
+
+        let falls_through = matches!(edge.kind, EdgeKind::FallThrough | EdgeKind::NotTaken(_));
+        warnings.push(if falls_through && target == end_of_program {
+            Warning::FallsThroughEnd { address, target }
+        } else if code_range.contains(&(target as usize)) {
+            Warning::MisalignedJump { address, target }
+        } else {
+            Warning::JumpOutsideProgram { address, target }
+        });
+    }
+
+    for block in &reachable {
+        for &(address, _, instruction) in &block.instructions {
+            match instruction {
+                Instruction::Store { address: target, .. } => {
+                    let overwrites_code = reachable.iter().any(|block| {
+                        block.instructions.iter().any(|&(start, _, _)| target == start || target == start.wrapping_add(1))
+                    });
+                    if overwrites_code {
+                        warnings.push(Warning::StoresOverCode { address, target });
+                    }
+                },
+                Instruction::Invalid { opcode } => warnings.push(Warning::InvalidOpCode { address, opcode }),
+                _ => {},
+            }
+        }
+    }
+
+    // Neighbouring unreachable blocks are reported as one region
+    let mut unreachable: Option<(Address, Address)> = None;
+    for block in &cfg.blocks {
+        let last = block.instructions.last().map(|&(address, _, _)| address.wrapping_add(1)).unwrap_or(block.start);
+        if is_reachable(block.start) {
+            if let Some((start, end)) = unreachable.take() {
+                warnings.push(Warning::Unreachable { start, end });
+            }
+            continue;
+        }
+        unreachable = match unreachable {
+            Some((start, _)) => Some((start, last)),
+            None => Some((block.start, last)),
+        };
+    }
+    if let Some((start, end)) = unreachable {
+        warnings.push(Warning::Unreachable { start, end });
+    }
+
+    warnings.sort_by_key(|warning| warning.address().wrapping_sub(program.start_address));
+    warnings
+}
+
+pub fn print_warnings(program_name: &str, warnings: &[Warning]) {
+    println!("\nProgram {program_name} has {} warning(s):", warnings.len());
+    for warning in warnings {
+        println!("  - {warning}");
+    }
+}
+
+// `vole-machine verify <program>...`
+pub fn verify_command(names: &[String]) -> bool {
+    if names.is_empty() {
+        println!("Usage: vole-machine verify <program>...");
+        return false;
+    }
+
+    let mut all_clean = true;
+    for name in names {
+        let program = match crate::load_program(name) {
+            Ok(program) => program,
+            Err(error) => {
+                println!("\n{error}");
+                all_clean = false;
+                continue;
+            },
+        };
+
+        let warnings = verify(&program);
+        if warnings.is_empty() {
+            println!("\nProgram {name} has no warnings.");
+        } else {
+            print_warnings(name, &warnings);
+            all_clean = false;
+        }
+    }
+
+    all_clean
+}
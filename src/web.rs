@@ -0,0 +1,260 @@
+// Web UI: the machine on a page served from localhost, for projecting in a lecture hall
+//
+// The page shows the registers, the 16x16 memory grid in the heat colours of the terminal with the
+// program counter in magenta, and buttons to step, run, pause and reset. It polls `/state` for
+// the machine, and the buttons post to `/step`, `/run`, `/pause` and `/reset`. While running,
+// the machine executes one instruction every `SLEEP_DURATION`, like the automatic mode.
+
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use crate::json::Json;
+use crate::{Cpu, Program, Step};
+
+const DEFAULT_PORT: u16 = 8080;
+// Browsers open connections ahead of time that may never send a request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Machine {
+    cpu: Cpu,
+    program: Program,
+    running: bool,
+    // Why the program stopped for good: its halt or fault
+    stopped: Option<String>,
+}
+
+impl Machine {
+    fn new(program: Program) -> Machine {
+        let mut machine = Machine { cpu: Cpu::new(), program, running: false, stopped: None };
+        machine.reset();
+        machine
+    }
+
+    fn reset(&mut self) {
+        self.cpu = Cpu::new();
+        self.cpu.import(self.program.clone());
+        if let Ok(Some(spec)) = crate::spec::Spec::find(&self.program.name) {
+            spec.prepare(&mut self.cpu);
+        }
+        self.running = false;
+        self.stopped = None;
+    }
+
+    fn step(&mut self) {
+        if self.stopped.is_some() {
+            self.running = false;
+            return;
+        }
+        match self.cpu.step() {
+            Step::Continue => {},
+            Step::Halt => self.stopped = Some(format!("Program {} halted.", self.cpu.program_name)),
+            Step::Fault(fault) => self.stopped = Some(fault.to_string()),
+        }
+        if self.stopped.is_some() {
+            self.running = false;
+        }
+    }
+
+    fn state(&self) -> Json {
+        let bytes = |values: &[u8]| Json::Array(values.iter().map(|&value| Json::from(value)).collect());
+        let markers: Vec<Json> = (0..=255u8)
+            .map(|address| {
+                if self.cpu.modified_code[address as usize] {
+                    Json::from("!")
+                } else if self.cpu.in_stack(address) {
+                    Json::from("^")
+                } else {
+                    Json::from("")
+                }
+            })
+            .collect();
+        Json::object([
+            ("program", Json::from(self.cpu.program_name.clone())),
+            ("pc", Json::from(self.cpu.pc)),
+            ("cycles", Json::from(self.cpu.cycles)),
+            ("registers", bytes(&self.cpu.register)),
+            ("registerHeat", bytes(&self.cpu.heat.register)),
+            ("memory", bytes(&self.cpu.memory)),
+            ("memoryHeat", bytes(&self.cpu.heat.memory)),
+            ("markers", Json::Array(markers)),
+            ("running", Json::from(self.running)),
+            ("stopped", Json::from(self.stopped.clone())),
+        ])
+    }
+}
+
+// A thread that panicked while holding the machine leaves it poisoned, it is still shown and reset
+fn lock(machine: &Mutex<Machine>) -> MutexGuard<'_, Machine> {
+    machine.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) -> std::io::Result<()> {
+    write!(stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+fn handle(mut stream: TcpStream, machine: &Mutex<Machine>) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers, and the body the buttons don't send
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or_default();
+            }
+        }
+    }
+    reader.take(content_length).read_to_end(&mut Vec::new())?;
+
+    let mut words = request_line.split_whitespace();
+    let (method, path) = (words.next().unwrap_or_default(), words.next().unwrap_or_default());
+    let mut machine = lock(machine);
+    match (method, path) {
+        ("GET", "/") => return respond(&mut stream, "200 OK", "text/html; charset=utf-8", PAGE),
+        ("GET", "/state") => {},
+        ("POST", "/step") => {
+            machine.running = false;
+            machine.step();
+        },
+        ("POST", "/run") => machine.running = machine.stopped.is_none(),
+        ("POST", "/pause") => machine.running = false,
+        ("POST", "/reset") => machine.reset(),
+        _ => return respond(&mut stream, "404 Not Found", "text/plain", "Not found"),
+    }
+    let state = machine.state().to_string();
+    drop(machine);
+    respond(&mut stream, "200 OK", "application/json", &state)
+}
+
+// `vole-machine web <program> [port]`
+pub fn web_command(args: &[String]) -> bool {
+    let [source, rest @ ..] = args else {
+        println!("Usage: vole-machine web <program> [port]");
+        return false;
+    };
+    let port = match rest.first().map(|port| port.parse::<u16>()) {
+        None => DEFAULT_PORT,
+        Some(Ok(port)) => port,
+        Some(Err(_)) => {
+            println!("'{}' is not a port number.", rest[0]);
+            return false;
+        },
+    };
+    let program = match crate::load_program(source) {
+        Ok(program) => program,
+        Err(error) => {
+            println!("{error}");
+            return false;
+        },
+    };
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(error) => {
+            println!("Can't listen on port {port}: {error}");
+            return false;
+        },
+    };
+
+    let machine = Arc::new(Mutex::new(Machine::new(program)));
+    let runner = Arc::clone(&machine);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(crate::SLEEP_DURATION);
+        let mut machine = lock(&runner);
+        if machine.running {
+            machine.step();
+        }
+    });
+
+    println!("Program {} is shown on http://localhost:{port}/", lock(&machine).cpu.program_name);
+    // Every connection on its own thread, so a slow or idle one doesn't hold up the others
+    for stream in listener.incoming().flatten() {
+        let machine = Arc::clone(&machine);
+        std::thread::spawn(move || match handle(stream, &machine) {
+            Err(error) if !matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => println!("{error}"),
+            _ => {},
+        });
+    }
+    true
+}
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Vole Machine</title>
+<style>
+    body { background: #111; color: #eee; font-family: monospace; font-size: 20px; margin: 2em; }
+    table { border-collapse: collapse; margin-bottom: 1em; }
+    th { color: #888; font-weight: normal; padding: 0.15em 0.45em; }
+    td { padding: 0.15em 0.45em; text-align: center; }
+    button { font: inherit; padding: 0.3em 1em; margin-right: 0.5em; }
+    .heat0 { color: #eee; } .heat1 { color: #5c8cff; } .heat2 { color: #3cd; }
+    .heat3 { color: #4c4; } .heat4 { color: #ee4; } .heat5 { color: #f44; }
+    .pc { color: #e4e; outline: 2px solid #e4e; }
+    #status { margin: 1em 0; min-height: 1.2em; }
+</style>
+</head>
+<body>
+<h2 id="title">Vole Machine</h2>
+<div>
+    <button onclick="post('step')">Step</button>
+    <button onclick="post('run')">Run</button>
+    <button onclick="post('pause')">Pause</button>
+    <button onclick="post('reset')">Reset</button>
+</div>
+<div id="status"></div>
+<table id="registers"></table>
+<table id="memory"></table>
+<script>
+const hex = value => value.toString(16).toUpperCase().padStart(2, "0");
+
+function render(state) {
+    document.getElementById("title").textContent =
+        `Program ${state.program}, cycle ${state.cycles}, PC m0x${hex(state.pc)}`;
+    document.getElementById("status").textContent =
+        state.stopped ?? (state.running ? "Running..." : "Paused");
+
+    let registers = "<tr>" + state.registers.map((_, r) => `<th>r${r.toString(16).toUpperCase()}</th>`).join("") + "</tr><tr>";
+    registers += state.registers.map((value, r) => `<td class="heat${state.registerHeat[r]}">${hex(value)}</td>`).join("");
+    document.getElementById("registers").innerHTML = registers + "</tr>";
+
+    let memory = "<tr><th></th>" + [...Array(16).keys()].map(i => `<th>m${hex(i)}</th>`).join("") + "</tr>";
+    for (let row = 0; row < 256; row += 16) {
+        memory += `<tr><th>m${hex(row)}</th>`;
+        for (let i = row; i < row + 16; i++) {
+            const style = i === state.pc ? "pc" : `heat${state.memoryHeat[i]}`;
+            memory += `<td class="${style}">${state.markers[i]}${hex(state.memory[i])}</td>`;
+        }
+        memory += "</tr>";
+    }
+    document.getElementById("memory").innerHTML = memory;
+}
+
+async function post(action) {
+    render(await (await fetch("/" + action, { method: "POST" })).json());
+}
+
+async function poll() {
+    try {
+        render(await (await fetch("/state")).json());
+    } finally {
+        setTimeout(poll, 200);
+    }
+}
+poll();
+</script>
+</body>
+</html>
+"#;